@@ -0,0 +1,138 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+//! Benchmarks of the hot paths.
+//!
+//! Run with `cargo bench`, every benchmark prints the average time
+//! per iteration.
+
+extern crate dht;
+extern crate rand;
+
+use std::time::{Duration, Instant};
+
+use dht::auth::AuthenticatedTransport;
+use dht::metrics::{AtomicHistogram, Counter};
+#[cfg(feature = "prometheus")]
+use dht::metrics::{Metrics, PrometheusRecorder};
+use dht::transport::{MemoryNetwork, Transport};
+use dht::{GenericNodeTable, GenericStorage, KNodeTable, MemoryStorage, Node, TrieNodeTable,
+          XorDistance};
+use rand::Rng;
+
+
+static MIN_DURATION_MS: u64 = 500;
+
+
+fn bench<F>(name: &str, mut f: F)
+        where F: FnMut() {
+    // Warm up and find out how many iterations fit into the minimum duration
+    let mut iterations: u32 = 1;
+    loop {
+        let start = Instant::now();
+        for _ in 0..iterations {
+            f();
+        }
+        let elapsed = start.elapsed();
+        if elapsed >= Duration::from_millis(MIN_DURATION_MS) {
+            println!("{:<32} {:>12} ns/iter ({} iterations)",
+                     name, (elapsed / iterations).as_nanos(), iterations);
+            return;
+        }
+        iterations *= 2;
+    }
+}
+
+fn random_nodes(count: usize) -> Vec<Node<u64, u16>> {
+    let mut rng = rand::thread_rng();
+    (0..count).map(|i| Node { id: rng.gen(), address: i as u16 }).collect()
+}
+
+fn main() {
+    let mut rng = rand::thread_rng();
+    let this_id: u64 = rng.gen();
+    let nodes = random_nodes(1000);
+
+    bench("xor_sort_1k", || {
+        let target: u64 = rand::thread_rng().gen();
+        let mut ids: Vec<u64> = nodes.iter().map(|n| n.id).collect();
+        ids.sort_by_key(|id| id ^ target);
+    });
+
+    let long_nodes: Vec<Node<[u8; 20], u16>> = nodes.iter()
+        .map(|n| Node { id: rng.gen(), address: n.address }).collect();
+    bench("xor_sort_1k_20b", || {
+        let target: [u8; 20] = rand::thread_rng().gen();
+        let mut sorted = long_nodes.clone();
+        dht::sort_by_distance(&mut sorted, &target, &XorDistance);
+    });
+
+    bench("table_update_1k", || {
+        let mut table = KNodeTable::new(this_id);
+        for node in &nodes {
+            table.update(node);
+        }
+    });
+
+    let mut table = KNodeTable::new(this_id);
+    for node in &nodes {
+        table.update(node);
+    }
+    bench("table_find_8", || {
+        let target: u64 = rand::thread_rng().gen();
+        table.find(&target, 8);
+    });
+
+    let many_nodes = random_nodes(20000);
+    bench("trie_update_20k", || {
+        let mut table = TrieNodeTable::new(this_id);
+        for node in &many_nodes {
+            table.update(node);
+        }
+    });
+
+    let mut trie = TrieNodeTable::new(this_id);
+    for node in &many_nodes {
+        trie.update(node);
+    }
+    bench("trie_find_8_in_20k", || {
+        let target: u64 = rand::thread_rng().gen();
+        trie.find(&target, 8);
+    });
+
+    bench("storage_put_get_1k", || {
+        let mut storage = MemoryStorage::new();
+        for node in &nodes {
+            storage.put(node.id, node.address);
+        }
+        for node in &nodes {
+            storage.get(&node.id);
+        }
+    });
+    let network = MemoryNetwork::new();
+    let mut sender = AuthenticatedTransport::new(network.bind(1), b"secret");
+    let mut receiver = AuthenticatedTransport::new(network.bind(2), b"secret");
+    let datagram = [0u8; 200];
+    let mut buffer = [0u8; 256];
+    bench("auth_send_recv_200b", || {
+        sender.send_to(&datagram, &2).unwrap();
+        receiver.recv_from(&mut buffer).unwrap().unwrap();
+    });
+    let counter = Counter::new();
+    bench("counter_add", || counter.add(1));
+
+    let histogram = AtomicHistogram::new();
+    bench("histogram_record", || histogram.record(Duration::from_millis(3)));
+
+    #[cfg(feature = "prometheus")]
+    {
+        let recorder = PrometheusRecorder::new();
+        bench("prometheus_increment", || recorder.increment("dht_bench_total", 1));
+    }
+}
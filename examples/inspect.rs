@@ -0,0 +1,44 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+//! Print the routing table of a running node.
+//!
+//! Run with `cargo run --example inspect -- <control socket>`, the node
+//! must serve `control::ControlServer` on that socket.
+
+extern crate dht;
+
+#[cfg(unix)]
+use std::env;
+use std::process;
+
+
+#[cfg(unix)]
+fn main() {
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("Usage: inspect <control socket>");
+            process::exit(2);
+        }
+    };
+    match dht::control::inspect(&path) {
+        Ok(report) => print!("{}", report),
+        Err(err) => {
+            eprintln!("Cannot inspect {}: {}", path, err);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("The control socket is only available on unix");
+    process::exit(2);
+}
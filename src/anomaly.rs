@@ -0,0 +1,264 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+//! Detection of nodes switching IDs or addresses.
+//!
+//! A normal node keeps its ID and address for a long time. An address
+//! presenting many different IDs is likely generating them to get into
+//! many buckets, an ID showing up from many addresses is likely used by
+//! several attacking nodes at once. Note that nodes behind the same NAT
+//! legitimately share an address, so the limits should not be too tight.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::{GenericId, Node};
+use super::clock::{Clock, SystemClock};
+
+
+static WINDOW: u64 = 60 * 60;
+static BAN_DURATION: u64 = 60 * 60;
+static MAX_IDS_PER_ADDRESS: usize = 10;
+static MAX_ADDRESSES_PER_ID: usize = 5;
+
+
+/// Anomaly found by `AnomalyDetector`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Anomaly<TId> {
+    /// Address presented the given number of different IDs, it is banned.
+    ManyIds(IpAddr, usize),
+    /// ID was used from the given number of different addresses, it is
+    /// distrusted.
+    ManyAddresses(TId, usize),
+}
+
+/// Detector of addresses presenting many IDs and IDs used from many
+/// addresses.
+///
+/// Offenders are banned for a while, see `is_trusted`.
+pub struct AnomalyDetector<TId> {
+    window: Duration,
+    ban_duration: Duration,
+    max_ids_per_address: usize,
+    max_addresses_per_id: usize,
+    ids: HashMap<IpAddr, Vec<(TId, Instant)>>,
+    addresses: HashMap<TId, Vec<(IpAddr, Instant)>>,
+    banned: HashMap<IpAddr, Instant>,
+    distrusted: HashMap<TId, Instant>,
+    last_sweep: Option<Instant>,
+    clock: Arc<dyn Clock>,
+}
+
+
+impl<TId> AnomalyDetector<TId>
+        where TId: GenericId {
+    /// Create a detector allowing 10 IDs per address and 5 addresses per
+    /// ID in an hour, offenders are banned for an hour.
+    pub fn new() -> AnomalyDetector<TId> {
+        AnomalyDetector::with_limits(Duration::from_secs(WINDOW), MAX_IDS_PER_ADDRESS,
+                                     MAX_ADDRESSES_PER_ID)
+    }
+
+    /// Create a detector allowing at most `max_ids_per_address` IDs from
+    /// an address and `max_addresses_per_id` addresses for an ID per `window`.
+    pub fn with_limits(window: Duration, max_ids_per_address: usize,
+                       max_addresses_per_id: usize) -> AnomalyDetector<TId> {
+        AnomalyDetector {
+            window,
+            ban_duration: Duration::from_secs(BAN_DURATION),
+            max_ids_per_address,
+            max_addresses_per_id,
+            ids: HashMap::new(),
+            addresses: HashMap::new(),
+            banned: HashMap::new(),
+            distrusted: HashMap::new(),
+            last_sweep: None,
+            clock: Arc::new(SystemClock)
+        }
+    }
+
+    /// Set the clock used for the window and bans.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Set how long offenders are banned, an hour by default.
+    pub fn set_ban_duration(&mut self, duration: Duration) {
+        self.ban_duration = duration;
+    }
+
+    /// Record a node seen in a request or a response.
+    ///
+    /// Returns an anomaly if the node has just gone over a limit.
+    pub fn observe(&mut self, node: &Node<TId, SocketAddr>) -> Option<Anomaly<TId>> {
+        let now = self.clock.now();
+        self.sweep(now);
+        let ip = node.address.ip().to_canonical();
+        let window = self.window;
+
+        let ids = seen(self.ids.entry(ip).or_default(), node.id.clone(), now, window);
+        if ids > self.max_ids_per_address && !self.banned.contains_key(&ip) {
+            warn!("Address {} presented {} IDs, banning it", ip, ids);
+            self.banned.insert(ip, now + self.ban_duration);
+            return Some(Anomaly::ManyIds(ip, ids));
+        }
+
+        let addresses = seen(self.addresses.entry(node.id.clone()).or_default(), ip, now,
+                             window);
+        if addresses > self.max_addresses_per_id && !self.distrusted.contains_key(&node.id) {
+            warn!("ID {:?} used from {} addresses, distrusting it", node.id, addresses);
+            self.distrusted.insert(node.id.clone(), now + self.ban_duration);
+            return Some(Anomaly::ManyAddresses(node.id.clone(), addresses));
+        }
+        None
+    }
+
+    /// Whether a node is neither from a banned address nor has
+    /// a distrusted ID.
+    pub fn is_trusted(&self, node: &Node<TId, SocketAddr>) -> bool {
+        let now = self.clock.now();
+        let active = |until: Option<&Instant>| until.is_some_and(|until| *until > now);
+        !active(self.banned.get(&node.address.ip().to_canonical())) &&
+            !active(self.distrusted.get(&node.id))
+    }
+
+    /// Number of different IDs an address presented in the current window.
+    pub fn ids_of(&self, ip: &IpAddr) -> usize {
+        self.ids.get(&ip.to_canonical()).map(Vec::len).unwrap_or(0)
+    }
+
+    /// Number of different addresses an ID was used from in the current
+    /// window.
+    pub fn addresses_of(&self, id: &TId) -> usize {
+        self.addresses.get(id).map(Vec::len).unwrap_or(0)
+    }
+
+    /// Ban an address manually, e.g. from the control interface.
+    pub fn ban(&mut self, ip: IpAddr) {
+        let until = self.clock.now() + self.ban_duration;
+        self.banned.insert(ip.to_canonical(), until);
+    }
+
+    /// Lift a ban from an address, returns whether it was banned.
+    pub fn unban(&mut self, ip: &IpAddr) -> bool {
+        self.banned.remove(&ip.to_canonical()).is_some()
+    }
+
+    /// Forget old sightings and lifted bans once per window.
+    fn sweep(&mut self, now: Instant) {
+        match self.last_sweep {
+            Some(last) if now.duration_since(last) < self.window => return,
+            _ => {}
+        }
+        self.last_sweep = Some(now);
+        let window = self.window;
+        self.ids.retain(|_, seen| {
+            seen.retain(|&(_, at)| now.duration_since(at) < window);
+            !seen.is_empty()
+        });
+        self.addresses.retain(|_, seen| {
+            seen.retain(|&(_, at)| now.duration_since(at) < window);
+            !seen.is_empty()
+        });
+        self.banned.retain(|_, until| *until > now);
+        self.distrusted.retain(|_, until| *until > now);
+    }
+}
+
+impl<TId> Default for AnomalyDetector<TId>
+        where TId: GenericId {
+    fn default() -> AnomalyDetector<TId> {
+        AnomalyDetector::new()
+    }
+}
+
+/// Record a value seen now, returns the number of different values seen
+/// within the window.
+fn seen<T: Eq>(seen: &mut Vec<(T, Instant)>, value: T, now: Instant,
+               window: Duration) -> usize {
+    seen.retain(|&(_, at)| now.duration_since(at) < window);
+    match seen.iter_mut().find(|entry| entry.0 == value) {
+        Some(entry) => entry.1 = now,
+        None => seen.push((value, now))
+    }
+    seen.len()
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, SocketAddr};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::super::Node;
+    use super::super::mock::MockClock;
+    use super::{Anomaly, AnomalyDetector};
+
+
+    fn node(id: u64, address: &str) -> Node<u64, SocketAddr> {
+        Node { id, address: address.parse().unwrap() }
+    }
+
+    fn detector(clock: &MockClock) -> AnomalyDetector<u64> {
+        let mut d = AnomalyDetector::with_limits(Duration::from_secs(60), 2, 2);
+        d.set_clock(Arc::new(clock.clone()));
+        d.set_ban_duration(Duration::from_secs(600));
+        d
+    }
+
+    #[test]
+    fn test_many_ids() {
+        let clock = MockClock::new();
+        let mut d = detector(&clock);
+        assert_eq!(None, d.observe(&node(1, "10.0.0.1:1")));
+        assert_eq!(None, d.observe(&node(1, "10.0.0.1:1")));
+        assert_eq!(None, d.observe(&node(2, "10.0.0.1:2")));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        assert_eq!(Some(Anomaly::ManyIds(ip, 3)), d.observe(&node(3, "10.0.0.1:3")));
+        assert_eq!(3, d.ids_of(&ip));
+        assert!(!d.is_trusted(&node(1, "10.0.0.1:1")));
+        assert!(!d.is_trusted(&node(4, "[::ffff:10.0.0.1]:1")));
+        assert!(d.is_trusted(&node(1, "10.0.0.2:1")));
+        // Reported only once
+        assert_eq!(None, d.observe(&node(4, "10.0.0.1:3")));
+
+        clock.advance(Duration::from_secs(600));
+        assert!(d.is_trusted(&node(1, "10.0.0.1:1")));
+    }
+
+    #[test]
+    fn test_many_addresses() {
+        let clock = MockClock::new();
+        let mut d = detector(&clock);
+        assert_eq!(None, d.observe(&node(1, "10.0.0.1:1")));
+        assert_eq!(None, d.observe(&node(1, "10.0.0.2:1")));
+        // Old sightings are forgotten
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(None, d.observe(&node(1, "10.0.0.3:1")));
+        assert_eq!(None, d.observe(&node(1, "10.0.0.4:1")));
+        assert_eq!(Some(Anomaly::ManyAddresses(1, 3)), d.observe(&node(1, "10.0.0.5:1")));
+        assert!(!d.is_trusted(&node(1, "10.0.0.3:1")));
+        assert!(d.is_trusted(&node(2, "10.0.0.3:1")));
+    }
+
+    #[test]
+    fn test_manual_ban() {
+        let clock = MockClock::new();
+        let mut d = detector(&clock);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        d.ban(ip);
+        assert!(!d.is_trusted(&node(1, "10.0.0.1:1")));
+        assert!(d.unban(&ip));
+        assert!(!d.unban(&ip));
+        assert!(d.is_trusted(&node(1, "10.0.0.1:1")));
+    }
+}
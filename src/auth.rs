@@ -0,0 +1,327 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+//! Message authentication for closed networks.
+//!
+//! `AuthenticatedTransport` appends an HMAC-SHA256 tag computed with
+//! a key shared by all nodes of the network to every datagram, and silently
+//! drops incoming datagrams without a valid tag. It only proves membership
+//! in the network, datagrams are neither encrypted nor protected from
+//! replays.
+
+use std::cmp;
+use std::io;
+
+use super::transport::Transport;
+
+
+static TAG_SIZE: usize = 32;
+static BLOCK_SIZE: usize = 64;
+static MAX_DATAGRAM_SIZE: usize = 65536;
+
+static SHA256_INITIAL: [u32; 8] = [
+    0x6a09_e667, 0xbb67_ae85, 0x3c6e_f372, 0xa54f_f53a,
+    0x510e_527f, 0x9b05_688c, 0x1f83_d9ab, 0x5be0_cd19
+];
+
+static SHA256_ROUND: [u32; 64] = [
+    0x428a_2f98, 0x7137_4491, 0xb5c0_fbcf, 0xe9b5_dba5, 0x3956_c25b, 0x59f1_11f1,
+    0x923f_82a4, 0xab1c_5ed5, 0xd807_aa98, 0x1283_5b01, 0x2431_85be, 0x550c_7dc3,
+    0x72be_5d74, 0x80de_b1fe, 0x9bdc_06a7, 0xc19b_f174, 0xe49b_69c1, 0xefbe_4786,
+    0x0fc1_9dc6, 0x240c_a1cc, 0x2de9_2c6f, 0x4a74_84aa, 0x5cb0_a9dc, 0x76f9_88da,
+    0x983e_5152, 0xa831_c66d, 0xb003_27c8, 0xbf59_7fc7, 0xc6e0_0bf3, 0xd5a7_9147,
+    0x06ca_6351, 0x1429_2967, 0x27b7_0a85, 0x2e1b_2138, 0x4d2c_6dfc, 0x5338_0d13,
+    0x650a_7354, 0x766a_0abb, 0x81c2_c92e, 0x9272_2c85, 0xa2bf_e8a1, 0xa81a_664b,
+    0xc24b_8b70, 0xc76c_51a3, 0xd192_e819, 0xd699_0624, 0xf40e_3585, 0x106a_a070,
+    0x19a4_c116, 0x1e37_6c08, 0x2748_774c, 0x34b0_bcb5, 0x391c_0cb3, 0x4ed8_aa4a,
+    0x5b9c_ca4f, 0x682e_6ff3, 0x748f_82ee, 0x78a5_636f, 0x84c8_7814, 0x8cc7_0208,
+    0x90be_fffa, 0xa450_6ceb, 0xbef9_a3f7, 0xc671_78f2
+];
+
+
+/// Transport wrapper authenticating datagrams with a shared key.
+pub struct AuthenticatedTransport<T: Transport> {
+    inner: T,
+    key: HmacKey,
+    rejected: usize,
+    /// Reused for every datagram to avoid allocations.
+    send_buffer: Vec<u8>,
+    recv_buffer: Vec<u8>,
+}
+
+
+impl<T: Transport> AuthenticatedTransport<T> {
+    /// Wrap a transport, all nodes of the network must use the same key.
+    pub fn new(inner: T, key: &[u8]) -> AuthenticatedTransport<T> {
+        AuthenticatedTransport {
+            inner,
+            key: HmacKey::new(key),
+            rejected: 0,
+            send_buffer: vec![],
+            recv_buffer: vec![0u8; MAX_DATAGRAM_SIZE]
+        }
+    }
+
+    /// Number of incoming datagrams dropped because of a missing or
+    /// invalid tag.
+    pub fn rejected(&self) -> usize {
+        self.rejected
+    }
+
+    /// Get the wrapped transport back.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Transport> Transport for AuthenticatedTransport<T> {
+    type Addr = T::Addr;
+
+    fn send_to(&mut self, data: &[u8], addr: &T::Addr) -> io::Result<()> {
+        let tag = self.key.tag(data);
+        self.send_buffer.clear();
+        self.send_buffer.extend_from_slice(data);
+        self.send_buffer.extend_from_slice(&tag);
+        self.inner.send_to(&self.send_buffer, addr)
+    }
+
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<Option<(usize, T::Addr)>> {
+        loop {
+            let (size, source) = match self.inner.recv_from(&mut self.recv_buffer)? {
+                Some(res) => res,
+                None => return Ok(None)
+            };
+            if size < TAG_SIZE {
+                self.rejected += 1;
+                continue;
+            }
+            let (data, tag) = self.recv_buffer[..size].split_at(size - TAG_SIZE);
+            if !constant_time_eq(&self.key.tag(data), tag) {
+                self.rejected += 1;
+                continue;
+            }
+            let size = cmp::min(data.len(), buffer.len());
+            buffer[..size].copy_from_slice(&data[..size]);
+            return Ok(Some((size, source)));
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<T::Addr> {
+        self.inner.local_addr()
+    }
+}
+
+/// Compute HMAC-SHA256 of a message.
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    HmacKey::new(key).tag(message)
+}
+
+/// Compute SHA-256 of a message.
+pub fn sha256(message: &[u8]) -> [u8; 32] {
+    let mut hash = Sha256::new();
+    hash.update(message);
+    hash.finish()
+}
+
+/// HMAC-SHA256 key with the padded key blocks already hashed, so that
+/// computing a tag does not repeat it for every message.
+#[derive(Clone)]
+struct HmacKey {
+    inner: Sha256,
+    outer: Sha256,
+}
+
+impl HmacKey {
+    fn new(key: &[u8]) -> HmacKey {
+        let mut block = [0u8; 64];
+        if key.len() > BLOCK_SIZE {
+            block[..32].copy_from_slice(&sha256(key));
+        }
+        else {
+            block[..key.len()].copy_from_slice(key);
+        }
+        let mut pad = [0u8; 64];
+        for (p, k) in pad.iter_mut().zip(block.iter()) {
+            *p = k ^ 0x36;
+        }
+        let mut inner = Sha256::new();
+        inner.update(&pad);
+        for (p, k) in pad.iter_mut().zip(block.iter()) {
+            *p = k ^ 0x5c;
+        }
+        let mut outer = Sha256::new();
+        outer.update(&pad);
+        HmacKey { inner, outer }
+    }
+
+    fn tag(&self, message: &[u8]) -> [u8; 32] {
+        let mut inner = self.inner.clone();
+        inner.update(message);
+        let mut outer = self.outer.clone();
+        outer.update(&inner.finish());
+        outer.finish()
+    }
+}
+
+/// Incremental SHA-256, does not allocate.
+#[derive(Clone)]
+struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    filled: usize,
+    length: u64,
+}
+
+impl Sha256 {
+    fn new() -> Sha256 {
+        Sha256 {
+            state: SHA256_INITIAL,
+            block: [0u8; 64],
+            filled: 0,
+            length: 0
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.length += data.len() as u64;
+        if self.filled > 0 {
+            let size = cmp::min(BLOCK_SIZE - self.filled, data.len());
+            self.block[self.filled..self.filled + size].copy_from_slice(&data[..size]);
+            self.filled += size;
+            data = &data[size..];
+            if self.filled < BLOCK_SIZE {
+                return;
+            }
+            compress(&mut self.state, &self.block);
+            self.filled = 0;
+        }
+        while data.len() >= BLOCK_SIZE {
+            compress(&mut self.state, &data[..BLOCK_SIZE]);
+            data = &data[BLOCK_SIZE..];
+        }
+        self.block[..data.len()].copy_from_slice(data);
+        self.filled = data.len();
+    }
+
+    fn finish(mut self) -> [u8; 32] {
+        let bits = self.length * 8;
+        self.update(&[0x80]);
+        while self.filled != 56 {
+            self.update(&[0]);
+        }
+        self.update(&bits.to_be_bytes());
+        let mut res = [0u8; 32];
+        for (output, value) in res.chunks_mut(4).zip(self.state.iter()) {
+            output.copy_from_slice(&value.to_be_bytes());
+        }
+        res
+    }
+}
+
+fn compress(state: &mut [u32; 8], block: &[u8]) {
+    let mut schedule = [0u32; 64];
+    for (i, word) in block.chunks(4).enumerate() {
+        schedule[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+    }
+    for i in 16..64 {
+        let s0 = schedule[i - 15].rotate_right(7) ^ schedule[i - 15].rotate_right(18)
+            ^ (schedule[i - 15] >> 3);
+        let s1 = schedule[i - 2].rotate_right(17) ^ schedule[i - 2].rotate_right(19)
+            ^ (schedule[i - 2] >> 10);
+        schedule[i] = schedule[i - 16].wrapping_add(s0)
+            .wrapping_add(schedule[i - 7]).wrapping_add(s1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+    for i in 0..64 {
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let choice = (e & f) ^ (!e & g);
+        let temp1 = h.wrapping_add(s1).wrapping_add(choice)
+            .wrapping_add(SHA256_ROUND[i]).wrapping_add(schedule[i]);
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let majority = (a & b) ^ (a & c) ^ (b & c);
+        let temp2 = s0.wrapping_add(majority);
+        h = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(temp1);
+        d = c;
+        c = b;
+        b = a;
+        a = temp1.wrapping_add(temp2);
+    }
+    for (value, new) in state.iter_mut().zip(&[a, b, c, d, e, f, g, h]) {
+        *value = value.wrapping_add(*new);
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+
+#[cfg(test)]
+mod test {
+    use rustc_serialize::hex::ToHex;
+
+    use super::super::transport::{MemoryNetwork, Transport};
+    use super::{AuthenticatedTransport, Sha256, hmac_sha256, sha256};
+
+
+    #[test]
+    fn test_sha256() {
+        assert_eq!("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+                   sha256(b"").to_hex());
+        assert_eq!("248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+                   sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq").to_hex());
+    }
+
+    #[test]
+    fn test_sha256_incremental() {
+        let data: Vec<u8> = (0..200).collect();
+        for split in &[0, 1, 63, 64, 65, 150, 200] {
+            let mut hash = Sha256::new();
+            hash.update(&data[..*split]);
+            hash.update(&data[*split..]);
+            assert_eq!(sha256(&data), hash.finish());
+        }
+    }
+
+    #[test]
+    fn test_hmac_sha256() {
+        // RFC 4231, test cases 2 and 6
+        assert_eq!("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+                   hmac_sha256(b"Jefe", b"what do ya want for nothing?").to_hex());
+        assert_eq!("60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+                   hmac_sha256(&[0xaa; 131],
+                               b"Test Using Larger Than Block-Size Key - Hash Key First")
+                       .to_hex());
+    }
+
+    #[test]
+    fn test_transport() {
+        let network = MemoryNetwork::new();
+        let mut t1 = AuthenticatedTransport::new(network.bind(1), b"secret");
+        let mut t2 = AuthenticatedTransport::new(network.bind(2), b"secret");
+        let mut stranger = AuthenticatedTransport::new(network.bind(3), b"other");
+        let mut plain = network.bind(4);
+
+        stranger.send_to(b"hello", &2).unwrap();
+        plain.send_to(b"hello", &2).unwrap();
+        t1.send_to(b"ping", &2).unwrap();
+        let mut buffer = [0u8; 64];
+        assert_eq!(Some((4, 1)), t2.recv_from(&mut buffer).unwrap());
+        assert_eq!(b"ping", &buffer[..4]);
+        assert_eq!(2, t2.rejected());
+        assert!(t2.recv_from(&mut buffer).unwrap().is_none());
+
+        t2.send_to(b"pong", &4).unwrap();
+        assert_eq!(Some((36, 2)), plain.recv_from(&mut buffer).unwrap());
+    }
+}
@@ -14,6 +14,8 @@ use std::hash::Hash;
 use std::fmt::Debug;
 use std::str::FromStr;
 use std::net;
+use std::time::Duration;
+use std::vec;
 
 use rustc_serialize as serialize;
 use rustc_serialize::hex::ToHex;
@@ -55,7 +57,7 @@ impl GenericId for u64 {
         s.emit_str(&format!("{:x}", self))
     }
     fn decode<D:serialize::Decoder> (d : &mut D) -> Result<u64, D::Error> {
-        let s: &str = &try!(d.read_str());
+        let s: &str = &d.read_str()?;
         match u64::from_str_radix(s, 16) {
             Ok(v) => Ok(v),
             Err(e) => {
@@ -108,7 +110,7 @@ impl GenericId for Vec<u8> {
         s.emit_str(&self.to_hex())
     }
     fn decode<D:serialize::Decoder> (d : &mut D) -> Result<Vec<u8>, D::Error> {
-        let s = try!(d.read_str());
+        let s = d.read_str()?;
         match s.from_hex() {
             Ok(v) => Ok(v),
             Err(e) => {
@@ -119,6 +121,95 @@ impl GenericId for Vec<u8> {
     }
 }
 
+/// Fixed-size IDs, e.g. `[u8; 20]` for SHA-1 or `[u8; 32]` for SHA-256.
+///
+/// Unlike `Vec<u8>`, all IDs of a network are guaranteed to have the same
+/// width, which is checked when decoding.
+impl<const N: usize> GenericId for [u8; N] {
+    fn bitxor(&self, other: &[u8; N]) -> [u8; N] {
+        let mut res = [0u8; N];
+        for (digit, (digit1, digit2)) in res.iter_mut().zip(self.iter().zip(other.iter())) {
+            *digit = digit1 ^ digit2;
+        }
+        res
+    }
+    fn is_zero(&self) -> bool {
+        self.iter().all(|digit| *digit == 0)
+    }
+    fn bits(&self) -> usize {
+        match self.iter().position(|digit| *digit != 0) {
+            Some(index) => (N - index) * 8 - self[index].leading_zeros() as usize,
+            None => 0
+        }
+    }
+    fn gen(bit_size: usize) -> [u8; N] {
+        assert!(bit_size <= N * 8);
+        let mut res = [0u8; N];
+        let full_digits = bit_size / 8;
+        let partial_bits = bit_size % 8;
+        let mut rng = rand::thread_rng();
+        rng.fill_bytes(&mut res[N - full_digits..]);
+        if partial_bits > 0 {
+            res[N - full_digits - 1] = rng.gen_range(0, 1 << partial_bits);
+        }
+        res
+    }
+
+    fn encode<S:serialize::Encoder> (&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_str(&self.to_hex())
+    }
+    fn decode<D:serialize::Decoder> (d : &mut D) -> Result<[u8; N], D::Error> {
+        let s = d.read_str()?;
+        match s.from_hex() {
+            Ok(ref v) if v.len() == N => {
+                let mut res = [0u8; N];
+                res.copy_from_slice(v);
+                Ok(res)
+            },
+            Ok(v) => {
+                let err = format!("Expected ID of {} bytes, got {}", N, v.len());
+                Err(d.error(&err))
+            },
+            Err(e) => {
+                let err = format!("Expected hex-encoded ID, got {}, error {:?}", s, e);
+                Err(d.error(&err))
+            }
+        }
+    }
+}
+
+/// Trait representing a metric on IDs.
+///
+/// The distance is an ID itself, smaller values are closer. `KNodeTable`
+/// puts nodes into k-buckets by `bits()` of their distance from its own ID,
+/// so nodes sharing a bucket should share a range of distances.
+pub trait Distance<TId> : Send + Sync {
+    /// Distance between two IDs, must be zero only for equal IDs.
+    fn distance(&self, id1: &TId, id2: &TId) -> TId;
+}
+
+/// Kademlia XOR metric.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct XorDistance;
+
+impl<TId> Distance<TId> for XorDistance
+        where TId: GenericId {
+    fn distance(&self, id1: &TId, id2: &TId) -> TId {
+        id1.bitxor(id2)
+    }
+}
+
+/// Sort nodes by distance to an ID, closest first.
+///
+/// Every distance is computed only once, instead of twice per comparison,
+/// which matters for long IDs and hundreds of candidates.
+pub fn sort_by_distance<TId, TAddr, TDistance>(nodes: &mut [Node<TId, TAddr>], id: &TId,
+                                               distance: &TDistance)
+        where TId: GenericId,
+              TDistance: Distance<TId> {
+    nodes.sort_by_cached_key(|node| distance.distance(id, &node.id));
+}
+
 /// Trait representing table with known nodes.
 ///
 /// Keeps some reasonable subset of known nodes passed to `update`.
@@ -132,6 +223,80 @@ pub trait GenericNodeTable<TId, TAddr> : Send + Sync
     fn find(&self, id: &TId, count: usize) -> Vec<Node<TId, TAddr>>;
     /// Pop expired or the oldest nodes from table for inspection.
     fn pop_oldest(&mut self) -> Vec<Node<TId, TAddr>>;
+    /// Number of nodes in the table.
+    ///
+    /// The default implementation counts all nodes returned by `find`,
+    /// tables should override it with something cheaper.
+    fn len(&self) -> usize {
+        self.find(&self.random_id(), usize::MAX).len()
+    }
+    /// Whether the table has no nodes.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Whether a node with given ID is in the table.
+    ///
+    /// The default implementation checks the closest node with `find`.
+    fn contains(&self, id: &TId) -> bool {
+        self.find(id, 1).first().is_some_and(|node| node.id == *id)
+    }
+    /// Remove a node with given ID from the table, returning it.
+    ///
+    /// The default implementation does not remove anything.
+    fn remove(&mut self, _id: &TId) -> Option<Node<TId, TAddr>> {
+        None
+    }
+}
+
+/// Trait representing a storage for the values stored on this node.
+///
+/// The default implementation is `MemoryStorage`, embedders may provide their
+/// own backed by a database.
+pub trait GenericStorage<TId, TData> : Send + Sync
+        where TId: GenericId {
+    /// Get a value by its ID.
+    fn get(&self, id: &TId) -> Option<TData>;
+    /// Store or update a value.
+    ///
+    /// Returns false if the value was rejected, e.g. due to the quota.
+    fn put(&mut self, id: TId, value: TData) -> bool;
+    /// Get IDs of at most `count` stored values, chosen uniformly at random.
+    fn sample(&self, count: usize) -> Vec<TId>;
+    /// Remove values stored more than `max_age` ago and iterate over them.
+    fn expire_iter(&mut self, max_age: Duration) -> vec::IntoIter<(TId, TData)>;
+    /// Remove `count` of the oldest values and iterate over them.
+    ///
+    /// The default implementation expires ever younger values with
+    /// `expire_iter`, so it may remove more values if many have the same
+    /// age.
+    fn evict_oldest(&mut self, count: usize) -> vec::IntoIter<(TId, TData)> {
+        let keep = self.stats().items.saturating_sub(count);
+        let mut res = Vec::new();
+        let mut max_age = Duration::from_secs(u64::from(u32::MAX));
+        // The last pass with zero age removes the remaining values
+        while self.stats().items > keep && !max_age.is_zero() {
+            max_age /= 2;
+            res.extend(self.expire_iter(max_age));
+        }
+        res.into_iter()
+    }
+    /// Get the storage statistics.
+    fn stats(&self) -> StorageStats;
+}
+
+/// Statistics of a storage.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StorageStats {
+    /// Number of items currently stored.
+    pub items: usize,
+    /// Number of accepted `put` calls.
+    pub puts_accepted: usize,
+    /// Number of `put` calls rejected because of the quota.
+    pub puts_over_quota: usize,
+    /// Number of `get` calls that found a value.
+    pub gets_served: usize,
+    /// Number of values removed by `expire_iter`.
+    pub expired: usize
 }
 
 /// Structure representing a node in system.
@@ -170,12 +335,12 @@ impl<TId> serialize::Encodable for Node<TId, net::SocketAddr>
         where TId: GenericId {
     fn encode<S:serialize::Encoder> (&self, s: &mut S) -> Result<(), S::Error> {
         s.emit_struct("Node", 2, |s| {
-            try!(s.emit_struct_field("address", 0, |s2| {
+            s.emit_struct_field("address", 0, |s2| {
                 let addr = format!("{}", self.address);
                 addr.encode(s2)
-            }));
+            })?;
 
-            try!(s.emit_struct_field("id", 1, |s2| self.id.encode(s2)));
+            s.emit_struct_field("id", 1, |s2| self.id.encode(s2))?;
 
             Ok(())
         })
@@ -186,8 +351,8 @@ impl<TId> serialize::Decodable for Node<TId, net::SocketAddr>
         where TId: GenericId {
     fn decode<D:serialize::Decoder> (d : &mut D) -> Result<Node<TId, net::SocketAddr>, D::Error> {
         d.read_struct("Node", 2, |d| {
-            let addr = try!(d.read_struct_field("address", 0, |d2| {
-                let s = try!(d2.read_str());
+            let addr = d.read_struct_field("address", 0, |d2| {
+                let s = d2.read_str()?;
                 match FromStr::from_str(&s) {
                     Ok(addr) => Ok(addr),
                     Err(e) => {
@@ -195,11 +360,11 @@ impl<TId> serialize::Decodable for Node<TId, net::SocketAddr>
                         Err(d2.error(&err))
                     }
                 }
-            }));
+            })?;
 
-            let id = try!(d.read_struct_field("id", 1, TId::decode));
+            let id = d.read_struct_field("id", 1, TId::decode)?;
 
-            Ok(Node { address: addr, id: id })
+            Ok(Node { address: addr, id })
         })
     }
 }
@@ -207,19 +372,46 @@ impl<TId> serialize::Decodable for Node<TId, net::SocketAddr>
 #[cfg(test)]
 mod test {
     use std::net;
+    use rustc_serialize as serialize;
     use rustc_serialize::json;
 
-    use super::{GenericAPI, Node};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use std::vec;
+
+    use super::{GenericAPI, GenericId, GenericNodeTable, GenericStorage, Node, StorageStats,
+                XorDistance};
+    use super::super::MemoryStorage;
+    use super::super::mock::MockClock;
 
     use super::super::utils::test;
     type TestsIdType = test::IdType;
 
-    #[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+    #[derive(Debug, Clone)]
     struct SimplifiedNode {
         address: String,
         id: String
     }
 
+    impl serialize::Encodable for SimplifiedNode {
+        fn encode<S:serialize::Encoder> (&self, s: &mut S) -> Result<(), S::Error> {
+            s.emit_struct("SimplifiedNode", 2, |s| {
+                s.emit_struct_field("address", 0, |s2| self.address.encode(s2))?;
+                s.emit_struct_field("id", 1, |s2| self.id.encode(s2))
+            })
+        }
+    }
+
+    impl serialize::Decodable for SimplifiedNode {
+        fn decode<D:serialize::Decoder> (d : &mut D) -> Result<SimplifiedNode, D::Error> {
+            d.read_struct("SimplifiedNode", 2, |d| {
+                let address = d.read_struct_field("address", 0, |d2| d2.read_str())?;
+                let id = d.read_struct_field("id", 1, |d2| d2.read_str())?;
+                Ok(SimplifiedNode { address, id })
+            })
+        }
+    }
+
     struct DummyAPI {
         value: Option<i32>
     }
@@ -243,6 +435,87 @@ mod test {
         }
     }
 
+    // Node table using the default implementations
+    struct DefaultNodeTable {
+        nodes: Vec<Node<TestsIdType, net::SocketAddr>>
+    }
+
+    impl GenericNodeTable<TestsIdType, net::SocketAddr> for DefaultNodeTable {
+        fn random_id(&self) -> TestsIdType {
+            test::make_id(42)
+        }
+        fn update(&mut self, node: &Node<TestsIdType, net::SocketAddr>) -> bool {
+            self.nodes.push(node.clone());
+            true
+        }
+        fn find(&self, id: &TestsIdType, count: usize) -> Vec<Node<TestsIdType, net::SocketAddr>> {
+            let mut res = self.nodes.clone();
+            super::sort_by_distance(&mut res, id, &XorDistance);
+            res.truncate(count);
+            res
+        }
+        fn pop_oldest(&mut self) -> Vec<Node<TestsIdType, net::SocketAddr>> {
+            vec![]
+        }
+    }
+
+    #[test]
+    fn test_node_table_defaults() {
+        let mut t = DefaultNodeTable { nodes: vec![] };
+        assert_eq!(0, t.len());
+        assert!(t.is_empty());
+        for i in 1..4 {
+            t.update(&test::new_node(test::make_id(i)));
+        }
+        assert_eq!(3, t.len());
+        assert!(t.contains(&test::make_id(2)));
+        assert!(!t.contains(&test::make_id(4)));
+        assert!(t.remove(&test::make_id(2)).is_none());
+    }
+
+    // Storage using the default implementations
+    struct DefaultStorage {
+        inner: MemoryStorage<TestsIdType, i32>
+    }
+
+    impl GenericStorage<TestsIdType, i32> for DefaultStorage {
+        fn get(&self, id: &TestsIdType) -> Option<i32> {
+            self.inner.get(id)
+        }
+        fn put(&mut self, id: TestsIdType, value: i32) -> bool {
+            self.inner.put(id, value)
+        }
+        fn sample(&self, count: usize) -> Vec<TestsIdType> {
+            self.inner.sample(count)
+        }
+        fn expire_iter(&mut self, max_age: Duration) -> vec::IntoIter<(TestsIdType, i32)> {
+            self.inner.expire_iter(max_age)
+        }
+        fn stats(&self) -> StorageStats {
+            self.inner.stats()
+        }
+    }
+
+    #[test]
+    fn test_storage_evict_oldest() {
+        let clock = MockClock::new();
+        let mut inner = MemoryStorage::new();
+        inner.set_clock(Arc::new(clock.clone()));
+        let mut s = DefaultStorage { inner };
+        for i in 0..3 {
+            s.put(test::make_id(i), 1);
+            clock.advance(Duration::from_secs(60));
+        }
+        let evicted: Vec<_> = s.evict_oldest(1).collect();
+        assert_eq!(vec![(test::make_id(0), 1)], evicted);
+        assert_eq!(0, s.evict_oldest(0).count());
+        // Values of the same age are removed together
+        s.put(test::make_id(42), 1);
+        s.put(test::make_id(43), 1);
+        assert_eq!(4, s.evict_oldest(3).count());
+        assert_eq!(0, s.stats().items);
+    }
+
     #[test]
     fn test_node_encode() {
         let n = test::new_node(test::make_id(42));
@@ -292,6 +565,33 @@ mod test {
         assert_eq!(n.address, n2.address);
     }
 
+    #[test]
+    fn test_array_id() {
+        let mut id = [0u8; 32];
+        id[31] = 1;
+        assert_eq!(1, id.bits());
+        id[1] = 0x42;
+        assert_eq!(247, id.bits());
+        assert!(id.bitxor(&id).is_zero());
+        assert!(<[u8; 32]>::gen(250).bits() <= 250);
+        assert!(<[u8; 32]>::gen(256).bits() <= 256);
+
+        let address: net::SocketAddr = test::ADDR.parse().unwrap();
+        let j = json::encode(&Node { id, address }).unwrap();
+        let n: Node<[u8; 32], net::SocketAddr> = json::decode(&j).unwrap();
+        assert_eq!(id, n.id);
+        assert!(json::decode::<Node<[u8; 20], net::SocketAddr>>(&j).is_err());
+    }
+
+    #[test]
+    fn test_sort_by_distance() {
+        let mut nodes: Vec<Node<[u8; 2], ()>> = [[0, 1], [8, 0], [0, 7], [1, 0]].iter()
+            .map(|&id| Node { id, address: () }).collect();
+        super::sort_by_distance(&mut nodes, &[0, 6], &XorDistance);
+        let ids: Vec<[u8; 2]> = nodes.iter().map(|node| node.id).collect();
+        assert_eq!(vec![[0, 7], [0, 1], [1, 0], [8, 0]], ids);
+    }
+
     #[test]
     fn test_generic_api() {
         let mut api = DummyAPI { value: None };
@@ -0,0 +1,214 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+//! Capture of datagrams in pcap format.
+//!
+//! Every datagram is wrapped into synthesized IP and UDP headers, so that
+//! the resulting file can be opened in Wireshark and decoded as usual.
+
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+
+static PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+static SNAPLEN: u32 = 65535;
+/// Raw IP packets, version is determined by the first nibble.
+static LINKTYPE_RAW: u32 = 101;
+static UDP_PROTOCOL: u8 = 17;
+
+
+/// Writer of datagrams in pcap format.
+pub struct PcapWriter<W: Write> {
+    output: W,
+    enabled: bool,
+}
+
+
+impl<W: Write> PcapWriter<W> {
+    /// Create a writer and write the pcap file header.
+    pub fn new(mut output: W) -> io::Result<PcapWriter<W>> {
+        let mut header = Vec::with_capacity(24);
+        push_u32(&mut header, PCAP_MAGIC);
+        header.extend_from_slice(&[2, 0, 4, 0]);  // version 2.4
+        push_u32(&mut header, 0);  // time zone
+        push_u32(&mut header, 0);  // timestamp accuracy
+        push_u32(&mut header, SNAPLEN);
+        push_u32(&mut header, LINKTYPE_RAW);
+        output.write_all(&header)?;
+        Ok(PcapWriter {
+            output,
+            enabled: true
+        })
+    }
+
+    /// Whether datagrams are currently written.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Start or stop writing datagrams.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Write a datagram sent from `source` to `destination`.
+    ///
+    /// Does nothing if capture is disabled.
+    pub fn write_datagram(&mut self, source: &SocketAddr, destination: &SocketAddr,
+                          data: &[u8]) -> io::Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let packet = ip_packet(source, destination, data);
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let len = packet.len() as u32;
+        let mut record = Vec::with_capacity(16 + packet.len());
+        push_u32(&mut record, timestamp.as_secs() as u32);
+        push_u32(&mut record, timestamp.subsec_micros());
+        push_u32(&mut record, len.min(SNAPLEN));
+        push_u32(&mut record, len);
+        record.extend_from_slice(&packet[..packet.len().min(SNAPLEN as usize)]);
+        self.output.write_all(&record)
+    }
+
+    /// Flush the output.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+
+    /// Get the underlying output back.
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+}
+
+fn push_u32(buffer: &mut Vec<u8>, value: u32) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn udp_header(source: &SocketAddr, destination: &SocketAddr, data: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(8 + data.len());
+    res.extend_from_slice(&source.port().to_be_bytes());
+    res.extend_from_slice(&destination.port().to_be_bytes());
+    res.extend_from_slice(&((8 + data.len()) as u16).to_be_bytes());
+    res.extend_from_slice(&[0, 0]);  // no checksum
+    res.extend_from_slice(data);
+    res
+}
+
+fn to_ipv6(ip: &IpAddr) -> Ipv6Addr {
+    match *ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip
+    }
+}
+
+fn ip_packet(source: &SocketAddr, destination: &SocketAddr, data: &[u8]) -> Vec<u8> {
+    let udp = udp_header(source, destination, data);
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let mut res = Vec::with_capacity(20 + udp.len());
+            res.extend_from_slice(&[0x45, 0]);
+            res.extend_from_slice(&((20 + udp.len()) as u16).to_be_bytes());
+            res.extend_from_slice(&[0, 0, 0x40, 0, 64, UDP_PROTOCOL, 0, 0]);
+            res.extend_from_slice(&src.octets());
+            res.extend_from_slice(&dst.octets());
+            let checksum = ipv4_checksum(&res);
+            res[10..12].copy_from_slice(&checksum.to_be_bytes());
+            res.extend_from_slice(&udp);
+            res
+        },
+        (src, dst) => {
+            let mut res = Vec::with_capacity(40 + udp.len());
+            res.extend_from_slice(&[0x60, 0, 0, 0]);
+            res.extend_from_slice(&(udp.len() as u16).to_be_bytes());
+            res.extend_from_slice(&[UDP_PROTOCOL, 64]);
+            res.extend_from_slice(&to_ipv6(&src).octets());
+            res.extend_from_slice(&to_ipv6(&dst).octets());
+            res.extend_from_slice(&udp);
+            res
+        }
+    }
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header.chunks(2)
+        .map(|pair| u32::from(u16::from_be_bytes([pair[0], pair[1]])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use super::{ipv4_checksum, PcapWriter};
+
+
+    #[test]
+    fn test_header() {
+        let w = PcapWriter::new(vec![]).unwrap();
+        let out = w.into_inner();
+        assert_eq!(24, out.len());
+        assert_eq!(&[0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0], &out[0..8]);
+        assert_eq!(&[101, 0, 0, 0], &out[20..24]);
+    }
+
+    #[test]
+    fn test_write_ipv4() {
+        let mut w = PcapWriter::new(vec![]).unwrap();
+        let src: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let dst: SocketAddr = "10.0.0.2:6882".parse().unwrap();
+        w.write_datagram(&src, &dst, b"ping").unwrap();
+        let out = w.into_inner();
+        let record = &out[24..];
+        // 16 bytes of record header, 20 bytes of IP, 8 bytes of UDP
+        assert_eq!(16 + 20 + 8 + 4, record.len());
+        assert_eq!(&[32, 0, 0, 0, 32, 0, 0, 0], &record[8..16]);
+        let packet = &record[16..];
+        assert_eq!(0x45, packet[0]);
+        assert_eq!(17, packet[9]);
+        assert_eq!(0, ipv4_checksum(&packet[0..20]));
+        assert_eq!(&[10, 0, 0, 1, 10, 0, 0, 2], &packet[12..20]);
+        assert_eq!(&[0x1a, 0xe1, 0x1a, 0xe2, 0, 12, 0, 0], &packet[20..28]);
+        assert_eq!(b"ping", &packet[28..]);
+    }
+
+    #[test]
+    fn test_write_ipv6() {
+        let mut w = PcapWriter::new(vec![]).unwrap();
+        let src: SocketAddr = "[::1]:6881".parse().unwrap();
+        let dst: SocketAddr = "10.0.0.2:6882".parse().unwrap();
+        w.write_datagram(&src, &dst, b"ping").unwrap();
+        let packet = &w.into_inner()[40..];
+        assert_eq!(40 + 8 + 4, packet.len());
+        assert_eq!(0x60, packet[0]);
+        assert_eq!(&[0, 12, 17, 64], &packet[4..8]);
+        assert_eq!(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 10, 0, 0, 2],
+                   &packet[24..40]);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut w = PcapWriter::new(vec![]).unwrap();
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        w.set_enabled(false);
+        assert!(!w.is_enabled());
+        w.write_datagram(&addr, &addr, b"ping").unwrap();
+        w.set_enabled(true);
+        w.write_datagram(&addr, &addr, b"ping").unwrap();
+        assert_eq!(24 + 16 + 32, w.into_inner().len());
+    }
+}
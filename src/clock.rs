@@ -0,0 +1,32 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+//! Source of time.
+//!
+//! `SystemClock` reads the monotonic system clock, `mock::MockClock` can be
+//! moved forward by tests.
+
+use std::time::Instant;
+
+
+/// Trait representing a source of monotonic time.
+pub trait Clock : Send + Sync {
+    /// Current time.
+    fn now(&self) -> Instant;
+}
+
+/// Clock returning `Instant::now()`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
@@ -0,0 +1,561 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+//! Control interface over a unix socket.
+//!
+//! Every line sent by a client is a command, every response is one line
+//! of JSON. Commands are:
+//!
+//! * `status` - summary of the service state, including the estimated
+//!   memory usage,
+//! * `table` - all nodes in the node table with their buckets and
+//!   response times,
+//! * `lookup <id>` - start a lookup of a hex-encoded ID,
+//! * `ban <address>` and `unban <address>` - stop or resume talking to
+//!   an address.
+//!
+//! The server only parses commands, what they do is up to the callback
+//! passed to `ControlServer::poll`. `status` and `table` give ready-made
+//! responses for a `Service`, `inspect` prints them for operators.
+
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::fs;
+use std::fmt::Write as FmtWrite;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use rustc_serialize::json::{self, Json};
+
+use super::{GenericId, GenericNodeTable, GenericStorage, Service};
+
+
+static MAX_LINE_SIZE: usize = 4096;
+static MAX_PENDING_OUTPUT: usize = 16 * 1024 * 1024;
+static INSPECT_TIMEOUT: u64 = 10;
+
+
+/// Command received from a client.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command<TId> {
+    Status,
+    Table,
+    Lookup(TId),
+    Ban(String),
+    Unban(String),
+}
+
+/// Server accepting control connections.
+///
+/// The socket file is removed when the server is dropped.
+pub struct ControlServer {
+    listener: UnixListener,
+    path: PathBuf,
+    clients: Vec<Client>,
+}
+
+struct Client {
+    stream: UnixStream,
+    buffer: Vec<u8>,
+    // Responses not written yet because the client does not read them
+    output: Vec<u8>,
+}
+
+
+impl<TId> Command<TId>
+        where TId: GenericId {
+    /// Parse a command line.
+    pub fn parse(line: &str) -> Result<Command<TId>, String> {
+        let mut words = line.split_whitespace();
+        let name = words.next().unwrap_or("");
+        let argument = words.next();
+        if words.next().is_some() {
+            return Err(format!("Too many arguments for {}", name));
+        }
+        match (name, argument) {
+            ("status", None) => Ok(Command::Status),
+            ("table", None) => Ok(Command::Table),
+            ("lookup", Some(id)) => {
+                let mut decoder = json::Decoder::new(Json::String(id.to_string()));
+                TId::decode(&mut decoder)
+                    .map(Command::Lookup)
+                    .map_err(|e| format!("Invalid ID {}: {}", id, e))
+            },
+            ("ban", Some(address)) => Ok(Command::Ban(address.to_string())),
+            ("unban", Some(address)) => Ok(Command::Unban(address.to_string())),
+            ("status", Some(_)) | ("table", Some(_)) =>
+                Err(format!("{} takes no arguments", name)),
+            ("lookup", None) | ("ban", None) | ("unban", None) =>
+                Err(format!("{} requires an argument", name)),
+            _ => Err(format!("Unknown command {}", name))
+        }
+    }
+}
+
+impl ControlServer {
+    /// Listen on a socket at a given path.
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<ControlServer> {
+        let listener = UnixListener::bind(path.as_ref())?;
+        listener.set_nonblocking(true)?;
+        Ok(ControlServer {
+            listener,
+            path: path.as_ref().to_path_buf(),
+            clients: vec![]
+        })
+    }
+
+    /// Path of the socket.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Number of connected clients.
+    pub fn clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Accept new clients and process commands without blocking.
+    ///
+    /// `handler` is called for every valid command, invalid ones get
+    /// an `{"error": ...}` response. Returns the number of commands processed.
+    ///
+    /// Responses a client does not read yet are queued, clients with more
+    /// than 16 MiB queued are dropped.
+    pub fn poll<TId, F>(&mut self, mut handler: F) -> io::Result<usize>
+            where TId: GenericId,
+                  F: FnMut(Command<TId>) -> Json {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(true)?;
+                    self.clients.push(Client { stream, buffer: vec![], output: vec![] });
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e)
+            }
+        }
+
+        let mut processed = 0;
+        self.clients.retain_mut(|client| {
+            match client.process(&mut handler) {
+                Ok(Some(count)) => {
+                    processed += count;
+                    true
+                },
+                Ok(None) => false,
+                Err(e) => {
+                    debug!("Dropping control client: {}", e);
+                    false
+                }
+            }
+        });
+        Ok(processed)
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl Client {
+    /// Process complete lines, returns `None` when the client is gone.
+    fn process<TId, F>(&mut self, handler: &mut F) -> io::Result<Option<usize>>
+            where TId: GenericId,
+                  F: FnMut(Command<TId>) -> Json {
+        let mut closed = false;
+        let mut chunk = [0u8; 512];
+        // The rest is read on the next call, after the queue shrinks
+        while self.buffer.len() <= MAX_LINE_SIZE && self.output.len() <= MAX_PENDING_OUTPUT {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    closed = true;
+                    break;
+                },
+                Ok(size) => self.buffer.extend_from_slice(&chunk[..size]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e)
+            }
+        }
+
+        let mut processed = 0;
+        while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let response = match String::from_utf8_lossy(&line).trim() {
+                "" => continue,
+                line => match Command::parse(line) {
+                    Ok(command) => {
+                        processed += 1;
+                        handler(command)
+                    },
+                    Err(e) => error(&e)
+                }
+            };
+            writeln!(self.output, "{}", response)?;
+            if self.output.len() > MAX_PENDING_OUTPUT {
+                self.flush()?;
+                if self.output.len() > MAX_PENDING_OUTPUT {
+                    return Err(io::Error::other("responses are not read"));
+                }
+            }
+        }
+        if self.buffer.len() > MAX_LINE_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+        }
+        self.flush()?;
+        // Half-closed clients may still read the queued responses
+        Ok(if closed && self.output.is_empty() { None } else { Some(processed) })
+    }
+
+    /// Write as much of the queued output as possible without blocking.
+    fn flush(&mut self) -> io::Result<()> {
+        let mut written = 0;
+        while written < self.output.len() {
+            match self.stream.write(&self.output[written..]) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                                   "client closed the socket")),
+                Ok(size) => written += size,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e)
+            }
+        }
+        self.output.drain(..written);
+        Ok(())
+    }
+}
+
+/// Response with an error message.
+pub fn error(message: &str) -> Json {
+    let mut object = BTreeMap::new();
+    object.insert("error".to_string(), Json::String(message.to_string()));
+    Json::Object(object)
+}
+
+/// Response to the `status` command.
+pub fn status<TId, TAddr, TNodeTable, TData, TStorage>(
+        service: &Service<TId, TAddr, TNodeTable, TData, TStorage>) -> Json
+        where TId: GenericId,
+              TAddr: Clone + Send + Sync,
+              TNodeTable: GenericNodeTable<TId, TAddr>,
+              TData: Send + Sync + Clone,
+              TStorage: GenericStorage<TId, TData> {
+    let health = service.health();
+    let counters = service.request_counters();
+    let mut requests = BTreeMap::new();
+    for &(name, value) in &[("ping", counters.ping), ("find_node", counters.find_node),
+                            ("find_value", counters.find_value), ("store", counters.store),
+                            ("sample", counters.sample), ("custom", counters.custom)] {
+        requests.insert(name.to_string(), Json::U64(value as u64));
+    }
+    let mut object = BTreeMap::new();
+    object.insert("node_id".to_string(), id_to_json(service.node_id()));
+    object.insert("table_size".to_string(), Json::U64(health.table_size as u64));
+    object.insert("clean_needed".to_string(), Json::Boolean(health.clean_needed));
+    object.insert("stored_items".to_string(), Json::U64(health.storage.items as u64));
+    object.insert("memory_bytes".to_string(),
+                  Json::U64(service.memory_report().total() as u64));
+    object.insert("requests".to_string(), Json::Object(requests));
+    Json::Object(object)
+}
+
+/// Response to the `table` command, nodes are sorted by distance.
+///
+/// Every node has its bucket by XOR distance from our ID and, if it has
+/// responded to us, the number of responses, the mean response time and
+/// the seconds since the last response.
+pub fn table<TId, TAddr, TNodeTable, TData, TStorage>(
+        service: &Service<TId, TAddr, TNodeTable, TData, TStorage>) -> Json
+        where TId: GenericId,
+              TAddr: Clone + Send + Sync + Debug,
+              TNodeTable: GenericNodeTable<TId, TAddr>,
+              TData: Send + Sync + Clone,
+              TStorage: GenericStorage<TId, TData> {
+    let table = service.node_table();
+    let now = service.clock().now();
+    let nodes = table.find(service.node_id(), table.len()).into_iter()
+        .map(|node| {
+            let mut object = BTreeMap::new();
+            object.insert("id".to_string(), id_to_json(&node.id));
+            object.insert("address".to_string(), Json::String(format!("{:?}", node.address)));
+            let distance = node.id.bitxor(service.node_id()).bits();
+            object.insert("bucket".to_string(), Json::U64(distance.saturating_sub(1) as u64));
+            if let Some(histogram) = service.node_rtt_histogram(&node.id) {
+                object.insert("responses".to_string(), Json::U64(histogram.count()));
+                if let Some(mean) = histogram.mean() {
+                    object.insert("rtt_ms".to_string(), Json::U64(mean.as_millis() as u64));
+                }
+            }
+            if let Some(at) = service.node_last_response(&node.id) {
+                let age = now.saturating_duration_since(at).as_secs();
+                object.insert("last_response_secs".to_string(), Json::U64(age));
+            }
+            Json::Object(object)
+        })
+        .collect();
+    Json::Array(nodes)
+}
+
+/// Connect to a control socket and describe the node behind it.
+///
+/// Sends `status` and `table` and formats the responses as text: a health
+/// summary, then the nodes of every bucket with their response times.
+pub fn inspect<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(Duration::from_secs(INSPECT_TIMEOUT)))?;
+    stream.write_all(b"status\ntable\n")?;
+    let mut lines = BufReader::new(stream).lines();
+    let mut next = || -> io::Result<Json> {
+        let line = lines.next().unwrap_or_else(|| {
+            Err(io::Error::new(io::ErrorKind::UnexpectedEof, "no response"))
+        })?;
+        Json::from_str(&line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    };
+    let status = next()?;
+    let table = next()?;
+    Ok(report(&status, &table))
+}
+
+/// Format `status` and `table` responses for `inspect`.
+fn report(status: &Json, table: &Json) -> String {
+    let number = |json: &Json, key: &str| json.find(key).and_then(Json::as_u64);
+    let mut res = String::new();
+    if let Some(message) = status.find("error").and_then(Json::as_string) {
+        let _ = writeln!(res, "Error: {}", message);
+    }
+    let _ = writeln!(res, "Node {}: {} nodes, {} stored items, {} bytes of memory{}",
+                     status.find("node_id").map_or("unknown".to_string(), text),
+                     number(status, "table_size").unwrap_or(0),
+                     number(status, "stored_items").unwrap_or(0),
+                     number(status, "memory_bytes").unwrap_or(0),
+                     if status.find("clean_needed") == Some(&Json::Boolean(true)) {
+                         ", clean up needed"
+                     } else { "" });
+    if let Some(requests) = status.find("requests").and_then(Json::as_object) {
+        let counts: Vec<String> = requests.iter()
+            .map(|(name, count)| format!("{} {}", name, text(count)))
+            .collect();
+        let _ = writeln!(res, "Requests: {}", counts.join(", "));
+    }
+
+    let mut buckets: BTreeMap<u64, Vec<&Json>> = BTreeMap::new();
+    for node in table.as_array().map_or(&[][..], |nodes| &nodes[..]) {
+        buckets.entry(number(node, "bucket").unwrap_or(0)).or_default().push(node);
+    }
+    for (bucket, nodes) in buckets {
+        let _ = writeln!(res, "Bucket {}: {} nodes", bucket, nodes.len());
+        for node in nodes {
+            let _ = write!(res, "  {} at {}",
+                           node.find("id").map_or("unknown".to_string(), text),
+                           node.find("address").map_or("unknown".to_string(), text));
+            match (number(node, "responses"), number(node, "last_response_secs")) {
+                (Some(responses), Some(age)) => {
+                    let _ = write!(res, ", {} responses, {} ms mean, last {} s ago",
+                                   responses, number(node, "rtt_ms").unwrap_or(0), age);
+                },
+                _ => res.push_str(", no responses")
+            }
+            res.push('\n');
+        }
+    }
+    res
+}
+
+/// Strings without quotes, everything else as JSON.
+fn text(json: &Json) -> String {
+    json.as_string().map_or_else(|| json.to_string(), str::to_string)
+}
+
+fn id_to_json<TId: GenericId>(id: &TId) -> Json {
+    let mut encoded = String::new();
+    id.encode(&mut json::Encoder::new(&mut encoded)).unwrap();
+    Json::from_str(&encoded).unwrap()
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+    use std::net;
+    use std::os::unix::net::UnixStream;
+    use std::process;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use rustc_serialize::json::Json;
+
+    use super::super::{GenericNodeTable, Service};
+    use super::super::mock::{MockClock, MockNodeTable};
+    use super::{Command, ControlServer};
+
+    use super::super::utils::test;
+    type TestsIdType = test::IdType;
+
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Ok(Command::Status), Command::<TestsIdType>::parse("status"));
+        assert_eq!(Ok(Command::Lookup(test::make_id(42))),
+                   Command::<TestsIdType>::parse(" lookup  2a "));
+        assert_eq!(Ok(Command::Ban("1.2.3.4:5".to_string())),
+                   Command::<TestsIdType>::parse("ban 1.2.3.4:5"));
+        assert!(Command::<TestsIdType>::parse("lookup zz").is_err());
+        assert!(Command::<TestsIdType>::parse("table 1").is_err());
+        assert!(Command::<TestsIdType>::parse("unban").is_err());
+        assert!(Command::<TestsIdType>::parse("reboot").is_err());
+    }
+
+    #[test]
+    fn test_server() {
+        let mut service: Service<TestsIdType, net::SocketAddr,
+                                 MockNodeTable<TestsIdType, net::SocketAddr>, String> =
+            Service::new_with_id(MockNodeTable::new(test::make_id(1)), test::make_id(1));
+        service.node_table_mut().update(&test::new_node(test::make_id(2)));
+
+        let path = env::temp_dir().join(format!("dht-control-{}.sock", process::id()));
+        let mut server = ControlServer::bind(&path).unwrap();
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"status\nfoo\ntable\nlookup 2a\n").unwrap();
+
+        let mut lookups = vec![];
+        let mut processed = 0;
+        while processed < 3 {
+            processed += server.poll::<TestsIdType, _>(|command| match command {
+                Command::Status => super::status(&service),
+                Command::Table => super::table(&service),
+                other => {
+                    lookups.push(other);
+                    Json::Null
+                }
+            }).unwrap();
+        }
+        assert_eq!(1, server.clients());
+        assert_eq!(vec![Command::Lookup(test::make_id(42))], lookups);
+
+        let lines: Vec<String> = BufReader::new(client).lines().take(4)
+            .map(|line| line.unwrap()).collect();
+        let status = Json::from_str(&lines[0]).unwrap();
+        assert_eq!(Some("01"), status["node_id"].as_string());
+        assert_eq!(Some(1), status["table_size"].as_u64());
+        assert!(status["memory_bytes"].as_u64().unwrap() > 0);
+        assert!(lines[1].contains("Unknown command foo"));
+        assert_eq!("[{\"address\":\"127.0.0.1:8008\",\"bucket\":1,\"id\":\"02\"}]", lines[2]);
+        assert_eq!("null", lines[3]);
+
+        drop(server);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_slow_client() {
+        let path = env::temp_dir().join(format!("dht-control-slow-{}.sock", process::id()));
+        let mut server = ControlServer::bind(&path).unwrap();
+        let large = "x".repeat(1024 * 1024);
+        let mut handler = |_: Command<TestsIdType>| Json::String(large.clone());
+
+        // Responses are queued instead of blocking the server
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"status\n").unwrap();
+        while server.poll(&mut handler).unwrap() == 0 { }
+        client.set_nonblocking(true).unwrap();
+        let mut received = vec![];
+        let mut chunk = [0u8; 65536];
+        while !received.ends_with(b"\n") {
+            match client.read(&mut chunk) {
+                Ok(size) => received.extend_from_slice(&chunk[..size]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    server.poll(&mut handler).unwrap();
+                },
+                Err(e) => panic!("{}", e)
+            }
+        }
+        assert_eq!(large.len() + 3, received.len());
+
+        drop(client);
+        while server.clients() > 0 {
+            server.poll(&mut handler).unwrap();
+        }
+
+        // Clients never reading are dropped
+        let mut hog = UnixStream::connect(&path).unwrap();
+        hog.write_all("status\n".repeat(20).as_bytes()).unwrap();
+        server.poll(&mut handler).unwrap();
+        assert_eq!(0, server.clients());
+    }
+
+    #[test]
+    fn test_bounded_reads() {
+        let path = env::temp_dir().join(format!("dht-control-bounded-{}.sock", process::id()));
+        let mut server = ControlServer::bind(&path).unwrap();
+        let mut handler = |_: Command<TestsIdType>| Json::Null;
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all("status\n".repeat(10000).as_bytes()).unwrap();
+
+        // Only about a line worth of input is read at once
+        let first = server.poll(&mut handler).unwrap();
+        assert!(first > 0 && first <= (super::MAX_LINE_SIZE + 512) / 7, "{}", first);
+        let mut processed = first;
+        while processed < 10000 {
+            processed += server.poll(&mut handler).unwrap();
+        }
+        assert_eq!(10000, processed);
+        assert_eq!(1, server.clients());
+
+        // A line longer than the limit drops the client
+        client.write_all("x".repeat(2 * super::MAX_LINE_SIZE).as_bytes()).unwrap();
+        while server.clients() > 0 {
+            server.poll(&mut handler).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_inspect() {
+        let clock = MockClock::new();
+        let mut service: Service<TestsIdType, net::SocketAddr,
+                                 MockNodeTable<TestsIdType, net::SocketAddr>, String> =
+            Service::new_with_id(MockNodeTable::new(test::make_id(1)), test::make_id(1));
+        service.set_clock(Arc::new(clock.clone()));
+        for i in 2..5 {
+            service.node_table_mut().update(&test::new_node_with_port(test::make_id(i), i as u16));
+        }
+        service.record_rtt(&test::make_id(2), Duration::from_millis(10));
+        service.record_rtt(&test::make_id(2), Duration::from_millis(30));
+        clock.advance(Duration::from_secs(5));
+        let status = super::status(&service);
+        let table = super::table(&service);
+
+        let path = env::temp_dir().join(format!("dht-control-inspect-{}.sock", process::id()));
+        let mut server = ControlServer::bind(&path).unwrap();
+        let server_thread = thread::spawn(move || {
+            let mut processed = 0;
+            while processed < 2 || server.clients() > 0 {
+                processed += server.poll::<TestsIdType, _>(|command| match command {
+                    Command::Status => status.clone(),
+                    _ => table.clone()
+                }).unwrap();
+                thread::sleep(Duration::from_millis(1));
+            }
+        });
+        let report = super::inspect(&path).unwrap();
+        server_thread.join().unwrap();
+        assert!(report.starts_with("Node 01: 3 nodes, 0 stored items, "), "{}", report);
+        let lines: Vec<&str> = report.lines().collect();
+        assert!(lines[1].starts_with("Requests: custom 0, find_node 0"), "{}", lines[1]);
+        assert_eq!(vec!["Bucket 1: 2 nodes",
+                        "  03 at 127.0.0.1:3, no responses",
+                        "  02 at 127.0.0.1:2, 2 responses, 20 ms mean, last 5 s ago",
+                        "Bucket 2: 1 nodes",
+                        "  04 at 127.0.0.1:4, no responses"], lines[2..].to_vec());
+        assert!(super::inspect(&path).is_err());
+    }
+}
@@ -0,0 +1,283 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+//! Keyspace crawler.
+//!
+//! Asks every discovered node for nodes close to a few random targets,
+//! until no new nodes show up. Sending the actual requests is up to
+//! the `NodeQuery` implementation.
+
+use std::collections::{HashSet, VecDeque};
+
+use super::{GenericId, Node};
+
+
+static CONCURRENCY: usize = 8;
+static QUERIES_PER_NODE: usize = 4;
+
+
+/// Trait for sending a find_node request to a particular node.
+pub trait NodeQuery<TId, TAddr> {
+    /// Ask `node` for the nodes it knows closest to `target`.
+    ///
+    /// Returns `None` if the node did not respond.
+    fn find_node(&mut self, node: &Node<TId, TAddr>, target: &TId)
+        -> Option<Vec<Node<TId, TAddr>>>;
+}
+
+/// State of a crawl, can be saved and used to resume it later.
+#[derive(Clone, Debug)]
+pub struct CrawlState<TId, TAddr> {
+    pending: VecDeque<Node<TId, TAddr>>,
+    seen: HashSet<TId>,
+    responded: usize,
+    failed: usize,
+}
+
+/// Crawler of the keyspace.
+pub struct Crawler<TId, TAddr> {
+    hash_size: usize,
+    concurrency: usize,
+    queries_per_node: usize,
+    state: CrawlState<TId, TAddr>,
+}
+
+
+impl<TId, TAddr> CrawlState<TId, TAddr>
+        where TId: GenericId,
+              TAddr: Clone {
+    /// Create a state with nodes to start from.
+    pub fn new(seeds: Vec<Node<TId, TAddr>>) -> CrawlState<TId, TAddr> {
+        let mut state = CrawlState {
+            pending: VecDeque::new(),
+            seen: HashSet::new(),
+            responded: 0,
+            failed: 0
+        };
+        for node in seeds {
+            state.add(node);
+        }
+        state
+    }
+
+    /// Nodes discovered but not queried yet.
+    pub fn pending(&self) -> &VecDeque<Node<TId, TAddr>> {
+        &self.pending
+    }
+
+    /// IDs of all nodes discovered so far.
+    pub fn seen(&self) -> &HashSet<TId> {
+        &self.seen
+    }
+
+    /// Number of nodes that responded to at least one query.
+    pub fn responded(&self) -> usize {
+        self.responded
+    }
+
+    /// Number of nodes that did not respond to any query.
+    pub fn failed(&self) -> usize {
+        self.failed
+    }
+
+    fn add(&mut self, node: Node<TId, TAddr>) -> bool {
+        if self.seen.insert(node.id.clone()) {
+            self.pending.push_back(node);
+            true
+        }
+        else {
+            false
+        }
+    }
+}
+
+impl<TId, TAddr> Crawler<TId, TAddr>
+        where TId: GenericId,
+              TAddr: Clone {
+    /// Create a crawler for IDs of `hash_size` bits starting from `seeds`.
+    pub fn new(hash_size: usize, seeds: Vec<Node<TId, TAddr>>) -> Crawler<TId, TAddr> {
+        Crawler::with_state(hash_size, CrawlState::new(seeds))
+    }
+
+    /// Resume a crawl from a saved state.
+    pub fn with_state(hash_size: usize, state: CrawlState<TId, TAddr>)
+            -> Crawler<TId, TAddr> {
+        Crawler {
+            hash_size,
+            concurrency: CONCURRENCY,
+            queries_per_node: QUERIES_PER_NODE,
+            state
+        }
+    }
+
+    /// Set how many nodes are queried on every step, 8 by default.
+    pub fn set_concurrency(&mut self, concurrency: usize) {
+        assert!(concurrency > 0);
+        self.concurrency = concurrency;
+    }
+
+    /// Set how many queries each node gets at most, 4 by default.
+    ///
+    /// Querying stops early when a node does not respond.
+    pub fn set_queries_per_node(&mut self, queries: usize) {
+        assert!(queries > 0);
+        self.queries_per_node = queries;
+    }
+
+    /// Get the current state.
+    pub fn state(&self) -> &CrawlState<TId, TAddr> {
+        &self.state
+    }
+
+    /// Stop crawling and get the state back.
+    pub fn into_state(self) -> CrawlState<TId, TAddr> {
+        self.state
+    }
+
+    /// Whether there are no nodes left to query.
+    pub fn is_done(&self) -> bool {
+        self.state.pending.is_empty()
+    }
+
+    /// Query the next batch of pending nodes.
+    ///
+    /// `visitor` is called once for every newly discovered node.
+    /// Returns the number of nodes discovered.
+    pub fn step<TQuery, TVisitor>(&mut self, query: &mut TQuery, mut visitor: TVisitor)
+            -> usize
+            where TQuery: NodeQuery<TId, TAddr>,
+                  TVisitor: FnMut(&Node<TId, TAddr>) {
+        let mut discovered = 0;
+        for _ in 0..self.concurrency {
+            let node = match self.state.pending.pop_front() {
+                Some(node) => node,
+                None => break
+            };
+            let mut responded = false;
+            for i in 0..self.queries_per_node {
+                // Our own ID first for the nodes closest to it
+                let target = if i == 0 { node.id.clone() } else { TId::gen(self.hash_size) };
+                let found = match query.find_node(&node, &target) {
+                    Some(found) => found,
+                    None => break
+                };
+                responded = true;
+                for found_node in found {
+                    if self.state.add(found_node.clone()) {
+                        visitor(&found_node);
+                        discovered += 1;
+                    }
+                }
+            }
+            if responded {
+                self.state.responded += 1;
+            }
+            else {
+                debug!("Node {:?} did not respond to the crawler", node.id);
+                self.state.failed += 1;
+            }
+        }
+        discovered
+    }
+
+    /// Run at most `max_steps` steps or until done.
+    ///
+    /// Returns the number of nodes discovered.
+    pub fn run<TQuery, TVisitor>(&mut self, query: &mut TQuery, mut visitor: TVisitor,
+                                 max_steps: usize) -> usize
+            where TQuery: NodeQuery<TId, TAddr>,
+                  TVisitor: FnMut(&Node<TId, TAddr>) {
+        let mut discovered = 0;
+        for _ in 0..max_steps {
+            if self.is_done() {
+                break;
+            }
+            discovered += self.step(query, &mut visitor);
+        }
+        discovered
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use rand::{Rng, SeedableRng, XorShiftRng};
+
+    use super::super::Node;
+    use super::{Crawler, NodeQuery};
+
+
+    /// Network where every node knows a few random others.
+    struct DummyNetwork {
+        neighbors: HashMap<u64, Vec<Node<u64, ()>>>,
+        down: u64,
+        queries: usize,
+    }
+
+    impl DummyNetwork {
+        fn new(count: usize) -> DummyNetwork {
+            let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+            let ids: Vec<u64> = (0..count as u64).map(|i| i + 1).collect();
+            let neighbors = ids.iter()
+                .map(|&id| (id, (0..5)
+                    .map(|_| Node { id: ids[rng.gen_range(0, count)], address: () })
+                    .collect()))
+                .collect();
+            DummyNetwork { neighbors, down: 0, queries: 0 }
+        }
+    }
+
+    impl NodeQuery<u64, ()> for DummyNetwork {
+        fn find_node(&mut self, node: &Node<u64, ()>, _target: &u64)
+                -> Option<Vec<Node<u64, ()>>> {
+            self.queries += 1;
+            if node.id == self.down {
+                None
+            }
+            else {
+                Some(self.neighbors[&node.id].clone())
+            }
+        }
+    }
+
+    #[test]
+    fn test_crawl() {
+        let mut network = DummyNetwork::new(100);
+        network.down = 2;
+        let mut crawler = Crawler::new(64, vec![Node { id: 1, address: () }]);
+        crawler.set_queries_per_node(1);
+        let mut visited = vec![];
+        let discovered = crawler.run(&mut network, |node| visited.push(node.id), 1000);
+        assert!(crawler.is_done());
+        assert_eq!(discovered, visited.len());
+        assert!(discovered > 50);
+        assert_eq!(discovered + 1, crawler.state().seen().len());
+        assert_eq!(crawler.state().responded() + crawler.state().failed(),
+                   crawler.state().seen().len());
+        assert_eq!(network.queries, crawler.state().seen().len());
+    }
+
+    #[test]
+    fn test_resume() {
+        let mut network = DummyNetwork::new(100);
+        let mut crawler = Crawler::new(64, vec![Node { id: 1, address: () }]);
+        crawler.set_concurrency(1);
+        crawler.set_queries_per_node(2);
+        crawler.step(&mut network, |_| ());
+        assert_eq!(2, network.queries);
+        let state = crawler.into_state();
+        assert_eq!(1, state.responded());
+
+        let mut crawler = Crawler::with_state(64, state.clone());
+        let total = crawler.run(&mut network, |_| (), 1000);
+        assert_eq!(state.seen().len() + total, crawler.state().seen().len());
+    }
+}
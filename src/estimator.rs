@@ -0,0 +1,154 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+//! Network size estimation.
+//!
+//! With N nodes spread uniformly over the ID space, the i-th closest node to
+//! a random target is expected at distance i / (N + 1) of the whole space.
+//! Fitting the distances observed in lookups to this line gives an estimate
+//! of N.
+
+use std::collections::VecDeque;
+use std::marker;
+
+use rustc_serialize::json;
+
+use super::GenericId;
+
+
+static MAX_SAMPLES: usize = 100;
+
+
+/// Estimator of the network size from lookup results.
+pub struct SizeEstimator<TId> {
+    hash_size: usize,
+    max_samples: usize,
+    /// Sums of i^2 and i * d_i for every observed lookup.
+    samples: VecDeque<(f64, f64)>,
+    _phantom: marker::PhantomData<TId>,
+}
+
+
+impl<TId> SizeEstimator<TId>
+        where TId: GenericId {
+    /// Create an estimator for IDs of `hash_size` bits.
+    ///
+    /// It keeps the last 100 lookups.
+    pub fn new(hash_size: usize) -> SizeEstimator<TId> {
+        SizeEstimator::with_max_samples(hash_size, MAX_SAMPLES)
+    }
+
+    /// Create an estimator keeping the last `max_samples` lookups.
+    pub fn with_max_samples(hash_size: usize, max_samples: usize) -> SizeEstimator<TId> {
+        assert!(max_samples > 0);
+        SizeEstimator {
+            hash_size,
+            max_samples,
+            samples: VecDeque::with_capacity(max_samples),
+            _phantom: marker::PhantomData
+        }
+    }
+
+    /// Record the closest nodes found by a lookup of `target`.
+    ///
+    /// `closest` should be all nodes found by a complete lookup, in any
+    /// order; the target itself is ignored.
+    pub fn observe(&mut self, target: &TId, closest: &[TId]) {
+        let mut distances: Vec<f64> = closest.iter()
+            .filter(|id| *id != target)
+            .map(|id| self.normalize(&id.bitxor(target)))
+            .collect();
+        if distances.is_empty() {
+            return;
+        }
+        distances.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let (mut squares, mut weighted) = (0.0, 0.0);
+        for (i, distance) in distances.iter().enumerate() {
+            let rank = (i + 1) as f64;
+            squares += rank * rank;
+            weighted += rank * distance;
+        }
+        if self.samples.len() >= self.max_samples {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((squares, weighted));
+    }
+
+    /// Number of lookups the estimate is based on.
+    pub fn samples(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Estimated number of nodes, if any lookups were observed.
+    pub fn estimate(&self) -> Option<f64> {
+        let (squares, weighted) = self.samples.iter()
+            .fold((0.0, 0.0), |(s, w), &(squares, weighted)| (s + squares, w + weighted));
+        if weighted > 0.0 {
+            Some(squares / weighted)
+        }
+        else {
+            None
+        }
+    }
+
+    /// Distance as a fraction of the whole ID space.
+    fn normalize(&self, distance: &TId) -> f64 {
+        // IDs only expose their hex encoding, which is enough for f64
+        let mut hex = String::new();
+        distance.encode(&mut json::Encoder::new(&mut hex)).unwrap();
+        let value = hex.trim_matches('"').chars()
+            .filter_map(|c| c.to_digit(16))
+            .fold(0.0, |acc, digit| acc * 16.0 + f64::from(digit));
+        value / 2f64.powi(self.hash_size as i32)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use rand::{Rng, SeedableRng, XorShiftRng};
+
+    use super::SizeEstimator;
+
+    use super::super::utils::test;
+
+
+    #[test]
+    fn test_empty() {
+        let mut e = SizeEstimator::<u64>::new(64);
+        assert_eq!(None, e.estimate());
+        e.observe(&42, &[42]);
+        assert_eq!(0, e.samples());
+    }
+
+    #[test]
+    fn test_estimate() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let ids: Vec<u64> = (0..2000).map(|_| rng.gen()).collect();
+        let mut e = SizeEstimator::with_max_samples(64, 50);
+        for _ in 0..100 {
+            let target: u64 = rng.gen();
+            let mut closest = ids.clone();
+            closest.sort_by_key(|id| id ^ target);
+            e.observe(&target, &closest[..8]);
+        }
+        assert_eq!(50, e.samples());
+        let estimate = e.estimate().unwrap();
+        assert!(estimate > 1400.0 && estimate < 2600.0, "{}", estimate);
+    }
+
+    #[test]
+    fn test_vec_ids() {
+        let mut e = SizeEstimator::new(8);
+        // Distances 1, 2 and 3 out of 256 fit 256 nodes exactly
+        e.observe(&test::make_id(0), &[test::make_id(1), test::make_id(2),
+                                       test::make_id(3)]);
+        assert_eq!(Some(256.0), e.estimate());
+    }
+}
@@ -0,0 +1,257 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+//! Detection of query floods.
+//!
+//! Queries are counted per address and per /24 or /48 subnet in sliding
+//! windows. Once a source goes over its limit, its queries should be
+//! dropped without a response until its rate falls to half of the limit.
+//! See `service::Handler::should_answer`.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::clock::{Clock, SystemClock};
+use super::subnettable::subnet;
+
+
+static WINDOW: u64 = 10;
+static MAX_PER_ADDRESS: u32 = 100;
+static MAX_PER_SUBNET: u32 = 400;
+
+
+/// Source of a flood.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FloodSource {
+    /// Single IP address.
+    Address(IpAddr),
+    /// Subnet, given by its first address.
+    Subnet(IpAddr)
+}
+
+/// Result of recording a query.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FloodCheck {
+    /// Answer the query.
+    Answer,
+    /// Drop the query, the source is already known to flood.
+    Drop,
+    /// Drop the query, the source has just gone over its limit.
+    Engage(FloodSource)
+}
+
+/// Detector of sources sending too many queries.
+pub struct FloodDetector {
+    window: Duration,
+    max_per_address: u32,
+    max_per_subnet: u32,
+    sources: HashMap<FloodSource, Window>,
+    last_sweep: Option<Instant>,
+    clock: Arc<dyn Clock>,
+}
+
+struct Window {
+    start: Instant,
+    current: u32,
+    previous: u32,
+    dropping: bool,
+}
+
+
+impl FloodDetector {
+    /// Create a detector allowing 100 queries per address and 400 per
+    /// subnet in 10 seconds.
+    pub fn new() -> FloodDetector {
+        FloodDetector::with_limits(Duration::from_secs(WINDOW), MAX_PER_ADDRESS, MAX_PER_SUBNET)
+    }
+
+    /// Create a detector allowing at most `max_per_address` queries from
+    /// an address and `max_per_subnet` from a subnet per `window`.
+    pub fn with_limits(window: Duration, max_per_address: u32,
+                       max_per_subnet: u32) -> FloodDetector {
+        assert!(window > Duration::from_secs(0));
+        FloodDetector {
+            window,
+            max_per_address,
+            max_per_subnet,
+            sources: HashMap::new(),
+            last_sweep: None,
+            clock: Arc::new(SystemClock)
+        }
+    }
+
+    /// Set the clock used for the windows.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Record a query from an address and check whether to answer it.
+    ///
+    /// Dropped queries are counted too, so a source keeps being dropped
+    /// for as long as it floods.
+    pub fn record(&mut self, address: &SocketAddr) -> FloodCheck {
+        let now = self.clock.now();
+        self.sweep(now);
+        let ip = address.ip().to_canonical();
+        let by_address = self.observe(FloodSource::Address(ip), self.max_per_address, now);
+        let by_subnet = self.observe(FloodSource::Subnet(subnet(address)),
+                                     self.max_per_subnet, now);
+        match (by_address, by_subnet) {
+            (FloodCheck::Engage(source), _) | (_, FloodCheck::Engage(source)) => {
+                warn!("Dropping queries from {:?}, too many in {:?}", source, self.window);
+                FloodCheck::Engage(source)
+            },
+            (FloodCheck::Answer, FloodCheck::Answer) => FloodCheck::Answer,
+            _ => FloodCheck::Drop
+        }
+    }
+
+    /// Sources which queries are being dropped.
+    pub fn flooding(&self) -> Vec<FloodSource> {
+        self.sources.iter()
+            .filter(|&(_, window)| window.dropping)
+            .map(|(source, _)| *source)
+            .collect()
+    }
+
+    fn observe(&mut self, source: FloodSource, limit: u32, now: Instant) -> FloodCheck {
+        let length = self.window;
+        let window = self.sources.entry(source).or_insert(Window {
+            start: now,
+            current: 0,
+            previous: 0,
+            dropping: false
+        });
+        window.advance(now, length);
+        window.current += 1;
+        let rate = window.rate(now, length);
+        if window.dropping {
+            if rate < limit as f64 / 2.0 {
+                debug!("Answering queries from {:?} again", source);
+                window.dropping = false;
+                return FloodCheck::Answer;
+            }
+            FloodCheck::Drop
+        }
+        else if rate > limit as f64 {
+            window.dropping = true;
+            FloodCheck::Engage(source)
+        }
+        else {
+            FloodCheck::Answer
+        }
+    }
+
+    /// Forget sources that were quiet for the last two windows.
+    fn sweep(&mut self, now: Instant) {
+        match self.last_sweep {
+            Some(last) if now.duration_since(last) < self.window => return,
+            _ => {}
+        }
+        self.last_sweep = Some(now);
+        let length = self.window;
+        self.sources.retain(|_, window| {
+            window.advance(now, length);
+            window.current > 0 || window.previous > 0
+        });
+    }
+}
+
+impl Default for FloodDetector {
+    fn default() -> FloodDetector {
+        FloodDetector::new()
+    }
+}
+
+impl Window {
+    fn advance(&mut self, now: Instant, length: Duration) {
+        let elapsed = now.duration_since(self.start);
+        if elapsed < length {
+            return;
+        }
+        let passed = (elapsed.as_nanos() / length.as_nanos()) as u32;
+        self.previous = if passed == 1 { self.current } else { 0 };
+        self.current = 0;
+        self.start += length * passed;
+    }
+
+    /// Estimated number of queries in the last `length`, weighting the
+    /// previous window by how much of it is still covered.
+    fn rate(&self, now: Instant, length: Duration) -> f64 {
+        let covered = now.duration_since(self.start).as_secs_f64() / length.as_secs_f64();
+        self.previous as f64 * (1.0 - covered) + self.current as f64
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::super::mock::MockClock;
+    use super::{FloodCheck, FloodDetector, FloodSource};
+
+
+    fn address(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_address_flood() {
+        let clock = MockClock::new();
+        let mut d = FloodDetector::with_limits(Duration::from_secs(10), 5, 100);
+        d.set_clock(Arc::new(clock.clone()));
+        for _ in 0..5 {
+            assert_eq!(FloodCheck::Answer, d.record(&address("10.0.0.1:1")));
+        }
+        assert_eq!(FloodCheck::Engage(FloodSource::Address("10.0.0.1".parse().unwrap())),
+                   d.record(&address("10.0.0.1:2")));
+        assert_eq!(FloodCheck::Drop, d.record(&address("10.0.0.1:1")));
+        assert_eq!(FloodCheck::Answer, d.record(&address("10.0.0.2:1")));
+        assert_eq!(1, d.flooding().len());
+
+        // 7 queries at the start of the previous window still count
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(FloodCheck::Drop, d.record(&address("10.0.0.1:1")));
+        // Only 2 queries in the last 10 seconds, below half of the limit
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(FloodCheck::Answer, d.record(&address("10.0.0.1:1")));
+        assert!(d.flooding().is_empty());
+    }
+
+    #[test]
+    fn test_subnet_flood() {
+        let clock = MockClock::new();
+        let mut d = FloodDetector::with_limits(Duration::from_secs(10), 5, 8);
+        d.set_clock(Arc::new(clock.clone()));
+        for i in 0..8 {
+            assert_eq!(FloodCheck::Answer, d.record(&address(&format!("10.0.0.{}:1", i))));
+        }
+        assert_eq!(FloodCheck::Engage(FloodSource::Subnet("10.0.0.0".parse().unwrap())),
+                   d.record(&address("[::ffff:10.0.0.42]:1")));
+        assert_eq!(FloodCheck::Drop, d.record(&address("10.0.0.43:1")));
+        assert_eq!(FloodCheck::Answer, d.record(&address("10.0.1.1:1")));
+    }
+
+    #[test]
+    fn test_sweep() {
+        let clock = MockClock::new();
+        let mut d = FloodDetector::with_limits(Duration::from_secs(10), 5, 100);
+        d.set_clock(Arc::new(clock.clone()));
+        d.record(&address("10.0.0.1:1"));
+        assert_eq!(2, d.sources.len());
+        clock.advance(Duration::from_secs(30));
+        d.record(&address("10.1.0.1:1"));
+        assert_eq!(2, d.sources.len());
+    }
+}
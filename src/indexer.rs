@@ -0,0 +1,325 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+//! Index of value IDs seen in the network.
+
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use rand;
+use rand::Rng;
+use rustc_serialize as serialize;
+use rustc_serialize::json;
+
+use super::GenericId;
+use super::clock::{Clock, SystemClock};
+
+
+static MAX_INDEX_ENTRIES: usize = 1000000;
+
+/// Deduplicated set of value IDs with the time they were first and last seen.
+///
+/// IDs come from requests passing through `Service` (see
+/// `Service::set_indexer`) and from sample responses of other nodes.
+/// The index can be saved to and loaded from JSON.
+///
+/// When the index is full, the IDs last seen longest ago are dropped.
+pub struct Indexer<TId> {
+    entries: HashMap<TId, IndexEntry>,
+    by_last_seen: BTreeSet<(u64, TId)>,
+    max_entries: usize,
+    clock: Arc<dyn Clock>,
+    // Clock reading and the UNIX time it corresponds to
+    epoch: (Instant, u64),
+}
+
+/// Times an ID was seen, in seconds since the UNIX epoch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IndexEntry {
+    /// When the ID was seen for the first time.
+    pub first_seen: u64,
+    /// When the ID was seen for the last time.
+    pub last_seen: u64,
+}
+
+/// ID with its entry, as stored in JSON.
+struct SavedEntry<TId> {
+    id: TId,
+    entry: IndexEntry,
+}
+
+
+impl<TId> Indexer<TId>
+        where TId: GenericId {
+    /// Create an empty index keeping at most 1000000 IDs.
+    pub fn new() -> Indexer<TId> {
+        Indexer::with_limit(MAX_INDEX_ENTRIES)
+    }
+
+    /// Create an empty index keeping at most `max_entries` IDs.
+    pub fn with_limit(max_entries: usize) -> Indexer<TId> {
+        assert!(max_entries > 0);
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        Indexer {
+            entries: HashMap::new(),
+            by_last_seen: BTreeSet::new(),
+            max_entries,
+            epoch: epoch(&*clock),
+            clock
+        }
+    }
+
+    /// Set the clock used to timestamp IDs recorded with `record`.
+    ///
+    /// `unix_time` -- UNIX time in seconds corresponding to the current
+    /// reading of the clock, timestamps count from it.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>, unix_time: u64) {
+        self.epoch = (clock.now(), unix_time);
+        self.clock = clock;
+    }
+
+    /// Record an ID seen right now.
+    pub fn record(&mut self, id: TId) {
+        let (start, start_secs) = self.epoch;
+        let now = start_secs + self.clock.now().duration_since(start).as_secs();
+        self.record_at(id, now);
+    }
+
+    /// Record an ID seen at a given time.
+    pub fn record_at(&mut self, id: TId, timestamp: u64) {
+        if let Some(entry) = self.entries.get_mut(&id) {
+            entry.first_seen = entry.first_seen.min(timestamp);
+            if timestamp > entry.last_seen {
+                self.by_last_seen.remove(&(entry.last_seen, id.clone()));
+                self.by_last_seen.insert((timestamp, id));
+                entry.last_seen = timestamp;
+            }
+            return;
+        }
+        if self.entries.len() >= self.max_entries {
+            match self.by_last_seen.first() {
+                Some(&(last_seen, _)) if last_seen <= timestamp => {},
+                _ => {
+                    debug!("Not indexing {:?} seen at {} - index is full", id, timestamp);
+                    return;
+                }
+            }
+            let (_, oldest) = self.by_last_seen.pop_first().unwrap();
+            self.entries.remove(&oldest);
+        }
+        self.by_last_seen.insert((timestamp, id.clone()));
+        self.entries.insert(id, IndexEntry {
+            first_seen: timestamp,
+            last_seen: timestamp
+        });
+    }
+
+    /// Record IDs from a sample response.
+    pub fn record_sample(&mut self, ids: &[TId]) {
+        for id in ids {
+            self.record(id.clone());
+        }
+    }
+
+    /// Get the entry for an ID.
+    pub fn get(&self, id: &TId) -> Option<IndexEntry> {
+        self.entries.get(id).cloned()
+    }
+
+    /// Number of IDs in the index.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether the index is empty.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Get at most `count` IDs chosen uniformly at random.
+    ///
+    /// Uses reservoir sampling, so only the sampled IDs are copied.
+    pub fn sample(&self, count: usize) -> Vec<TId> {
+        let mut rng = rand::thread_rng();
+        let mut res = Vec::with_capacity(count.min(self.entries.len()));
+        for (index, id) in self.entries.keys().enumerate() {
+            if index < count {
+                res.push(id.clone());
+            }
+            else {
+                let replace = rng.gen_range(0, index + 1);
+                if replace < count {
+                    res[replace] = id.clone();
+                }
+            }
+        }
+        res
+    }
+
+    /// Write the index as JSON.
+    pub fn save<W: Write>(&self, output: &mut W) -> io::Result<()> {
+        let mut saved: Vec<SavedEntry<TId>> = self.entries.iter()
+            .map(|(id, entry)| SavedEntry { id: id.clone(), entry: *entry })
+            .collect();
+        saved.sort_by(|a, b| a.id.cmp(&b.id));
+        let encoded = json::encode(&saved)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        output.write_all(encoded.as_bytes())
+    }
+
+    /// Read an index written by `save`.
+    ///
+    /// At most 1000000 IDs are loaded, the ones last seen most recently.
+    pub fn load<R: Read>(input: &mut R) -> io::Result<Indexer<TId>> {
+        let mut encoded = String::new();
+        input.read_to_string(&mut encoded)?;
+        let saved: Vec<SavedEntry<TId>> = json::decode(&encoded)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut res = Indexer::new();
+        for s in saved {
+            res.record_at(s.id.clone(), s.entry.last_seen);
+            res.record_at(s.id, s.entry.first_seen);
+        }
+        Ok(res)
+    }
+}
+
+impl<TId> Default for Indexer<TId>
+        where TId: GenericId {
+    fn default() -> Indexer<TId> {
+        Indexer::new()
+    }
+}
+
+/// Current reading of a clock and the current UNIX time in seconds.
+fn epoch(clock: &dyn Clock) -> (Instant, u64) {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    (clock.now(), secs)
+}
+
+impl<TId> serialize::Encodable for SavedEntry<TId>
+        where TId: GenericId {
+    fn encode<S:serialize::Encoder> (&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_struct("SavedEntry", 3, |s| {
+            s.emit_struct_field("id", 0, |s2| self.id.encode(s2))?;
+            s.emit_struct_field("first_seen", 1, |s2| s2.emit_u64(self.entry.first_seen))?;
+            s.emit_struct_field("last_seen", 2, |s2| s2.emit_u64(self.entry.last_seen))
+        })
+    }
+}
+
+impl<TId> serialize::Decodable for SavedEntry<TId>
+        where TId: GenericId {
+    fn decode<D:serialize::Decoder> (d : &mut D) -> Result<SavedEntry<TId>, D::Error> {
+        d.read_struct("SavedEntry", 3, |d| {
+            let id = d.read_struct_field("id", 0, TId::decode)?;
+            let first_seen = d.read_struct_field("first_seen", 1, |d2| d2.read_u64())?;
+            let last_seen = d.read_struct_field("last_seen", 2, |d2| d2.read_u64())?;
+            Ok(SavedEntry {
+                id,
+                entry: IndexEntry { first_seen, last_seen }
+            })
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::super::mock::MockClock;
+    use super::{IndexEntry, Indexer};
+
+    use super::super::utils::test;
+    type TestsIdType = test::IdType;
+
+
+    #[test]
+    fn test_record() {
+        let mut i = Indexer::<TestsIdType>::new();
+        assert!(i.is_empty());
+        i.record_at(test::make_id(42), 100);
+        i.record_at(test::make_id(42), 50);
+        i.record_at(test::make_id(42), 200);
+        i.record_sample(&[test::make_id(43), test::make_id(42)]);
+        assert_eq!(2, i.len());
+        let entry = i.get(&test::make_id(42)).unwrap();
+        assert_eq!(50, entry.first_seen);
+        assert!(entry.last_seen > 200);
+        assert_eq!(2, i.sample(10).len());
+        assert_eq!(1, i.sample(1).len());
+    }
+
+    #[test]
+    fn test_record_clock() {
+        let clock = MockClock::new();
+        let mut i = Indexer::<TestsIdType>::new();
+        i.set_clock(Arc::new(clock.clone()), 1000);
+        i.record(test::make_id(42));
+        clock.advance(Duration::from_secs(100));
+        i.record(test::make_id(42));
+        assert_eq!(Some(IndexEntry { first_seen: 1000, last_seen: 1100 }),
+                   i.get(&test::make_id(42)));
+    }
+
+    #[test]
+    fn test_limit() {
+        let mut i = Indexer::<TestsIdType>::with_limit(2);
+        i.record_at(test::make_id(42), 100);
+        i.record_at(test::make_id(43), 200);
+        i.record_at(test::make_id(42), 300);
+        // Seen before everything else in the full index
+        i.record_at(test::make_id(44), 50);
+        assert!(i.get(&test::make_id(44)).is_none());
+        i.record_at(test::make_id(45), 400);
+        assert_eq!(2, i.len());
+        assert!(i.get(&test::make_id(43)).is_none());
+        assert_eq!(Some(IndexEntry { first_seen: 100, last_seen: 300 }),
+                   i.get(&test::make_id(42)));
+        assert!(i.get(&test::make_id(45)).is_some());
+        assert_eq!(2, i.by_last_seen.len());
+    }
+
+    #[test]
+    fn test_sample() {
+        let mut i = Indexer::<TestsIdType>::new();
+        assert!(i.sample(5).is_empty());
+        for id in 0..100 {
+            i.record_at(test::make_id(id), 100);
+        }
+        let mut sample = i.sample(5);
+        sample.sort();
+        sample.dedup();
+        assert_eq!(5, sample.len());
+        assert_eq!(100, i.sample(200).len());
+        assert!(i.sample(0).is_empty());
+    }
+
+    #[test]
+    fn test_save_load() {
+        let mut i = Indexer::<TestsIdType>::new();
+        i.record_at(test::make_id(42), 100);
+        i.record_at(test::make_id(43), 200);
+        let mut saved = vec![];
+        i.save(&mut saved).unwrap();
+        assert_eq!("[{\"id\":\"2a\",\"first_seen\":100,\"last_seen\":100},\
+                    {\"id\":\"2b\",\"first_seen\":200,\"last_seen\":200}]",
+                   String::from_utf8(saved.clone()).unwrap());
+
+        let loaded = Indexer::<TestsIdType>::load(&mut &saved[..]).unwrap();
+        assert_eq!(2, loaded.len());
+        assert_eq!(Some(IndexEntry { first_seen: 200, last_seen: 200 }),
+                   loaded.get(&test::make_id(43)));
+        assert!(Indexer::<TestsIdType>::load(&mut &b"[{}]"[..]).is_err());
+    }
+}
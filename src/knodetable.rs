@@ -14,10 +14,13 @@
 //! no RPC call is done. It is up to upper-level code to ensure proper clean up
 //! using `pop_oldest` call.
 
-use std::cmp;
-use std::fmt::Debug;
+use std::fmt::{Debug, Display};
 use std::collections::VecDeque;
 
+use rustc_serialize as serialize;
+use rustc_serialize::json;
+
+use super::{Distance, XorDistance};
 use super::GenericId;
 use super::GenericNodeTable;
 use super::Node;
@@ -33,11 +36,14 @@ static HASH_SIZE: usize = 64;
 /// Keeps nodes in a number of k-buckets (equal to bit size of ID in a system,
 /// usually 160), where N-th k-bucket contains nodes with distance
 /// from 2^N to 2^(N+1) from our node.
-pub struct KNodeTable<TId, TAddr> {
+///
+/// The distance is XOR by default, see `with_distance` for other metrics.
+pub struct KNodeTable<TId, TAddr, TDistance = XorDistance> {
     this_id: TId,
     hash_size: usize,
     // TODO(divius): convert to more appropriate data structure
     buckets: Vec<KBucket<TId, TAddr>>,
+    distance: TDistance,
 }
 
 /// K-bucket - structure for keeping last nodes in Kademlia.
@@ -57,24 +63,35 @@ impl<TId, TAddr> KNodeTable<TId, TAddr>
         KNodeTable::with_details(this_id, BUCKET_SIZE, HASH_SIZE)
     }
 
-    // TODO(divius): make public?
-    fn with_details(this_id: TId, bucket_size: usize,
-                    hash_size: usize) -> KNodeTable<TId, TAddr> {
+    /// Create a new node table with a given k-bucket size and ID width.
+    ///
+    /// `hash_size` -- number of bits in IDs, e.g. 256 for `[u8; 32]`.
+    pub fn with_details(this_id: TId, bucket_size: usize,
+                        hash_size: usize) -> KNodeTable<TId, TAddr> {
+        KNodeTable::with_distance(this_id, bucket_size, hash_size, XorDistance)
+    }
+}
+
+impl<TId, TAddr, TDistance> KNodeTable<TId, TAddr, TDistance>
+        where TId: GenericId,
+              TAddr: Clone + Debug,
+              TDistance: Distance<TId> {
+    /// Create a new node table with a custom metric.
+    ///
+    /// `hash_size` is the maximum number of bits in a distance.
+    pub fn with_distance(this_id: TId, bucket_size: usize, hash_size: usize,
+                         distance: TDistance) -> KNodeTable<TId, TAddr, TDistance> {
         KNodeTable {
-            this_id: this_id,
-            hash_size: hash_size,
+            this_id,
+            hash_size,
             buckets: (0..hash_size).map(
                               |_| KBucket::new(bucket_size)).collect(),
+            distance,
         }
     }
 
-    #[inline]
-    fn distance(id1: &TId, id2: &TId) -> TId {
-        id1.bitxor(id2)
-    }
-
     fn bucket_number(&self, id: &TId) -> usize {
-        let diff = KNodeTable::<TId, TAddr>::distance(&self.this_id, id);
+        let diff = self.distance.distance(&self.this_id, id);
         debug_assert!(!diff.is_zero());
         let res = diff.bits() - 1;
         debug!("ID {:?} relative to own ID {:?} falls into bucket {:?}",
@@ -83,9 +100,104 @@ impl<TId, TAddr> KNodeTable<TId, TAddr>
     }
 }
 
-impl<TId, TAddr> GenericNodeTable<TId, TAddr> for KNodeTable<TId, TAddr>
+impl<TId, TAddr, TDistance> KNodeTable<TId, TAddr, TDistance>
         where TId: GenericId,
-              TAddr: Clone + Debug + Sync + Send {
+              TAddr: Clone + Debug + Display,
+              TDistance: Distance<TId> {
+    /// Export the table structure as JSON.
+    ///
+    /// Only non-empty buckets are included, see `Encodable` implementation.
+    pub fn export_json(&self) -> String {
+        json::encode(self).unwrap()
+    }
+
+    /// Export the table structure in Graphviz DOT format.
+    ///
+    /// Nodes are grouped by the number of leading bits they share with
+    /// the current node ID.
+    pub fn export_dot(&self) -> String {
+        let this_id = id_to_string(&self.this_id);
+        let mut res = format!("digraph nodetable {{\n    \"{}\" [shape=box];\n",
+                              this_id);
+        for (index, bucket) in self.buckets.iter().enumerate() {
+            if bucket.data.is_empty() {
+                continue;
+            }
+            let prefix = self.hash_size - index - 1;
+            res.push_str(&format!(
+                "    \"bucket{}\" [shape=ellipse, label=\"{} common bits ({}/{})\"];\n",
+                index, prefix, bucket.data.len(), bucket.size));
+            res.push_str(&format!("    \"{}\" -> \"bucket{}\";\n", this_id, index));
+            for node in &bucket.data {
+                let id = id_to_string(&node.id);
+                res.push_str(&format!("    \"{}\" [label=\"{}\\n{}\"];\n",
+                                      id, id, node.address));
+                res.push_str(&format!("    \"bucket{}\" -> \"{}\";\n", index, id));
+            }
+        }
+        res.push_str("}\n");
+        res
+    }
+}
+
+impl<TId, TAddr, TDistance> serialize::Encodable for KNodeTable<TId, TAddr, TDistance>
+        where TId: GenericId,
+              TAddr: Display {
+    fn encode<S:serialize::Encoder> (&self, s: &mut S) -> Result<(), S::Error> {
+        let buckets: Vec<_> = self.buckets.iter().enumerate()
+            .filter(|&(_, b)| !b.data.is_empty())
+            .collect();
+        s.emit_struct("KNodeTable", 3, |s| {
+            s.emit_struct_field("this_id", 0, |s2| self.this_id.encode(s2))?;
+            s.emit_struct_field("hash_size", 1, |s2| s2.emit_usize(self.hash_size))?;
+            s.emit_struct_field("buckets", 2, |s2| {
+                s2.emit_seq(buckets.len(), |s3| {
+                    for (i, &(index, bucket)) in buckets.iter().enumerate() {
+                        s3.emit_seq_elt(i, |s4| encode_bucket(index, bucket, s4))?;
+                    }
+                    Ok(())
+                })
+            })
+        })
+    }
+}
+
+fn encode_bucket<TId, TAddr, S>(index: usize, bucket: &KBucket<TId, TAddr>, s: &mut S)
+        -> Result<(), S::Error>
+        where TId: GenericId,
+              TAddr: Display,
+              S: serialize::Encoder {
+    s.emit_struct("KBucket", 3, |s| {
+        s.emit_struct_field("index", 0, |s2| s2.emit_usize(index))?;
+        s.emit_struct_field("size", 1, |s2| s2.emit_usize(bucket.size))?;
+        s.emit_struct_field("nodes", 2, |s2| {
+            s2.emit_seq(bucket.data.len(), |s3| {
+                for (i, node) in bucket.data.iter().enumerate() {
+                    s3.emit_seq_elt(i, |s4| {
+                        s4.emit_struct("Node", 2, |s5| {
+                            s5.emit_struct_field("address", 0, |s6| {
+                                s6.emit_str(&format!("{}", node.address))
+                            })?;
+                            s5.emit_struct_field("id", 1, |s6| node.id.encode(s6))
+                        })
+                    })?;
+                }
+                Ok(())
+            })
+        })
+    })
+}
+
+fn id_to_string<TId: GenericId>(id: &TId) -> String {
+    let mut res = String::new();
+    id.encode(&mut json::Encoder::new(&mut res)).unwrap();
+    res.trim_matches('"').to_string()
+}
+
+impl<TId, TAddr, TDistance> GenericNodeTable<TId, TAddr> for KNodeTable<TId, TAddr, TDistance>
+        where TId: GenericId,
+              TAddr: Clone + Debug + Sync + Send,
+              TDistance: Distance<TId> {
     fn random_id(&self) -> TId {
         TId::gen(self.hash_size)
     }
@@ -98,9 +210,9 @@ impl<TId, TAddr> GenericNodeTable<TId, TAddr> for KNodeTable<TId, TAddr>
 
     fn find(&self, id: &TId, count: usize) -> Vec<Node<TId, TAddr>> {
         debug_assert!(count > 0);
-        assert!(*id != self.this_id);
-        let bucket = self.bucket_number(id);
-        self.buckets[bucket].find(id, count)
+        // The bucket of `id` may have less than `count` nodes (or be empty
+        // when `id` is close to our own ID), so look through all of them.
+        closest(self.buckets.iter().flat_map(|b| b.data.iter()), id, count, &self.distance)
     }
 
     fn pop_oldest(&mut self) -> Vec<Node<TId, TAddr>> {
@@ -111,6 +223,43 @@ impl<TId, TAddr> GenericNodeTable<TId, TAddr> for KNodeTable<TId, TAddr>
             .map(|b| b.data.pop_front().unwrap())
             .collect()
     }
+
+    fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.data.len()).sum()
+    }
+
+    fn contains(&self, id: &TId) -> bool {
+        *id != self.this_id &&
+            self.buckets[self.bucket_number(id)].data.iter().any(|n| n.id == *id)
+    }
+
+    fn remove(&mut self, id: &TId) -> Option<Node<TId, TAddr>> {
+        if *id == self.this_id {
+            return None;
+        }
+        let bucket = self.bucket_number(id);
+        let data = &mut self.buckets[bucket].data;
+        let index = data.iter().position(|n| n.id == *id)?;
+        data.remove(index)
+    }
+}
+
+/// Find `count` nodes closest to `id`, sorted by distance.
+///
+/// Every distance is computed once and only the closest nodes are sorted.
+fn closest<'a, TId, TAddr, TDistance, I>(nodes: I, id: &TId, count: usize,
+                                         distance: &TDistance) -> Vec<Node<TId, TAddr>>
+        where TId: GenericId + 'a,
+              TAddr: Clone + 'a,
+              TDistance: Distance<TId>,
+              I: Iterator<Item=&'a Node<TId, TAddr>> {
+    let mut res: Vec<_> = nodes.map(|node| (distance.distance(id, &node.id), node)).collect();
+    if res.len() > count {
+        res.select_nth_unstable_by(count - 1, |a, b| a.0.cmp(&b.0));
+        res.truncate(count);
+    }
+    res.sort_by(|a, b| a.0.cmp(&b.0));
+    res.into_iter().map(|(_, node)| node.clone()).collect()
 }
 
 impl<TId, TAddr> KBucket<TId, TAddr>
@@ -141,22 +290,12 @@ impl<TId, TAddr> KBucket<TId, TAddr>
         }
     }
 
-    pub fn find(&self, id: &TId, count: usize) -> Vec<Node<TId, TAddr>> {
-        let sort_fn = |a: &Node<TId, TAddr>, b: &Node<TId, TAddr>| {
-            KNodeTable::<TId, TAddr>::distance(id, &a.id)
-                .cmp(&KNodeTable::<TId, TAddr>::distance(id, &b.id))
-        };
-        let mut data_copy: Vec<_> = self.data.iter().map(|n| n.clone()).collect();
-        data_copy.sort_by(sort_fn);
-        data_copy[0..cmp::min(count, data_copy.len())].to_vec()
-    }
-
     fn update_position(&mut self, node: Node<TId, TAddr>) {
         // TODO(divius): 1. optimize, 2. make it less ugly
         let mut new_data = VecDeque::with_capacity(self.data.len());
         new_data.extend(self.data.iter()
                         .filter(|x| x.id != node.id)
-                        .map(|x| x.clone()));
+                        .cloned());
         new_data.push_back(node.clone());
         self.data = new_data;
     }
@@ -167,7 +306,8 @@ impl<TId, TAddr> KBucket<TId, TAddr>
 mod test {
     use std::net;
 
-    use super::super::GenericNodeTable;
+    use super::super::{GenericNodeTable, XorDistance};
+    use super::super::smallworld::RingDistance;
     use super::super::Node;
 
     use super::HASH_SIZE;
@@ -186,7 +326,7 @@ mod test {
         }
     }
 
-    fn assert_node_list_eq(expected: &[&Node<TestsIdType, net::SocketAddr>], actual: &Vec<Node<TestsIdType, net::SocketAddr>>) {
+    fn assert_node_list_eq(expected: &[&Node<TestsIdType, net::SocketAddr>], actual: &[Node<TestsIdType, net::SocketAddr>]) {
         let act: Vec<TestsIdType> = actual.iter()
             .map(|n| n.id.clone()).collect();
         let exp: Vec<TestsIdType> = expected.iter()
@@ -233,6 +373,7 @@ mod test {
             buckets: vec![prepare(1), prepare(3), prepare(1)],
             this_id: test::make_id(0),
             hash_size: HASH_SIZE,
+            distance: XorDistance,
         };
         // 0 xor 3 = 3, 1 xor 3 = 2, 2 xor 3 = 1
         let id = test::make_id(3);
@@ -240,15 +381,43 @@ mod test {
                             &n.find(&id, 1));
     }
 
+    #[test]
+    fn test_nodetable_find_all_buckets() {
+        let mut n = KNodeTable::with_details(test::make_id(42), 2, HASH_SIZE);
+        n.update(&test::new_node(test::make_id(41)));
+        n.update(&test::new_node(test::make_id(52)));
+        n.update(&test::new_node(test::make_id(200)));
+        // 43 falls into the empty bucket 0
+        let found = n.find(&test::make_id(43), 2);
+        assert_eq!(vec![test::make_id(41), test::make_id(52)],
+                   found.iter().map(|n| n.id.clone()).collect::<Vec<_>>());
+        // Own ID is allowed, e.g. for bootstrapping
+        assert_eq!(3, n.find(&test::make_id(42), 10).len());
+    }
+
     #[test]
     fn test_nodetable_update() {
         let mut n = KNodeTable::with_details(
             test::make_id(42), 1, HASH_SIZE);
         let node = test::new_node(test::make_id(41));
+        assert!(n.is_empty());
         n.update(&node);
         assert_eq!(1, n.buckets[1].data.len());
         n.update(&node);
         assert_eq!(1, n.buckets[1].data.len());
+        assert_eq!(1, n.len());
+    }
+
+    #[test]
+    fn test_nodetable_remove() {
+        let mut n = KNodeTable::new(test::make_id(42));
+        n.update(&test::new_node(test::make_id(41)));
+        n.update(&test::new_node(test::make_id(43)));
+        assert_eq!(test::make_id(41), n.remove(&test::make_id(41)).unwrap().id);
+        assert!(n.remove(&test::make_id(41)).is_none());
+        assert!(n.remove(&test::make_id(42)).is_none());
+        assert!(!n.contains(&test::make_id(41)));
+        assert_eq!(1, n.len());
     }
 
     #[test]
@@ -261,6 +430,28 @@ mod test {
         assert!(n.random_id() != n.random_id());
     }
 
+    #[test]
+    fn test_nodetable_export_json() {
+        let mut n = KNodeTable::with_details(test::make_id(42), 2, HASH_SIZE);
+        n.update(&test::new_node(test::make_id(41)));
+        assert_eq!("{\"this_id\":\"2a\",\"hash_size\":64,\"buckets\":[\
+                    {\"index\":1,\"size\":2,\"nodes\":[\
+                    {\"address\":\"127.0.0.1:8008\",\"id\":\"29\"}]}]}",
+                   n.export_json());
+    }
+
+    #[test]
+    fn test_nodetable_export_dot() {
+        let mut n = KNodeTable::with_details(test::make_id(42), 2, HASH_SIZE);
+        n.update(&test::new_node(test::make_id(41)));
+        let dot = n.export_dot();
+        assert!(dot.starts_with("digraph nodetable {\n"));
+        assert!(dot.contains("\"bucket1\" [shape=ellipse, label=\"62 common bits (1/2)\"];\n"));
+        assert!(dot.contains("\"2a\" -> \"bucket1\";\n"));
+        assert!(dot.contains("\"bucket1\" -> \"29\";\n"));
+        assert!(dot.ends_with("}\n"));
+    }
+
     #[test]
     fn test_kbucket_new() {
         let b = KBucket::<TestsIdType, net::SocketAddr>::new(3);
@@ -294,22 +485,34 @@ mod test {
     }
 
     #[test]
-    fn test_kbucket_find() {
+    fn test_closest() {
         let b = prepare(3);
         // Nodes with ID's 0, 1, 2; assume our ID is also 2 (impossible IRL)
         let id = test::make_id(2);
         // 0 xor 2 = 2, 1 xor 2 = 3, 2 xor 2 = 0
-        assert_node_list_eq(&[&b.data[2]], &b.find(&id, 1));
-        assert_node_list_eq(&[&b.data[2], &b.data[0]], &b.find(&id, 2));
+        assert_node_list_eq(&[&b.data[2]], &super::closest(b.data.iter(), &id, 1, &XorDistance));
+        assert_node_list_eq(&[&b.data[2], &b.data[0]], &super::closest(b.data.iter(), &id, 2, &XorDistance));
     }
 
     #[test]
-    fn test_kbucket_find_too_much() {
+    fn test_closest_too_much() {
         let b = prepare(3);
         // Nodes with ID's 0, 1, 2; assume our ID is also 2 (impossible IRL)
         let id = test::make_id(2);
         // 0 xor 2 = 2, 1 xor 2 = 3, 2 xor 2 = 0
         assert_node_list_eq(&[&b.data[2], &b.data[0], &b.data[1]],
-                            &b.find(&id, 100));
+                            &super::closest(b.data.iter(), &id, 100, &XorDistance));
+    }
+
+    #[test]
+    fn test_nodetable_distance() {
+        let mut n = KNodeTable::with_distance(0u64, 2, HASH_SIZE, RingDistance);
+        for &id in &[1u64, 10, u64::MAX, u64::MAX - 20] {
+            assert!(n.update(&Node { id, address: () }));
+        }
+        let found: Vec<u64> = n.find(&(u64::MAX - 5), 2).iter().map(|n| n.id).collect();
+        assert_eq!(vec![u64::MAX, 1], found);
+        assert_eq!(vec![u64::MAX - 20, u64::MAX],
+                   n.find(&(u64::MAX - 15), 2).iter().map(|n| n.id).collect::<Vec<_>>());
     }
 }
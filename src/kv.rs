@@ -0,0 +1,236 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+//! Plain key/value store on top of the DHT.
+//!
+//! Values are stored on the nodes closest to their keys, re-published
+//! periodically while this node is around and copied to the closest nodes
+//! missing them whenever they are read.
+
+use std::marker;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{GenericAPI, GenericId, Node, PublishSet};
+use super::clock::Clock;
+
+
+static REPLICAS: usize = 8;
+
+
+/// Key/value store over any `GenericAPI` implementation.
+///
+/// Expects `api` to call callbacks before returning, like `PublishSet` does.
+pub struct KvDht<TId, TAddr, TAPI>
+        where TId: GenericId,
+              TAPI: GenericAPI<TId, TAddr> {
+    api: TAPI,
+    published: PublishSet<TId, TAPI::TValue>,
+    replicas: usize,
+    _phantom: marker::PhantomData<TAddr>,
+}
+
+
+impl<TId, TAddr, TAPI> KvDht<TId, TAddr, TAPI>
+        where TId: GenericId,
+              TAPI: GenericAPI<TId, TAddr> {
+    /// Create a store keeping values on 8 closest nodes.
+    pub fn new(api: TAPI) -> KvDht<TId, TAddr, TAPI> {
+        KvDht {
+            api,
+            published: PublishSet::new(),
+            replicas: REPLICAS,
+            _phantom: marker::PhantomData
+        }
+    }
+
+    /// Get the underlying API.
+    pub fn api(&self) -> &TAPI {
+        &self.api
+    }
+
+    /// Get a mutable reference to the underlying API.
+    pub fn api_mut(&mut self) -> &mut TAPI {
+        &mut self.api
+    }
+
+    /// Stop the store and get the underlying API back.
+    pub fn into_api(self) -> TAPI {
+        self.api
+    }
+
+    /// Set on how many closest nodes values are stored, 8 by default.
+    pub fn set_replicas(&mut self, replicas: usize) {
+        assert!(replicas > 0);
+        self.replicas = replicas;
+    }
+
+    /// Set how often values put by this node are stored again, 1 hour
+    /// by default.
+    pub fn set_republish_interval(&mut self, interval: Duration) {
+        self.published.set_interval(interval);
+    }
+
+    /// Set the clock used for re-publication.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.published.set_clock(clock);
+    }
+
+    /// Store a value on the nodes closest to the key.
+    ///
+    /// The value is re-published by `maintain` until removed.
+    /// Returns the number of nodes the value was sent to.
+    pub fn put(&mut self, key: TId, value: TAPI::TValue) -> usize {
+        let count = self.replicate(&key, value.clone());
+        self.published.add_published(key, value);
+        count
+    }
+
+    /// Look up a value by its key.
+    ///
+    /// Closest nodes reported by the lookup as not having the value
+    /// get a copy of it.
+    pub fn get(&mut self, key: &TId) -> Option<TAPI::TValue> {
+        let mut result = None;
+        self.api.find_value(key, |value, nodes| result = Some((value, nodes)));
+        let (value, mut nodes) = result?;
+        let value = value?;
+        nodes.truncate(self.replicas);
+        if !nodes.is_empty() {
+            debug!("Repairing value {:?} on {} nodes", key, nodes.len());
+        }
+        for node in &nodes {
+            self.api.store(node, key, value.clone());
+        }
+        Some(value)
+    }
+
+    /// Stop re-publishing a value put by this node.
+    ///
+    /// Copies already stored expire on their own.
+    pub fn remove(&mut self, key: &TId) -> Option<TAPI::TValue> {
+        self.published.remove(key)
+    }
+
+    /// Re-publish values that are due, should be called periodically.
+    ///
+    /// Returns the number of values re-published.
+    pub fn maintain(&mut self) -> usize {
+        let due = self.published.due();
+        for (key, value) in &due {
+            self.replicate(key, value.clone());
+        }
+        due.len()
+    }
+
+    fn replicate(&mut self, key: &TId, value: TAPI::TValue) -> usize {
+        let mut nodes: Vec<Node<TId, TAddr>> = vec![];
+        self.api.find_node(key, |found| nodes = found);
+        nodes.truncate(self.replicas);
+        debug!("Storing value {:?} on {} nodes", key, nodes.len());
+        for node in &nodes {
+            self.api.store(node, key, value.clone());
+        }
+        nodes.len()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::net;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::super::{GenericAPI, Node};
+    use super::super::mock::MockClock;
+    use super::KvDht;
+
+    use super::super::utils::test;
+    type TestsIdType = test::IdType;
+
+
+    /// Network of 10 nodes, each keeping its own values.
+    struct DummyAPI {
+        stored: HashMap<TestsIdType, HashMap<TestsIdType, i32>>,
+        stores: usize
+    }
+
+    impl DummyAPI {
+        fn closest(&self, id: &TestsIdType) -> Vec<Node<TestsIdType, net::SocketAddr>> {
+            let mut nodes: Vec<_> = (0..10).map(|i| test::new_node(test::make_id(i))).collect();
+            nodes.sort_by_key(|node| node.id[0] ^ id[0]);
+            nodes
+        }
+    }
+
+    impl GenericAPI<TestsIdType, net::SocketAddr> for DummyAPI {
+        type TValue = i32;
+        fn ping<F>(&mut self, node: &Node<TestsIdType, net::SocketAddr>, callback: F)
+                where F: FnOnce(&Node<TestsIdType, net::SocketAddr>, bool) {
+            callback(node, true);
+        }
+        fn find_node<F>(&mut self, id: &TestsIdType, callback: F)
+                where F: FnOnce(Vec<Node<TestsIdType, net::SocketAddr>>) {
+            callback(self.closest(id));
+        }
+        fn find_value<F>(&mut self, id: &TestsIdType, callback: F)
+                where F: FnOnce(Option<Self::TValue>, Vec<Node<TestsIdType, net::SocketAddr>>) {
+            let mut missing = vec![];
+            for node in self.closest(id).into_iter().take(3) {
+                match self.stored.get(&node.id).and_then(|values| values.get(id)) {
+                    Some(value) => return callback(Some(*value), missing),
+                    None => missing.push(node)
+                }
+            }
+            callback(None, missing)
+        }
+        fn store(&mut self, node: &Node<TestsIdType, net::SocketAddr>, id: &TestsIdType, value: Self::TValue) {
+            self.stores += 1;
+            self.stored.entry(node.id.clone()).or_default().insert(id.clone(), value);
+        }
+    }
+
+    #[test]
+    fn test_put_get_repair() {
+        let api = DummyAPI { stored: HashMap::new(), stores: 0 };
+        let mut kv = KvDht::new(api);
+        kv.set_replicas(2);
+        assert_eq!(2, kv.put(test::make_id(0), 42));
+        assert_eq!(None, kv.get(&test::make_id(5)));
+        assert_eq!(Some(42), kv.get(&test::make_id(0)));
+        assert_eq!(2, kv.api().stores);
+
+        // The closest node lost the value
+        kv.api_mut().stored.clear();
+        kv.api_mut().store(&test::new_node(test::make_id(1)), &test::make_id(0), 42);
+        assert_eq!(Some(42), kv.get(&test::make_id(0)));
+        assert_eq!(Some(&42), kv.api().stored[&test::make_id(0)].get(&test::make_id(0)));
+    }
+
+    #[test]
+    fn test_maintain() {
+        let clock = MockClock::new();
+        let api = DummyAPI { stored: HashMap::new(), stores: 0 };
+        let mut kv = KvDht::new(api);
+        kv.set_clock(Arc::new(clock.clone()));
+        kv.set_republish_interval(Duration::from_secs(60));
+        assert_eq!(8, kv.put(test::make_id(0), 42));
+        assert_eq!(0, kv.maintain());
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(1, kv.maintain());
+        assert_eq!(16, kv.api().stores);
+
+        assert_eq!(Some(42), kv.remove(&test::make_id(0)));
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(0, kv.maintain());
+    }
+}
@@ -18,24 +18,74 @@
 //!    structures.
 //! 3. Generic bits for implementing protocols in `service::Handler` structure
 //!    and `protocol` module.
-//! 4. (In the future) simple implementations for testing purposes.
+//! 4. Storage for the values kept by this node, represented by
+//!    `GenericStorage` trait and `MemoryStorage` implementation.
+//! 5. Datagram transports in `transport` module, including an in-memory
+//!    one for testing purposes.
+//! 6. Network simulator in `sim` module for checking the DHT logic
+//!    on many nodes at once.
+//! 7. Mock implementations of the core traits in `mock` module for testing
+//!    applications embedding the DHT.
+//! 8. Plain key/value store with replication in `KvDht` for applications
+//!    that just need a distributed hash table.
 
 #![crate_name = "dht"]
 #![crate_type = "lib"]
 
 #[macro_use]
 extern crate log;
+#[cfg(target_os = "linux")]
+extern crate libc;
 extern crate rand;
 extern crate rustc_serialize;
 
+pub use base::Distance;
+pub use base::GenericAPI;
 pub use base::GenericId;
 pub use base::GenericNodeTable;
+pub use base::GenericStorage;
 pub use base::Node;
+pub use base::StorageStats;
+pub use base::XorDistance;
+pub use base::sort_by_distance;
+pub use estimator::SizeEstimator;
+pub use indexer::Indexer;
 pub use knodetable::KNodeTable;
+pub use kv::KvDht;
+pub use memstorage::MemoryStorage;
+pub use publish::PublishSet;
 pub use service::Service;
+pub use shardedstorage::ShardedStorage;
+pub use subnettable::SubnetCappedTable;
+pub use subnettable::cap_per_subnet;
+pub use trienodetable::TrieNodeTable;
 
+pub mod anomaly;
+pub mod auth;
 mod base;
+pub mod capture;
+pub mod clock;
+#[cfg(unix)]
+pub mod control;
+pub mod crawler;
+mod estimator;
+pub mod flood;
+mod indexer;
 mod knodetable;
+mod kv;
+mod memstorage;
+pub mod metrics;
+pub mod mock;
+pub mod poison;
 pub mod protocol;
+mod publish;
+#[cfg(feature = "test-support")]
+pub mod roundtrip;
 pub mod service;
+mod shardedstorage;
+pub mod sim;
+pub mod smallworld;
+mod subnettable;
+pub mod transport;
+mod trienodetable;
 mod utils;
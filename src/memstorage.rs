@@ -0,0 +1,254 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+//! In-memory storage implementation.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use std::vec;
+
+use rand;
+use rand::Rng;
+
+use super::GenericId;
+use super::base::{GenericStorage, StorageStats};
+use super::clock::{Clock, SystemClock};
+
+
+/// Storage keeping all values in a hash map.
+///
+/// This is the default storage used by `Service`.
+pub struct MemoryStorage<TId, TData> {
+    data: HashMap<TId, StoredValue<TData>>,
+    max_items: Option<usize>,
+    stats: StorageStats,
+    gets_served: AtomicUsize,
+    clock: Arc<dyn Clock>,
+}
+
+/// Value with the time it was stored.
+struct StoredValue<TData> {
+    value: TData,
+    stored_at: Instant,
+}
+
+
+impl<TId, TData> MemoryStorage<TId, TData>
+        where TId: GenericId {
+    /// Create an empty storage.
+    pub fn new() -> MemoryStorage<TId, TData> {
+        MemoryStorage {
+            data: HashMap::new(),
+            max_items: None,
+            stats: StorageStats::default(),
+            gets_served: AtomicUsize::new(0),
+            clock: Arc::new(SystemClock)
+        }
+    }
+
+    /// Create an empty storage accepting at most `max_items` values.
+    pub fn with_quota(max_items: usize) -> MemoryStorage<TId, TData> {
+        MemoryStorage {
+            max_items: Some(max_items),
+            .. MemoryStorage::new()
+        }
+    }
+
+    /// Set the clock used to track the age of values.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+}
+
+impl<TId, TData> Default for MemoryStorage<TId, TData>
+        where TId: GenericId {
+    fn default() -> MemoryStorage<TId, TData> {
+        MemoryStorage::new()
+    }
+}
+
+impl<TId, TData> GenericStorage<TId, TData> for MemoryStorage<TId, TData>
+        where TId: GenericId,
+              TData: Send + Sync + Clone {
+    fn get(&self, id: &TId) -> Option<TData> {
+        let res = self.data.get(id).map(|stored| stored.value.clone());
+        if res.is_some() {
+            self.gets_served.fetch_add(1, Ordering::Relaxed);
+        }
+        res
+    }
+
+    fn put(&mut self, id: TId, value: TData) -> bool {
+        if let Some(max_items) = self.max_items {
+            if self.data.len() >= max_items && !self.data.contains_key(&id) {
+                debug!("Not storing value {:?} - quota of {} items reached",
+                       id, max_items);
+                self.stats.puts_over_quota += 1;
+                return false;
+            }
+        }
+        let stored = StoredValue {
+            value,
+            stored_at: self.clock.now()
+        };
+        self.data.insert(id, stored);
+        self.stats.puts_accepted += 1;
+        true
+    }
+
+    fn sample(&self, count: usize) -> Vec<TId> {
+        let mut ids: Vec<TId> = self.data.keys().cloned().collect();
+        rand::thread_rng().shuffle(&mut ids);
+        ids.truncate(count);
+        ids
+    }
+
+    fn expire_iter(&mut self, max_age: Duration) -> vec::IntoIter<(TId, TData)> {
+        let now = self.clock.now();
+        let expired: Vec<TId> = self.data.iter()
+            .filter(|&(_, stored)| now.duration_since(stored.stored_at) >= max_age)
+            .map(|(id, _)| id.clone())
+            .collect();
+        let res: Vec<_> = expired.into_iter()
+            .filter_map(|id| self.data.remove(&id).map(|stored| (id, stored.value)))
+            .collect();
+        debug!("Expired {} stored values", res.len());
+        self.stats.expired += res.len();
+        res.into_iter()
+    }
+
+    fn evict_oldest(&mut self, count: usize) -> vec::IntoIter<(TId, TData)> {
+        let mut ages: Vec<(Instant, TId)> = self.data.iter()
+            .map(|(id, stored)| (stored.stored_at, id.clone()))
+            .collect();
+        if ages.len() > count && count > 0 {
+            ages.select_nth_unstable_by(count - 1, |a, b| a.0.cmp(&b.0));
+        }
+        ages.truncate(count);
+        let res: Vec<_> = ages.into_iter()
+            .filter_map(|(_, id)| self.data.remove(&id).map(|stored| (id, stored.value)))
+            .collect();
+        debug!("Evicted {} stored values", res.len());
+        res.into_iter()
+    }
+
+    fn stats(&self) -> StorageStats {
+        StorageStats {
+            items: self.data.len(),
+            gets_served: self.gets_served.load(Ordering::Relaxed),
+            .. self.stats.clone()
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::super::base::GenericStorage;
+    use super::super::mock::MockClock;
+    use super::MemoryStorage;
+
+    use super::super::utils::test;
+    type TestsIdType = test::IdType;
+
+
+    #[test]
+    fn test_put_get() {
+        let mut s = MemoryStorage::<TestsIdType, String>::new();
+        assert!(s.get(&test::make_id(42)).is_none());
+        s.put(test::make_id(42), "foo".to_string());
+        assert_eq!(Some("foo".to_string()), s.get(&test::make_id(42)));
+        s.put(test::make_id(42), "bar".to_string());
+        assert_eq!(Some("bar".to_string()), s.get(&test::make_id(42)));
+        assert_eq!(1, s.stats().items);
+    }
+
+    #[test]
+    fn test_quota() {
+        let mut s = MemoryStorage::<TestsIdType, String>::with_quota(1);
+        assert!(s.put(test::make_id(42), "foo".to_string()));
+        assert!(!s.put(test::make_id(43), "foo".to_string()));
+        assert!(s.put(test::make_id(42), "bar".to_string()));
+        assert!(s.get(&test::make_id(43)).is_none());
+        assert_eq!(Some("bar".to_string()), s.get(&test::make_id(42)));
+
+        let stats = s.stats();
+        assert_eq!(1, stats.items);
+        assert_eq!(2, stats.puts_accepted);
+        assert_eq!(1, stats.puts_over_quota);
+        assert_eq!(1, stats.gets_served);
+    }
+
+    #[test]
+    fn test_sample() {
+        let mut s = MemoryStorage::<TestsIdType, String>::new();
+        assert!(s.sample(5).is_empty());
+        for i in 0..10 {
+            s.put(test::make_id(i), "foo".to_string());
+        }
+        let mut sample = s.sample(5);
+        assert_eq!(5, sample.len());
+        sample.sort();
+        sample.dedup();
+        assert_eq!(5, sample.len());
+        assert_eq!(10, s.sample(100).len());
+    }
+
+    #[test]
+    fn test_expire_iter() {
+        let mut s = MemoryStorage::<TestsIdType, String>::new();
+        s.put(test::make_id(42), "foo".to_string());
+        assert_eq!(0, s.expire_iter(Duration::from_secs(3600)).count());
+        assert_eq!(1, s.stats().items);
+
+        let expired: Vec<_> = s.expire_iter(Duration::from_secs(0)).collect();
+        assert_eq!(vec![(test::make_id(42), "foo".to_string())], expired);
+        assert_eq!(0, s.stats().items);
+        assert_eq!(1, s.stats().expired);
+        assert!(s.get(&test::make_id(42)).is_none());
+    }
+
+    #[test]
+    fn test_expire_iter_clock() {
+        let clock = MockClock::new();
+        let mut s = MemoryStorage::<TestsIdType, String>::new();
+        s.set_clock(Arc::new(clock.clone()));
+        s.put(test::make_id(42), "foo".to_string());
+        clock.advance(Duration::from_secs(3600));
+        s.put(test::make_id(43), "bar".to_string());
+        clock.advance(Duration::from_secs(3600));
+
+        let expired: Vec<_> = s.expire_iter(Duration::from_secs(2 * 3600)).collect();
+        assert_eq!(vec![(test::make_id(42), "foo".to_string())], expired);
+        assert_eq!(1, s.stats().items);
+    }
+
+    #[test]
+    fn test_evict_oldest() {
+        let clock = MockClock::new();
+        let mut s = MemoryStorage::<TestsIdType, String>::new();
+        s.set_clock(Arc::new(clock.clone()));
+        for i in 0..10 {
+            s.put(test::make_id(i), "foo".to_string());
+            clock.advance(Duration::from_secs(1));
+        }
+        assert_eq!(0, s.evict_oldest(0).count());
+        let mut evicted: Vec<_> = s.evict_oldest(3).map(|(id, _)| id).collect();
+        evicted.sort();
+        assert_eq!(vec![test::make_id(0), test::make_id(1), test::make_id(2)], evicted);
+        assert_eq!(7, s.stats().items);
+        assert_eq!(7, s.evict_oldest(100).count());
+        assert_eq!(0, s.stats().items);
+    }
+}
@@ -0,0 +1,560 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+//! Metrics facade.
+//!
+//! `Service` reports what it does through the `Metrics` trait. By default
+//! nothing is recorded (`NoopMetrics`); with the `prometheus` feature enabled
+//! `PrometheusRecorder` keeps the values and renders them in the Prometheus
+//! text exposition format, also as responses to scrape requests.
+//!
+//! `Counter` and `AtomicHistogram` are lock-free building blocks for
+//! recorders: every thread updates its own shard and reading sums them up.
+
+use std::cell::Cell;
+#[cfg(feature = "prometheus")]
+use std::collections::BTreeMap;
+#[cfg(feature = "prometheus")]
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+#[cfg(feature = "prometheus")]
+use std::sync::RwLock;
+use std::time::Duration;
+
+
+/// Number of ping requests received.
+pub static PING_REQUESTS: &str = "dht_ping_requests_total";
+/// Number of find_node requests received.
+pub static FIND_NODE_REQUESTS: &str = "dht_find_node_requests_total";
+/// Number of find_value requests received.
+pub static FIND_VALUE_REQUESTS: &str = "dht_find_value_requests_total";
+/// Number of store requests received.
+pub static STORE_REQUESTS: &str = "dht_store_requests_total";
+/// Number of sample requests received.
+pub static SAMPLE_REQUESTS: &str = "dht_sample_requests_total";
+/// Number of nodes the node table refused to add.
+pub static TABLE_UPDATES_REJECTED: &str = "dht_table_updates_rejected_total";
+/// Number of nodes removed from the node table during clean up.
+pub static TABLE_NODES_REMOVED: &str = "dht_table_nodes_removed_total";
+/// Number of stored values removed because of their age.
+pub static STORAGE_EXPIRED: &str = "dht_storage_expired_total";
+/// Number of queries dropped because their source floods.
+pub static FLOOD_DROPPED: &str = "dht_flood_dropped_total";
+/// Number of addresses banned and IDs distrusted by the anomaly detector.
+pub static ANOMALIES_DETECTED: &str = "dht_anomalies_detected_total";
+/// Number of packets ignored because their sender is not trusted.
+pub static UNTRUSTED_DROPPED: &str = "dht_untrusted_dropped_total";
+/// Number of node IDs rejected because they are zero.
+pub static ZERO_IDS_REJECTED: &str = "dht_zero_ids_rejected_total";
+/// Number of node IDs rejected because they are the same as ours.
+pub static OWN_IDS_REJECTED: &str = "dht_own_ids_rejected_total";
+/// Number of node IDs rejected because they are banned.
+pub static BANNED_IDS_REJECTED: &str = "dht_banned_ids_rejected_total";
+/// Number of entries evicted to fit into the memory budget.
+pub static MEMORY_EVICTED: &str = "dht_memory_evicted_total";
+/// Number of values from store requests rejected by the verifier.
+pub static STORE_UNVERIFIED: &str = "dht_store_unverified_total";
+/// Number of our requests answered by other nodes.
+pub static RESPONSES_RECEIVED: &str = "dht_responses_received_total";
+/// Number of our requests that timed out.
+pub static REQUEST_TIMEOUTS: &str = "dht_request_timeouts_total";
+/// Number of our requests that failed with an error.
+pub static REQUEST_ERRORS: &str = "dht_request_errors_total";
+/// Number of bytes received from the network.
+pub static BYTES_RECEIVED: &str = "dht_received_bytes_total";
+/// Number of bytes sent to the network.
+pub static BYTES_SENT: &str = "dht_sent_bytes_total";
+/// Number of nodes currently in the node table.
+pub static TABLE_NODES: &str = "dht_table_nodes";
+/// Number of values currently stored.
+pub static STORAGE_ITEMS: &str = "dht_storage_items";
+/// Response latency.
+pub static RTT: &str = "dht_rtt_seconds";
+
+/// Upper bounds of the histogram buckets in milliseconds.
+static HISTOGRAM_BUCKETS: [u64; 12] = [1, 2, 5, 10, 20, 50, 100, 200, 500,
+                                       1000, 2000, 5000];
+static SHARDS: usize = 16;
+#[cfg(feature = "prometheus")]
+static MAX_SCRAPE_REQUEST_SIZE: usize = 8192;
+
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static SHARD: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+
+/// Trait for recording metrics.
+pub trait Metrics : Send + Sync {
+    /// Increase a counter by `value`.
+    fn increment(&self, name: &'static str, value: u64);
+    /// Set a gauge to `value`.
+    fn gauge(&self, name: &'static str, value: f64);
+    /// Add a duration to a histogram.
+    fn observe(&self, name: &'static str, value: Duration);
+}
+
+/// Histogram of durations with fixed exponential buckets.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    count: u64,
+    sum: Duration,
+}
+
+/// Counter updated without locks.
+///
+/// Threads add to different shards, so they do not contend for the same
+/// cache line.
+#[derive(Debug)]
+pub struct Counter {
+    shards: Vec<PaddedU64>,
+}
+
+/// Histogram of durations updated without locks, see `Counter`.
+#[derive(Debug)]
+pub struct AtomicHistogram {
+    shards: Vec<HistogramShard>,
+}
+
+#[derive(Debug, Default)]
+#[repr(align(64))]
+struct PaddedU64(AtomicU64);
+
+#[derive(Debug, Default)]
+#[repr(align(64))]
+struct HistogramShard {
+    counts: [AtomicU64; 13],
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    /// Create an empty histogram.
+    pub fn new() -> Histogram {
+        Histogram {
+            counts: vec![0; HISTOGRAM_BUCKETS.len() + 1],
+            count: 0,
+            sum: Duration::from_secs(0)
+        }
+    }
+
+    /// Add a value to the histogram.
+    pub fn record(&mut self, value: Duration) {
+        self.counts[bucket_index(value)] += 1;
+        self.count += 1;
+        self.sum += value;
+    }
+
+    /// Number of values recorded.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Sum of all values recorded.
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    /// Average value, if any values were recorded.
+    pub fn mean(&self) -> Option<Duration> {
+        if self.count == 0 {
+            None
+        }
+        else {
+            let nanos = self.sum.as_nanos() / self.count as u128;
+            Some(Duration::new((nanos / 1_000_000_000) as u64, (nanos % 1_000_000_000) as u32))
+        }
+    }
+
+    /// Upper bound of the bucket containing the `q` quantile.
+    ///
+    /// Returns `None` if no values were recorded or the quantile falls into
+    /// the last, unbounded, bucket.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = (q * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bound, count) in self.buckets() {
+            seen += count;
+            if seen >= rank {
+                return bound;
+            }
+        }
+        None
+    }
+
+    /// Iterate over bucket upper bounds (`None` for infinity) and counts.
+    pub fn buckets(&self) -> Vec<(Option<Duration>, u64)> {
+        HISTOGRAM_BUCKETS.iter()
+            .map(|&bound| Some(Duration::from_millis(bound)))
+            .chain(Some(None))
+            .zip(self.counts.iter().cloned())
+            .collect()
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram::new()
+    }
+}
+
+impl Counter {
+    /// Create a counter with value 0.
+    pub fn new() -> Counter {
+        Counter {
+            shards: (0..SHARDS).map(|_| PaddedU64::default()).collect()
+        }
+    }
+
+    /// Increase the counter.
+    pub fn add(&self, value: u64) {
+        self.shards[shard()].0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Current value of the counter.
+    pub fn value(&self) -> u64 {
+        self.shards.iter().map(|shard| shard.0.load(Ordering::Relaxed)).sum()
+    }
+}
+
+impl Default for Counter {
+    fn default() -> Counter {
+        Counter::new()
+    }
+}
+
+impl AtomicHistogram {
+    /// Create an empty histogram.
+    pub fn new() -> AtomicHistogram {
+        AtomicHistogram {
+            shards: (0..SHARDS).map(|_| HistogramShard::default()).collect()
+        }
+    }
+
+    /// Add a value to the histogram.
+    pub fn record(&self, value: Duration) {
+        let shard = &self.shards[shard()];
+        shard.counts[bucket_index(value)].fetch_add(1, Ordering::Relaxed);
+        let nanos = value.as_nanos().min(u64::MAX as u128) as u64;
+        shard.sum_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    /// Get the current values.
+    ///
+    /// Concurrent updates may be partially visible.
+    pub fn snapshot(&self) -> Histogram {
+        let mut res = Histogram::new();
+        let mut sum_nanos = 0u64;
+        for shard in &self.shards {
+            for (total, count) in res.counts.iter_mut().zip(shard.counts.iter()) {
+                *total += count.load(Ordering::Relaxed);
+            }
+            sum_nanos = sum_nanos.wrapping_add(shard.sum_nanos.load(Ordering::Relaxed));
+        }
+        res.count = res.counts.iter().sum();
+        res.sum = Duration::from_nanos(sum_nanos);
+        res
+    }
+}
+
+impl Default for AtomicHistogram {
+    fn default() -> AtomicHistogram {
+        AtomicHistogram::new()
+    }
+}
+
+fn bucket_index(value: Duration) -> usize {
+    let millis = value.as_millis();
+    HISTOGRAM_BUCKETS.iter()
+        .position(|&bound| millis <= bound as u128)
+        .unwrap_or(HISTOGRAM_BUCKETS.len())
+}
+
+/// Shard of the current thread, threads get shards in turn.
+fn shard() -> usize {
+    SHARD.with(|shard| match shard.get() {
+        Some(index) => index,
+        None => {
+            let index = NEXT_SHARD.fetch_add(1, Ordering::Relaxed) % SHARDS;
+            shard.set(Some(index));
+            index
+        }
+    })
+}
+
+/// Metrics implementation that records nothing.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {
+    fn increment(&self, _name: &'static str, _value: u64) { }
+    fn gauge(&self, _name: &'static str, _value: f64) { }
+    fn observe(&self, _name: &'static str, _value: Duration) { }
+}
+
+/// Metrics implementation keeping values for Prometheus to scrape.
+///
+/// Recording only takes a write lock for a metric seen for the first time,
+/// values are updated with atomics.
+#[cfg(feature = "prometheus")]
+#[derive(Debug, Default)]
+pub struct PrometheusRecorder {
+    counters: RwLock<BTreeMap<&'static str, Counter>>,
+    // Bits of f64 values
+    gauges: RwLock<BTreeMap<&'static str, AtomicU64>>,
+    histograms: RwLock<BTreeMap<&'static str, AtomicHistogram>>,
+}
+
+#[cfg(feature = "prometheus")]
+impl PrometheusRecorder {
+    /// Create a recorder with no values.
+    pub fn new() -> PrometheusRecorder {
+        PrometheusRecorder::default()
+    }
+
+    /// Get the current value of a counter.
+    pub fn counter_value(&self, name: &str) -> u64 {
+        self.counters.read().unwrap().get(name).map(Counter::value).unwrap_or(0)
+    }
+
+    /// Get the current value of a gauge.
+    pub fn gauge_value(&self, name: &str) -> f64 {
+        self.gauges.read().unwrap().get(name)
+            .map(|bits| f64::from_bits(bits.load(Ordering::Relaxed)))
+            .unwrap_or(0.0)
+    }
+
+    /// Get a copy of a histogram.
+    pub fn histogram(&self, name: &str) -> Histogram {
+        self.histograms.read().unwrap().get(name)
+            .map(AtomicHistogram::snapshot)
+            .unwrap_or_default()
+    }
+
+    /// Render all values in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut res = String::new();
+        for (name, counter) in self.counters.read().unwrap().iter() {
+            res.push_str(&format!("# TYPE {} counter\n{} {}\n", name, name, counter.value()));
+        }
+        for (name, bits) in self.gauges.read().unwrap().iter() {
+            let value = f64::from_bits(bits.load(Ordering::Relaxed));
+            res.push_str(&format!("# TYPE {} gauge\n{} {}\n", name, name, value));
+        }
+        for (name, histogram) in self.histograms.read().unwrap().iter() {
+            let histogram = histogram.snapshot();
+            res.push_str(&format!("# TYPE {} histogram\n", name));
+            let mut total = 0;
+            for (bound, count) in histogram.buckets() {
+                total += count;
+                let le = match bound {
+                    Some(bound) => format!("{}", bound.as_secs_f64()),
+                    None => "+Inf".to_string()
+                };
+                res.push_str(&format!("{}_bucket{{le=\"{}\"}} {}\n", name, le, total));
+            }
+            res.push_str(&format!("{}_sum {}\n{}_count {}\n",
+                                  name, histogram.sum().as_secs_f64(),
+                                  name, histogram.count()));
+        }
+        res
+    }
+
+    /// Answer an HTTP scrape request with all values rendered.
+    ///
+    /// `stream` is e.g. a `TcpStream` accepted from a listener on the
+    /// metrics port. The request path and headers are not checked.
+    pub fn respond<S: Read + Write>(&self, stream: &mut S) -> io::Result<()> {
+        let mut head = vec![];
+        let mut byte = [0u8; 1];
+        while !head.ends_with(b"\r\n\r\n") && !head.ends_with(b"\n\n") {
+            if head.len() >= MAX_SCRAPE_REQUEST_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          "Scrape request is too large"));
+            }
+            if stream.read(&mut byte)? == 0 {
+                break;
+            }
+            head.push(byte[0]);
+        }
+        let body = self.render();
+        write!(stream, "HTTP/1.0 200 OK\r\n\
+                        Content-Type: text/plain; version=0.0.4\r\n\
+                        Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        stream.flush()
+    }
+}
+
+#[cfg(feature = "prometheus")]
+impl Metrics for PrometheusRecorder {
+    fn increment(&self, name: &'static str, value: u64) {
+        with_entry(&self.counters, name, |counter| counter.add(value));
+    }
+    fn gauge(&self, name: &'static str, value: f64) {
+        with_entry(&self.gauges, name, |bits| bits.store(value.to_bits(), Ordering::Relaxed));
+    }
+    fn observe(&self, name: &'static str, value: Duration) {
+        with_entry(&self.histograms, name, |histogram| histogram.record(value));
+    }
+}
+
+/// Call `f` on the value for `name`, creating it if needed.
+#[cfg(feature = "prometheus")]
+fn with_entry<T, F>(map: &RwLock<BTreeMap<&'static str, T>>, name: &'static str, f: F)
+        where T: Default,
+              F: FnOnce(&T) {
+    if let Some(value) = map.read().unwrap().get(name) {
+        return f(value);
+    }
+    f(map.write().unwrap().entry(name).or_default())
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::{AtomicHistogram, Counter, Histogram};
+    #[cfg(feature = "prometheus")]
+    use std::io::{self, Cursor, Read, Write};
+
+    #[cfg(feature = "prometheus")]
+    use super::{Metrics, PrometheusRecorder};
+
+    #[test]
+    fn test_histogram() {
+        let mut h = Histogram::new();
+        assert_eq!(None, h.mean());
+        assert_eq!(None, h.quantile(0.5));
+        h.record(Duration::from_millis(3));
+        h.record(Duration::from_millis(5));
+        h.record(Duration::from_millis(40));
+        h.record(Duration::from_secs(10));
+        assert_eq!(4, h.count());
+        assert_eq!(Some(Duration::from_millis(5)), h.quantile(0.5));
+        assert_eq!(Some(Duration::from_millis(50)), h.quantile(0.75));
+        assert_eq!(None, h.quantile(1.0));
+        assert_eq!(Some(Duration::from_millis(2512)), h.mean());
+        let buckets = h.buckets();
+        assert_eq!(13, buckets.len());
+        assert_eq!((Some(Duration::from_millis(5)), 2), buckets[2]);
+        assert_eq!((None, 1), buckets[12]);
+    }
+
+    #[test]
+    fn test_histogram_mean_large_count() {
+        let mut h = Histogram::new();
+        h.count = 1 << 32;
+        h.sum = Duration::from_secs(1 << 32);
+        assert_eq!(Some(Duration::from_secs(1)), h.mean());
+        h.count = 3 << 32;
+        assert_eq!(Some(Duration::new(0, 333_333_333)), h.mean());
+    }
+
+    #[test]
+    fn test_atomic() {
+        let counter = Arc::new(Counter::new());
+        let histogram = Arc::new(AtomicHistogram::new());
+        let threads: Vec<_> = (0..4).map(|_| {
+            let counter = counter.clone();
+            let histogram = histogram.clone();
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    counter.add(2);
+                    histogram.record(Duration::from_millis(3));
+                }
+            })
+        }).collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(8000, counter.value());
+        let snapshot = histogram.snapshot();
+        assert_eq!(4000, snapshot.count());
+        assert_eq!(Duration::from_secs(12), snapshot.sum());
+        assert_eq!((Some(Duration::from_millis(5)), 4000), snapshot.buckets()[2]);
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn test_prometheus_render() {
+        let m = PrometheusRecorder::new();
+        assert_eq!("", m.render());
+        m.increment("foo_total", 1);
+        m.increment("foo_total", 2);
+        m.gauge("bar", 42.0);
+        assert_eq!(3, m.counter_value("foo_total"));
+        assert_eq!(42.0, m.gauge_value("bar"));
+        assert_eq!("# TYPE foo_total counter\nfoo_total 3\n\
+                    # TYPE bar gauge\nbar 42\n", m.render());
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn test_prometheus_render_histogram() {
+        let m = PrometheusRecorder::new();
+        m.observe("rtt", Duration::from_millis(150));
+        assert_eq!(1, m.histogram("rtt").count());
+        let rendered = m.render();
+        assert!(rendered.starts_with("# TYPE rtt histogram\n"));
+        assert!(rendered.contains("rtt_bucket{le=\"0.1\"} 0\n"));
+        assert!(rendered.contains("rtt_bucket{le=\"0.2\"} 1\n"));
+        assert!(rendered.contains("rtt_bucket{le=\"+Inf\"} 1\n"));
+        assert!(rendered.ends_with("rtt_sum 0.15\nrtt_count 1\n"));
+    }
+
+    #[cfg(feature = "prometheus")]
+    struct Exchange {
+        request: Cursor<Vec<u8>>,
+        response: Vec<u8>
+    }
+
+    #[cfg(feature = "prometheus")]
+    impl Read for Exchange {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            self.request.read(buffer)
+        }
+    }
+
+    #[cfg(feature = "prometheus")]
+    impl Write for Exchange {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.response.write(data)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn test_prometheus_respond() {
+        let m = PrometheusRecorder::new();
+        m.increment("foo_total", 3);
+        let request = b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec();
+        let mut exchange = Exchange { request: Cursor::new(request), response: vec![] };
+        m.respond(&mut exchange).unwrap();
+        let response = String::from_utf8(exchange.response).unwrap();
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response.contains("Content-Length: 37\r\n"));
+        assert!(response.ends_with("\r\n\r\n# TYPE foo_total counter\nfoo_total 3\n"));
+
+        let request = vec![b'a'; 10000];
+        let mut exchange = Exchange { request: Cursor::new(request), response: vec![] };
+        assert!(m.respond(&mut exchange).is_err());
+        assert!(exchange.response.is_empty());
+    }
+}
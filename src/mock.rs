@@ -0,0 +1,328 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+//! Mock implementations of the core traits.
+//!
+//! Applications embedding the DHT can use them to test their integration
+//! without a network: behavior is set up in advance and calls are recorded.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::{GenericId, GenericNodeTable, Node, XorDistance, sort_by_distance};
+use super::clock::Clock;
+use super::transport::Transport;
+
+
+/// Call made to `MockNodeTable`.
+#[derive(Clone, Debug)]
+pub enum TableCall<TId, TAddr> {
+    RandomId,
+    Update(Node<TId, TAddr>),
+    Find(TId, usize),
+    PopOldest
+}
+
+/// Node table with scripted behavior.
+///
+/// Accepts all nodes by default and finds them by distance like
+/// a real table would, but without any limits.
+pub struct MockNodeTable<TId, TAddr> {
+    random_id: TId,
+    nodes: Vec<Node<TId, TAddr>>,
+    accept_updates: bool,
+    oldest: Vec<Node<TId, TAddr>>,
+    calls: Mutex<Vec<TableCall<TId, TAddr>>>,
+}
+
+/// Transport with scripted incoming datagrams.
+///
+/// Clones share the same state, so a test can keep one to inspect
+/// what was sent by the code under test.
+#[derive(Clone)]
+pub struct MockTransport<TAddr> {
+    state: Arc<Mutex<MockTransportState<TAddr>>>,
+}
+
+struct MockTransportState<TAddr> {
+    address: TAddr,
+    incoming: VecDeque<(Vec<u8>, TAddr)>,
+    sent: Vec<(Vec<u8>, TAddr)>,
+    send_errors: VecDeque<io::ErrorKind>,
+}
+
+/// Clock that only moves when told to.
+///
+/// Clones share the same time.
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    start: Instant,
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+
+impl<TId, TAddr> MockNodeTable<TId, TAddr>
+        where TId: GenericId,
+              TAddr: Clone {
+    /// Create an empty table, `random_id` is returned from `random_id` calls.
+    pub fn new(random_id: TId) -> MockNodeTable<TId, TAddr> {
+        MockNodeTable {
+            random_id,
+            nodes: vec![],
+            accept_updates: true,
+            oldest: vec![],
+            calls: Mutex::new(vec![])
+        }
+    }
+
+    /// Make `update` accept or reject new nodes.
+    pub fn set_accept_updates(&mut self, accept: bool) {
+        self.accept_updates = accept;
+    }
+
+    /// Set nodes returned from the next `pop_oldest` call.
+    pub fn set_oldest(&mut self, nodes: Vec<Node<TId, TAddr>>) {
+        self.oldest = nodes;
+    }
+
+    /// Calls made so far, oldest first.
+    pub fn calls(&self) -> Vec<TableCall<TId, TAddr>> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// Forget the calls made so far.
+    pub fn clear_calls(&mut self) {
+        self.calls.lock().unwrap().clear();
+    }
+
+    fn record(&self, call: TableCall<TId, TAddr>) {
+        self.calls.lock().unwrap().push(call);
+    }
+}
+
+impl<TId, TAddr> GenericNodeTable<TId, TAddr> for MockNodeTable<TId, TAddr>
+        where TId: GenericId,
+              TAddr: Clone + Send + Sync {
+    fn random_id(&self) -> TId {
+        self.record(TableCall::RandomId);
+        self.random_id.clone()
+    }
+
+    fn update(&mut self, node: &Node<TId, TAddr>) -> bool {
+        self.record(TableCall::Update(node.clone()));
+        if let Some(known) = self.nodes.iter_mut().find(|n| n.id == node.id) {
+            *known = node.clone();
+            return true;
+        }
+        if self.accept_updates {
+            self.nodes.push(node.clone());
+        }
+        self.accept_updates
+    }
+
+    fn find(&self, id: &TId, count: usize) -> Vec<Node<TId, TAddr>> {
+        self.record(TableCall::Find(id.clone(), count));
+        let mut res = self.nodes.clone();
+        sort_by_distance(&mut res, id, &XorDistance);
+        res.truncate(count);
+        res
+    }
+
+    fn pop_oldest(&mut self) -> Vec<Node<TId, TAddr>> {
+        self.record(TableCall::PopOldest);
+        let res = mem::take(&mut self.oldest);
+        self.nodes.retain(|node| !res.iter().any(|old| old.id == node.id));
+        res
+    }
+
+    fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    fn contains(&self, id: &TId) -> bool {
+        self.nodes.iter().any(|n| n.id == *id)
+    }
+
+    fn remove(&mut self, id: &TId) -> Option<Node<TId, TAddr>> {
+        let index = self.nodes.iter().position(|n| n.id == *id)?;
+        Some(self.nodes.remove(index))
+    }
+}
+
+impl<TAddr> MockTransport<TAddr>
+        where TAddr: Clone + Send {
+    /// Create a transport with a given local address.
+    pub fn new(address: TAddr) -> MockTransport<TAddr> {
+        let state = MockTransportState {
+            address,
+            incoming: VecDeque::new(),
+            sent: vec![],
+            send_errors: VecDeque::new()
+        };
+        MockTransport {
+            state: Arc::new(Mutex::new(state))
+        }
+    }
+
+    /// Queue a datagram to be returned from `recv_from`.
+    pub fn push_incoming(&self, data: &[u8], source: TAddr) {
+        self.state.lock().unwrap().incoming.push_back((data.to_vec(), source));
+    }
+
+    /// Make the next `send_to` call fail with a given error.
+    ///
+    /// Can be called several times to fail several calls in a row.
+    pub fn fail_next_send(&self, kind: io::ErrorKind) {
+        self.state.lock().unwrap().send_errors.push_back(kind);
+    }
+
+    /// Datagrams successfully sent so far with their destinations.
+    pub fn sent(&self) -> Vec<(Vec<u8>, TAddr)> {
+        self.state.lock().unwrap().sent.clone()
+    }
+
+    /// Return datagrams sent so far and forget about them.
+    pub fn take_sent(&self) -> Vec<(Vec<u8>, TAddr)> {
+        mem::take(&mut self.state.lock().unwrap().sent)
+    }
+}
+
+impl<TAddr> Transport for MockTransport<TAddr>
+        where TAddr: Clone + Send {
+    type Addr = TAddr;
+
+    fn send_to(&mut self, data: &[u8], addr: &TAddr) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(kind) = state.send_errors.pop_front() {
+            return Err(io::Error::new(kind, "scripted send failure"));
+        }
+        state.sent.push((data.to_vec(), addr.clone()));
+        Ok(())
+    }
+
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<Option<(usize, TAddr)>> {
+        let mut state = self.state.lock().unwrap();
+        Ok(state.incoming.pop_front().map(|(data, source)| {
+            let size = cmp::min(data.len(), buffer.len());
+            buffer[..size].copy_from_slice(&data[..size]);
+            (size, source)
+        }))
+    }
+
+    fn local_addr(&self) -> io::Result<TAddr> {
+        Ok(self.state.lock().unwrap().address.clone())
+    }
+}
+
+impl MockClock {
+    /// Create a clock starting at the current time.
+    pub fn new() -> MockClock {
+        MockClock {
+            start: Instant::now(),
+            elapsed: Arc::new(Mutex::new(Duration::from_secs(0)))
+        }
+    }
+
+    /// Move the clock forward.
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+
+    /// Time passed since the clock was created.
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> MockClock {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::time::Duration;
+
+    use super::super::GenericNodeTable;
+    use super::super::clock::Clock;
+    use super::super::transport::Transport;
+    use super::{MockClock, MockNodeTable, MockTransport, TableCall};
+
+    use super::super::utils::test;
+
+
+    #[test]
+    fn test_node_table() {
+        let mut t = MockNodeTable::new(test::make_id(42));
+        assert_eq!(test::make_id(42), t.random_id());
+        assert!(t.update(&test::new_node(test::make_id(1))));
+        assert!(t.update(&test::new_node(test::make_id(3))));
+        t.set_accept_updates(false);
+        assert!(!t.update(&test::new_node(test::make_id(4))));
+        assert!(t.update(&test::new_node(test::make_id(1))));
+        assert_eq!(2, t.len());
+
+        let found = t.find(&test::make_id(2), 1);
+        assert_eq!(test::make_id(3), found[0].id);
+
+        t.set_oldest(vec![test::new_node(test::make_id(1))]);
+        assert_eq!(1, t.pop_oldest().len());
+        assert!(t.pop_oldest().is_empty());
+        assert_eq!(1, t.len());
+
+        let calls = t.calls();
+        assert_eq!(8, calls.len());
+        match calls[5] {
+            TableCall::Find(ref id, 1) => assert_eq!(test::make_id(2), *id),
+            ref other => panic!("unexpected call {:?}", other)
+        }
+        t.clear_calls();
+        assert!(t.calls().is_empty());
+    }
+
+    #[test]
+    fn test_transport() {
+        let mut t = MockTransport::new(1);
+        let handle = t.clone();
+        let mut buffer = [0u8; 16];
+        assert!(t.recv_from(&mut buffer).unwrap().is_none());
+        handle.push_incoming(b"ping", 2);
+        assert_eq!(Some((4, 2)), t.recv_from(&mut buffer).unwrap());
+
+        t.send_to(b"pong", &2).unwrap();
+        handle.fail_next_send(io::ErrorKind::WouldBlock);
+        let err = t.send_to(b"pong", &3).unwrap_err();
+        assert_eq!(io::ErrorKind::WouldBlock, err.kind());
+        assert_eq!(vec![(b"pong".to_vec(), 2)], handle.take_sent());
+        assert!(handle.sent().is_empty());
+        assert_eq!(1, t.local_addr().unwrap());
+    }
+
+    #[test]
+    fn test_clock() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        assert_eq!(start, clock.now());
+        clock.clone().advance(Duration::from_secs(3600));
+        assert_eq!(Duration::from_secs(3600), clock.now() - start);
+    }
+}
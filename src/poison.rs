@@ -0,0 +1,218 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+//! Detection of nodes poisoning lookups.
+//!
+//! A poisoning node answers find requests with nodes that do not exist,
+//! never respond or are banned, so that lookups going through it fail.
+//! Every returned node is remembered together with the node that returned
+//! it, and once we know whether it responds, the referrer gets a good or
+//! a bad mark. Referrers with mostly bad marks are poisoners and should be
+//! excluded from lookup shortlists.
+
+use std::collections::{HashMap, HashSet};
+
+use super::{GenericId, Node};
+
+
+static MIN_SAMPLES: usize = 8;
+static THRESHOLD: f64 = 0.75;
+static MAX_REFERRALS: usize = 100_000;
+
+
+/// Tracker of nodes returning bad nodes.
+pub struct PoisonTracker<TId> {
+    min_samples: usize,
+    threshold: f64,
+    // Node returned by a find request -> node that returned it
+    referrals: HashMap<TId, TId>,
+    marks: HashMap<TId, Marks>,
+    poisoners: HashSet<TId>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Marks {
+    good: usize,
+    bad: usize,
+}
+
+
+impl<TId> PoisonTracker<TId>
+        where TId: GenericId {
+    /// Create a tracker considering a node a poisoner once at least 75%
+    /// of at least 8 nodes returned by it were bad.
+    pub fn new() -> PoisonTracker<TId> {
+        PoisonTracker::with_limits(MIN_SAMPLES, THRESHOLD)
+    }
+
+    /// Create a tracker considering a node a poisoner once at least
+    /// `threshold` share of at least `min_samples` nodes returned by it
+    /// were bad.
+    pub fn with_limits(min_samples: usize, threshold: f64) -> PoisonTracker<TId> {
+        assert!(min_samples > 0);
+        assert!(threshold > 0.0 && threshold <= 1.0);
+        PoisonTracker {
+            min_samples,
+            threshold,
+            referrals: HashMap::new(),
+            marks: HashMap::new(),
+            poisoners: HashSet::new()
+        }
+    }
+
+    /// Record nodes returned by `referrer` in response to a find request.
+    ///
+    /// Only the first referrer of a node is remembered.
+    pub fn referred<TAddr>(&mut self, referrer: &TId, nodes: &[Node<TId, TAddr>]) {
+        for node in nodes {
+            if self.referrals.len() >= MAX_REFERRALS {
+                debug!("Too many referrals tracked, ignoring nodes from {:?}", referrer);
+                return;
+            }
+            if node.id != *referrer {
+                self.referrals.entry(node.id.clone()).or_insert_with(|| referrer.clone());
+            }
+        }
+    }
+
+    /// Record that `referrer` returned `count` nodes which are banned.
+    ///
+    /// Returns true if the referrer has just become a poisoner.
+    pub fn referred_banned(&mut self, referrer: &TId, count: usize) -> bool {
+        self.marks.entry(referrer.clone()).or_default().bad += count;
+        self.check(referrer)
+    }
+
+    /// Record that a node responded to a request.
+    ///
+    /// Returns the referrer of the node if it has just become a poisoner.
+    pub fn responded(&mut self, id: &TId) -> Option<TId> {
+        self.mark(id, true)
+    }
+
+    /// Record that a node did not respond to a request.
+    ///
+    /// Returns the referrer of the node if it has just become a poisoner.
+    pub fn failed(&mut self, id: &TId) -> Option<TId> {
+        self.mark(id, false)
+    }
+
+    /// Share of bad nodes returned by a node, `None` until there are
+    /// enough of them to judge.
+    pub fn score(&self, referrer: &TId) -> Option<f64> {
+        let marks = self.marks.get(referrer)?;
+        let total = marks.good + marks.bad;
+        if total < self.min_samples {
+            return None;
+        }
+        Some(marks.bad as f64 / total as f64)
+    }
+
+    /// Whether a node is known to poison lookups.
+    pub fn is_poisoner(&self, id: &TId) -> bool {
+        self.poisoners.contains(id)
+    }
+
+    /// Remove poisoners from a list of nodes, e.g. a lookup shortlist.
+    pub fn filter<TAddr>(&self, nodes: &mut Vec<Node<TId, TAddr>>) {
+        nodes.retain(|node| !self.poisoners.contains(&node.id));
+    }
+
+    /// Forget everything about a node, e.g. to give it another chance.
+    pub fn forget(&mut self, id: &TId) {
+        self.marks.remove(id);
+        self.poisoners.remove(id);
+        self.referrals.retain(|_, referrer| referrer != id);
+    }
+
+    fn mark(&mut self, id: &TId, good: bool) -> Option<TId> {
+        let referrer = self.referrals.remove(id)?;
+        {
+            let marks = self.marks.entry(referrer.clone()).or_default();
+            if good {
+                marks.good += 1;
+            }
+            else {
+                marks.bad += 1;
+            }
+        }
+        if self.check(&referrer) { Some(referrer) } else { None }
+    }
+
+    fn check(&mut self, referrer: &TId) -> bool {
+        match self.score(referrer) {
+            Some(score) if score >= self.threshold && !self.poisoners.contains(referrer) => {
+                warn!("Node {:?} returned {:.0}% bad nodes, excluding it",
+                      referrer, score * 100.0);
+                self.poisoners.insert(referrer.clone());
+                true
+            },
+            _ => false
+        }
+    }
+}
+
+impl<TId> Default for PoisonTracker<TId>
+        where TId: GenericId {
+    fn default() -> PoisonTracker<TId> {
+        PoisonTracker::new()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::super::Node;
+    use super::PoisonTracker;
+
+
+    fn nodes(ids: &[u64]) -> Vec<Node<u64, ()>> {
+        ids.iter().map(|&id| Node { id, address: () }).collect()
+    }
+
+    #[test]
+    fn test_unresponsive_referrals() {
+        let mut t = PoisonTracker::with_limits(4, 0.75);
+        t.referred(&1, &nodes(&[10, 11, 12, 13, 14]));
+        t.referred(&2, &nodes(&[10, 20, 2]));
+        assert_eq!(None, t.responded(&10));
+        assert_eq!(None, t.failed(&11));
+        assert_eq!(None, t.failed(&12));
+        assert_eq!(None, t.score(&1));
+        // 3 bad out of 4
+        assert_eq!(Some(1), t.failed(&13));
+        assert_eq!(Some(0.75), t.score(&1));
+        assert!(t.is_poisoner(&1));
+        // Reported only once
+        assert_eq!(None, t.failed(&14));
+        // Node 10 was referred by 1 first, 2 does not refer itself
+        assert_eq!(None, t.failed(&20));
+        assert_eq!(None, t.score(&2));
+        assert_eq!(None, t.failed(&2));
+
+        let mut shortlist = nodes(&[1, 2, 3]);
+        t.filter(&mut shortlist);
+        assert_eq!(vec![2, 3], shortlist.into_iter().map(|n| n.id).collect::<Vec<_>>());
+
+        t.forget(&1);
+        assert!(!t.is_poisoner(&1));
+        assert_eq!(None, t.score(&1));
+    }
+
+    #[test]
+    fn test_banned_referrals() {
+        let mut t = PoisonTracker::with_limits(4, 0.5);
+        t.referred(&1, &nodes(&[10, 11]));
+        t.responded(&10);
+        t.responded(&11);
+        assert!(!t.referred_banned(&1, 1));
+        assert!(t.referred_banned(&1, 1));
+        assert_eq!(Some(0.5), t.score(&1));
+    }
+}
@@ -8,7 +8,29 @@
 
 //! Generic protocol bits for implementing custom protocols.
 
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::{self, Debug};
+use std::io;
+use std::net::SocketAddr;
+use std::str;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rustc_serialize::{Decodable, Encodable};
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::json::{self, Json};
+
 use super::{GenericId, Node};
+use super::clock::{Clock, SystemClock};
+
+
+static BAD_PACKETS_PER_MINUTE: usize = 10;
+static REQUEST_TIMEOUT: u64 = 10;
+static MAX_PENDING_REQUESTS: usize = 10000;
+static MAX_DECODE_SIZE: usize = 65536;
+static MAX_NESTING_DEPTH: usize = 32;
+/// Maximum size of an extension blob in requests and responses.
+pub static MAX_EXTENSION_SIZE: usize = 256;
 
 
 /// Payload in the request.
@@ -16,28 +38,38 @@ pub enum RequestPayload<TId, TValue> {
     Ping,
     FindNode(TId),
     FindValue(TId),
-    Store(TId, TValue)
+    Store(TId, TValue),
+    /// Application-defined method with its name and encoded arguments.
+    Custom(String, Vec<u8>)
 }
 
 /// Request structure.
 pub struct Request<TId, TAddr, TValue> {
     pub caller: Node<TId, TAddr>,
     pub request_id: TId,
-    pub payload: RequestPayload<TId, TValue>
+    pub payload: RequestPayload<TId, TValue>,
+    /// Opaque application data, see `service::Handler::on_extension`.
+    pub extension: Option<Vec<u8>>
 }
 
 /// Payload in the response.
 pub enum ResponsePayload<TId, TAddr, TValue> {
     NodesFound(Vec<Node<TId, TAddr>>),
     ValueFound(TValue),
-    NoResult
+    NoResult,
+    /// Encoded result of an application-defined method.
+    Custom(Vec<u8>),
+    /// Method is not known, e.g. error 204 in KRPC.
+    UnknownMethod
 }
 
 /// Response structure.
 pub struct Response<TId, TAddr, TValue> {
     pub request: Request<TId, TAddr, TValue>,
     pub responder: Node<TId, TAddr>,
-    pub payload: ResponsePayload<TId, TAddr, TValue>
+    pub payload: ResponsePayload<TId, TAddr, TValue>,
+    /// Opaque application data, see `service::Handler::on_extension`.
+    pub extension: Option<Vec<u8>>
 }
 
 /// Trait for a protocol implementation.
@@ -49,5 +81,817 @@ pub trait Protocol : Send {
     /// Parse request from binary data.
     fn parse_request(&self, data: &[u8]) -> Request<Self::Id, Self::Addr, Self::Value>;
     /// Format response to binary data.
-    fn format_response(&self, Response<Self::Id, Self::Addr, Self::Value>) -> Vec<u8>;
+    fn format_response(&self, response: Response<Self::Id, Self::Addr, Self::Value>) -> Vec<u8>;
+    /// Format response to the end of a buffer, e.g. one reused for all
+    /// outgoing packets.
+    ///
+    /// The default implementation copies the result of `format_response`.
+    fn format_response_into(&self, response: Response<Self::Id, Self::Addr, Self::Value>,
+                            buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&self.format_response(response));
+    }
+}
+
+/// Trait for encoding messages to bytes.
+///
+/// Protocols can be generic over it to share the message handling between
+/// different encodings.
+pub trait WireCodec : Send + Sync {
+    /// Encode a value.
+    fn encode<T: Encodable>(&self, value: &T) -> io::Result<Vec<u8>>;
+    /// Encode a value to the end of a buffer.
+    ///
+    /// The default implementation copies the result of `encode`.
+    fn encode_into<T: Encodable>(&self, value: &T, buffer: &mut Vec<u8>) -> io::Result<()> {
+        buffer.extend_from_slice(&self.encode(value)?);
+        Ok(())
+    }
+    /// Decode a value, failing with `InvalidData` on malformed input.
+    fn decode<T: Decodable>(&self, data: &[u8]) -> io::Result<T>;
+}
+
+/// JSON encoding.
+///
+/// Input larger than a limit (64 KiB by default) or nested deeper than
+/// a limit (32 arrays and objects by default) is rejected before parsing,
+/// since the parser recurses on nesting.
+#[derive(Clone, Copy, Debug)]
+pub struct JsonCodec {
+    max_size: usize,
+    max_depth: usize,
+}
+
+impl JsonCodec {
+    /// Create a codec with the default limits.
+    pub fn new() -> JsonCodec {
+        JsonCodec::with_limits(MAX_DECODE_SIZE, MAX_NESTING_DEPTH)
+    }
+
+    /// Create a codec decoding at most `max_size` bytes nested at most
+    /// `max_depth` levels deep.
+    pub fn with_limits(max_size: usize, max_depth: usize) -> JsonCodec {
+        JsonCodec {
+            max_size,
+            max_depth
+        }
+    }
+
+    /// Parse a document into a JSON tree with the same limits as `decode`,
+    /// e.g. to inspect keys unknown to the decoded types.
+    pub fn parse(&self, data: &[u8]) -> io::Result<Json> {
+        Json::from_str(self.check(data)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Check the limits and the encoding of a document.
+    fn check<'a>(&self, data: &'a [u8]) -> io::Result<&'a str> {
+        if data.len() > self.max_size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("{} bytes is too large", data.len())));
+        }
+        check_nesting(data, self.max_depth)?;
+        str::from_utf8(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+impl Default for JsonCodec {
+    fn default() -> JsonCodec {
+        JsonCodec::new()
+    }
+}
+
+impl WireCodec for JsonCodec {
+    fn encode<T: Encodable>(&self, value: &T) -> io::Result<Vec<u8>> {
+        json::encode(value)
+            .map(String::into_bytes)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Writes directly to the buffer, without an intermediate string.
+    fn encode_into<T: Encodable>(&self, value: &T, buffer: &mut Vec<u8>) -> io::Result<()> {
+        let size = buffer.len();
+        let res = value.encode(&mut json::Encoder::new(&mut BufferWriter(buffer)));
+        if let Err(e) = res {
+            buffer.truncate(size);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+        Ok(())
+    }
+
+    fn decode<T: Decodable>(&self, data: &[u8]) -> io::Result<T> {
+        json::decode(self.check(data)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// Adapter for encoders writing text.
+struct BufferWriter<'a>(&'a mut Vec<u8>);
+
+impl<'a> fmt::Write for BufferWriter<'a> {
+    fn write_str(&mut self, data: &str) -> fmt::Result {
+        self.0.extend_from_slice(data.as_bytes());
+        Ok(())
+    }
+}
+
+/// Fail if JSON arrays and objects are nested deeper than allowed.
+fn check_nesting(data: &[u8], max_depth: usize) -> io::Result<()> {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for &byte in data {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                depth += 1;
+                if depth > max_depth {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              "nesting is too deep"));
+                }
+            },
+            b']' | b'}' => {
+                if depth == 0 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              "unbalanced closing bracket"));
+                }
+                depth -= 1;
+            },
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Encode arguments or a result of a custom method with `JsonCodec`.
+pub fn encode_payload<T: Encodable>(value: &T) -> io::Result<Vec<u8>> {
+    JsonCodec::new().encode(value)
+}
+
+/// Decode arguments or a result of a custom method with `JsonCodec`.
+pub fn decode_payload<T: Decodable>(payload: &[u8]) -> io::Result<T> {
+    JsonCodec::new().decode(payload)
+}
+
+/// Parse a packet with the default `JsonCodec` limits.
+///
+/// This is the entry point for fuzzers: it takes any bytes and never
+/// panics, malformed input fails with `ErrorKind::InvalidData`.
+pub fn decode_packet(data: &[u8]) -> io::Result<Json> {
+    JsonCodec::new().parse(data)
+}
+
+/// Per-reason rate limiter for log messages.
+///
+/// Allows at most a given number of messages per minute for every reason.
+/// Once a minute is over, the number of messages suppressed for a reason
+/// is logged as a single summary, either on the next message with this
+/// reason or by `flush`.
+pub struct LogLimiter {
+    max_per_minute: usize,
+    windows: HashMap<&'static str, LogWindow>,
+    clock: Arc<dyn Clock>,
+}
+
+struct LogWindow {
+    start: Instant,
+    logged: usize,
+    suppressed: usize,
+}
+
+/// Rate-limited log of packets that could not be parsed.
+///
+/// Logs at most a given number of packets per minute for every reason as
+/// hex together with their source, and keeps them for inspection.
+pub struct BadPacketLog<TAddr> {
+    max_per_minute: usize,
+    limiter: LogLimiter,
+    packets: VecDeque<(TAddr, Vec<u8>)>,
+}
+
+impl LogLimiter {
+    /// Create a limiter allowing 10 messages per minute for every reason.
+    pub fn new() -> LogLimiter {
+        LogLimiter::with_limit(BAD_PACKETS_PER_MINUTE)
+    }
+
+    /// Create a limiter allowing `max_per_minute` messages per minute for
+    /// every reason.
+    pub fn with_limit(max_per_minute: usize) -> LogLimiter {
+        LogLimiter {
+            max_per_minute,
+            windows: HashMap::new(),
+            clock: Arc::new(SystemClock)
+        }
+    }
+
+    /// Set the clock used for the per-minute limit.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Check whether a message with a given reason may be logged now.
+    ///
+    /// If not, it is counted as suppressed.
+    pub fn allow(&mut self, reason: &'static str) -> bool {
+        let now = self.clock.now();
+        let window = self.windows.entry(reason).or_insert(LogWindow {
+            start: now,
+            logged: 0,
+            suppressed: 0
+        });
+        if now.duration_since(window.start) >= Duration::from_secs(60) {
+            window.summarize(reason);
+            window.start = now;
+            window.logged = 0;
+        }
+        if window.logged >= self.max_per_minute {
+            window.suppressed += 1;
+            return false;
+        }
+        window.logged += 1;
+        true
+    }
+
+    /// Log summaries for all reasons whose minute is over.
+    ///
+    /// Should be called periodically, so that summaries are not delayed
+    /// until the next message. Returns the number of suppressed messages
+    /// summarized.
+    pub fn flush(&mut self) -> usize {
+        let now = self.clock.now();
+        let mut summarized = 0;
+        self.windows.retain(|reason, window| {
+            if now.duration_since(window.start) < Duration::from_secs(60) {
+                return true;
+            }
+            summarized += window.suppressed;
+            window.summarize(reason);
+            false
+        });
+        summarized
+    }
+
+    /// Number of messages with a given reason suppressed in the current
+    /// minute.
+    pub fn suppressed(&self, reason: &'static str) -> usize {
+        self.windows.get(reason).map(|window| window.suppressed).unwrap_or(0)
+    }
+
+    /// Number of messages suppressed in the current minute for all reasons.
+    pub fn suppressed_total(&self) -> usize {
+        self.windows.values().map(|window| window.suppressed).sum()
+    }
+}
+
+impl Default for LogLimiter {
+    fn default() -> LogLimiter {
+        LogLimiter::new()
+    }
+}
+
+impl LogWindow {
+    fn summarize(&mut self, reason: &str) {
+        if self.suppressed > 0 {
+            warn!("{}: suppressed {} similar events", reason, self.suppressed);
+        }
+        self.suppressed = 0;
+    }
+}
+
+impl<TAddr> BadPacketLog<TAddr>
+        where TAddr: Debug {
+    /// Create a log keeping at most 10 packets per minute.
+    pub fn new() -> BadPacketLog<TAddr> {
+        BadPacketLog::with_limit(BAD_PACKETS_PER_MINUTE)
+    }
+
+    /// Create a log keeping at most `max_per_minute` packets per minute.
+    pub fn with_limit(max_per_minute: usize) -> BadPacketLog<TAddr> {
+        BadPacketLog {
+            max_per_minute,
+            limiter: LogLimiter::with_limit(max_per_minute),
+            packets: VecDeque::with_capacity(max_per_minute)
+        }
+    }
+
+    /// Set the clock used for the per-minute limit.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.limiter.set_clock(clock);
+    }
+
+    /// Record a packet that failed to parse.
+    ///
+    /// Returns whether the packet was logged.
+    pub fn record(&mut self, source: TAddr, data: &[u8]) -> bool {
+        self.record_with_reason(source, data, "Unparseable packet")
+    }
+
+    /// Record a packet that failed to parse for a given reason.
+    ///
+    /// Every reason has its own limit. Returns whether the packet was logged.
+    pub fn record_with_reason(&mut self, source: TAddr, data: &[u8],
+                              reason: &'static str) -> bool {
+        if !self.limiter.allow(reason) {
+            return false;
+        }
+        warn!("{} from {:?}: {}", reason, source, data.to_hex());
+        if self.packets.len() >= self.max_per_minute {
+            self.packets.pop_front();
+        }
+        self.packets.push_back((source, data.to_vec()));
+        true
+    }
+
+    /// Log summaries of packets suppressed in the previous minutes.
+    pub fn flush(&mut self) -> usize {
+        self.limiter.flush()
+    }
+
+    /// Packets logged recently, oldest first.
+    pub fn packets(&self) -> &VecDeque<(TAddr, Vec<u8>)> {
+        &self.packets
+    }
+
+    /// Number of packets not logged in the current minute.
+    pub fn suppressed(&self) -> usize {
+        self.limiter.suppressed_total()
+    }
+}
+
+impl<TAddr> Default for BadPacketLog<TAddr>
+        where TAddr: Debug {
+    fn default() -> BadPacketLog<TAddr> {
+        BadPacketLog::new()
+    }
+}
+
+/// Reason for rejecting a response, see `PendingRequests::check`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ResponseError<TId> {
+    /// No request with this ID is pending, or it has timed out.
+    UnknownRequest,
+    /// The request was sent to another address.
+    WrongAddress,
+    /// The responder claims another ID than the one we know for its address.
+    IdConflict(TId),
+}
+
+/// Requests sent to other nodes and waiting for responses.
+///
+/// Responses are only accepted from the exact address a request was
+/// sent to, unless another matcher is set with `set_address_matcher`.
+/// If the ID of the node is known, e.g. from the node table, the
+/// responder must also claim the same ID.
+///
+/// Request IDs must be unique among pending requests. When the maximum
+/// number of requests is pending, the oldest one is dropped to make room
+/// for a new one.
+pub struct PendingRequests<TId, TAddr> {
+    timeout: Duration,
+    max_pending: usize,
+    requests: HashMap<TId, PendingRequest<TId, TAddr>>,
+    // Request IDs by insertion order
+    order: BTreeMap<u64, TId>,
+    next_order: u64,
+    matcher: AddressMatcher<TAddr>,
+    clock: Arc<dyn Clock>,
+}
+
+type AddressMatcher<TAddr> = Box<dyn Fn(&TAddr, &TAddr) -> bool + Send>;
+
+struct PendingRequest<TId, TAddr> {
+    address: TAddr,
+    node_id: Option<TId>,
+    sent_at: Instant,
+    order: u64,
+}
+
+impl<TId, TAddr> PendingRequests<TId, TAddr>
+        where TId: GenericId,
+              TAddr: PartialEq + Debug + 'static {
+    /// Create an empty set, requests time out after 10 seconds.
+    ///
+    /// At most 10000 requests are pending at a time.
+    pub fn new() -> PendingRequests<TId, TAddr> {
+        PendingRequests::with_timeout(Duration::from_secs(REQUEST_TIMEOUT))
+    }
+
+    /// Create an empty set with a given request timeout.
+    pub fn with_timeout(timeout: Duration) -> PendingRequests<TId, TAddr> {
+        PendingRequests::with_limits(timeout, MAX_PENDING_REQUESTS)
+    }
+
+    /// Create an empty set with a given request timeout and maximum
+    /// number of pending requests.
+    pub fn with_limits(timeout: Duration, max_pending: usize)
+            -> PendingRequests<TId, TAddr> {
+        assert!(max_pending > 0);
+        PendingRequests {
+            timeout,
+            max_pending,
+            requests: HashMap::new(),
+            order: BTreeMap::new(),
+            next_order: 0,
+            matcher: Box::new(|expected, source| expected == source),
+            clock: Arc::new(SystemClock)
+        }
+    }
+
+    /// Set the clock used for timeouts.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Set the function deciding whether a response source matches
+    /// the address a request was sent to, e.g. `same_ip` for nodes
+    /// behind NATs changing ports.
+    pub fn set_address_matcher<F>(&mut self, matcher: F)
+            where F: Fn(&TAddr, &TAddr) -> bool + Send + 'static {
+        self.matcher = Box::new(matcher);
+    }
+
+    /// Number of pending requests.
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    /// Whether no requests are pending.
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Remember a request sent to an address.
+    ///
+    /// `node_id` -- ID of the node we expect to respond, if known.
+    ///
+    /// Returns `false` and keeps the existing request if a request with
+    /// the same ID is already pending.
+    pub fn insert(&mut self, request_id: TId, address: TAddr, node_id: Option<TId>) -> bool {
+        if self.requests.contains_key(&request_id) {
+            debug!("Request {:?} to {:?} is already pending", request_id, address);
+            return false;
+        }
+        if self.requests.len() >= self.max_pending {
+            let oldest = self.order.keys().next().cloned().unwrap();
+            let id = self.order.remove(&oldest).unwrap();
+            debug!("Dropping the oldest pending request {:?}", id);
+            self.requests.remove(&id);
+        }
+        let sent_at = self.clock.now();
+        let order = self.next_order;
+        self.next_order += 1;
+        self.order.insert(order, request_id.clone());
+        self.requests.insert(request_id, PendingRequest { address, node_id, sent_at, order });
+        true
+    }
+
+    /// Check a response to a request, returns the time since it was sent.
+    ///
+    /// The request stays pending if the response came from a wrong
+    /// address, so that a spoofed response cannot cancel the real one.
+    pub fn check(&mut self, source: &TAddr, request_id: &TId, responder_id: &TId)
+            -> Result<Duration, ResponseError<TId>> {
+        let now = self.clock.now();
+        let request = match self.requests.get(request_id) {
+            Some(request) if now.duration_since(request.sent_at) < self.timeout => request,
+            _ => return Err(ResponseError::UnknownRequest)
+        };
+        if !(self.matcher)(&request.address, source) {
+            debug!("Response to {:?} from {:?}, expected {:?}",
+                   request_id, source, request.address);
+            return Err(ResponseError::WrongAddress);
+        }
+        let request = self.requests.remove(request_id).unwrap();
+        self.order.remove(&request.order);
+        match request.node_id {
+            Some(id) if id != *responder_id => {
+                debug!("Node {:?} at {:?} responded as {:?}", id, source, responder_id);
+                Err(ResponseError::IdConflict(id))
+            },
+            _ => Ok(now.duration_since(request.sent_at))
+        }
+    }
+
+    /// Check a parsed response, see `check`.
+    pub fn check_response<TValue>(&mut self, source: &TAddr,
+                                  response: &Response<TId, TAddr, TValue>)
+            -> Result<Duration, ResponseError<TId>> {
+        self.check(source, &response.request.request_id, &response.responder.id)
+    }
+
+    /// Remove requests that timed out, returns their IDs and addresses.
+    pub fn expire(&mut self) -> Vec<(TId, TAddr)> {
+        let now = self.clock.now();
+        let timeout = self.timeout;
+        let expired: Vec<TId> = self.requests.iter()
+            .filter(|&(_, request)| now.duration_since(request.sent_at) >= timeout)
+            .map(|(id, _)| id.clone())
+            .collect();
+        expired.into_iter()
+            .map(|id| {
+                let request = self.requests.remove(&id).unwrap();
+                self.order.remove(&request.order);
+                (id, request.address)
+            })
+            .collect()
+    }
+}
+
+impl<TId, TAddr> Default for PendingRequests<TId, TAddr>
+        where TId: GenericId,
+              TAddr: PartialEq + Debug + 'static {
+    fn default() -> PendingRequests<TId, TAddr> {
+        PendingRequests::new()
+    }
+}
+
+/// Whether a response came from the IP address a request was sent to,
+/// possibly from another port.
+pub fn same_ip(expected: &SocketAddr, source: &SocketAddr) -> bool {
+    expected.ip().to_canonical() == source.ip().to_canonical()
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use rand::{Rng, SeedableRng, XorShiftRng};
+    use rustc_serialize::json::ToJson;
+
+    use super::super::Node;
+    use super::super::mock::MockClock;
+    use super::{BadPacketLog, JsonCodec, LogLimiter, PendingRequests, ResponseError,
+                WireCodec, decode_packet, decode_payload, encode_payload, same_ip};
+
+    use super::super::utils::test;
+    type TestsIdType = test::IdType;
+
+    #[test]
+    fn test_bad_packet_log() {
+        let mut log = BadPacketLog::with_limit(2);
+        assert!(log.record("a", b"foo"));
+        assert!(log.record("b", b"bar"));
+        assert!(!log.record("c", b"baz"));
+        assert_eq!(1, log.suppressed());
+        assert_eq!(2, log.packets().len());
+        assert_eq!(("a", b"foo".to_vec()), log.packets()[0]);
+    }
+
+    #[test]
+    fn test_payload() {
+        let payload = encode_payload(&(42u32, "foo".to_string())).unwrap();
+        let decoded: (u32, String) = decode_payload(&payload).unwrap();
+        assert_eq!((42, "foo".to_string()), decoded);
+        assert!(decode_payload::<u32>(b"\xff").is_err());
+        assert!(decode_payload::<u32>(&payload).is_err());
+    }
+
+    #[test]
+    fn test_encode_into() {
+        let value = (42u32, vec!["foo".to_string()]);
+        let mut buffer = b"header".to_vec();
+        JsonCodec::new().encode_into(&value, &mut buffer).unwrap();
+        assert_eq!(b"header", &buffer[..6]);
+        assert_eq!(JsonCodec::new().encode(&value).unwrap(), &buffer[6..]);
+    }
+
+    #[test]
+    fn test_encode_matches_tree() {
+        let mut map = BTreeMap::new();
+        map.insert("b".to_string(), vec![1.5f64, -2.0]);
+        map.insert("a".to_string(), vec![]);
+        let value = (42u32, "quote \" and \u{e9}".to_string(), Some(map), None::<bool>);
+        let mut buffer = vec![];
+        JsonCodec::new().encode_into(&value, &mut buffer).unwrap();
+        assert_eq!(value.to_json().to_string().into_bytes(), buffer);
+    }
+
+    #[test]
+    fn test_decode_packet() {
+        let nodes = vec![test::new_node(test::make_id(42)),
+                         test::new_node_with_port(test::make_id(43), 1)];
+        let encoded = JsonCodec::new().encode(&nodes).unwrap();
+        let decoded: Vec<Node<TestsIdType, SocketAddr>> =
+            JsonCodec::new().decode(&encoded).unwrap();
+        assert_eq!(nodes.iter().map(|n| (&n.id, n.address)).collect::<Vec<_>>(),
+                   decoded.iter().map(|n| (&n.id, n.address)).collect::<Vec<_>>());
+        let tree = decode_packet(&encoded).unwrap();
+        assert_eq!(Some("2a"), tree[0]["id"].as_string());
+        assert_eq!(tree.to_string().into_bytes(), encoded);
+        // Unknown keys are kept in the tree
+        let tree = decode_packet(b"{\"id\":\"2a\",\"future\":[1]}").unwrap();
+        assert!(tree["future"].is_array());
+        assert!(decode_packet(b"{\"id\":").is_err());
+        assert!(decode_packet(&[0xff, 0xfe]).is_err());
+    }
+
+    #[test]
+    fn test_decode_packet_garbage() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let valid = JsonCodec::new().encode(&vec![test::new_node(test::make_id(42))]).unwrap();
+        let alphabet = b"[]{}\",:\\ 0123456789.eE+-truefalsnu\xff";
+        for _ in 0..10000 {
+            // Random bytes from JSON syntax, or a corrupted valid packet
+            let data: Vec<u8> = if rng.gen() {
+                let len = rng.gen_range(0, 64);
+                (0..len).map(|_| alphabet[rng.gen_range(0, alphabet.len())]).collect()
+            }
+            else {
+                let mut data = valid.clone();
+                let index = rng.gen_range(0, data.len());
+                data[index] = alphabet[rng.gen_range(0, alphabet.len())];
+                data.truncate(rng.gen_range(index, valid.len() + 1));
+                data
+            };
+            let _ = decode_packet(&data);
+            let _ = JsonCodec::new().decode::<Vec<Node<TestsIdType, SocketAddr>>>(&data);
+        }
+    }
+
+    #[test]
+    fn test_bad_packet_log_window() {
+        let clock = MockClock::new();
+        let mut log = BadPacketLog::with_limit(1);
+        log.set_clock(Arc::new(clock.clone()));
+        assert!(log.record("a", b"foo"));
+        assert!(!log.record("b", b"bar"));
+        clock.advance(Duration::from_secs(60));
+        assert!(log.record("c", b"baz"));
+        assert_eq!(0, log.suppressed());
+        assert_eq!(("c", b"baz".to_vec()), log.packets()[0]);
+    }
+
+    #[test]
+    fn test_log_limiter() {
+        let clock = MockClock::new();
+        let mut limiter = LogLimiter::with_limit(2);
+        limiter.set_clock(Arc::new(clock.clone()));
+        let logged = (0..5).filter(|_| limiter.allow("foo")).count();
+        assert_eq!(2, logged);
+        assert!(limiter.allow("bar"));
+        assert_eq!(3, limiter.suppressed("foo"));
+        assert_eq!(3, limiter.suppressed_total());
+        assert_eq!(0, limiter.flush());
+
+        clock.advance(Duration::from_secs(30));
+        assert!(limiter.allow("bar"));
+        assert!(!limiter.allow("bar"));
+        clock.advance(Duration::from_secs(30));
+        // Summary for foo is logged by the next message
+        assert!(limiter.allow("foo"));
+        assert_eq!(0, limiter.suppressed("foo"));
+        // Summary for bar is only logged by flush
+        assert_eq!(1, limiter.suppressed("bar"));
+        assert_eq!(1, limiter.flush());
+        assert_eq!(0, limiter.suppressed_total());
+    }
+
+    #[test]
+    fn test_bad_packet_log_reasons() {
+        let mut log = BadPacketLog::with_limit(1);
+        assert!(log.record_with_reason("a", b"foo", "Invalid ID"));
+        assert!(log.record_with_reason("a", b"bar", "Invalid value"));
+        assert!(!log.record_with_reason("a", b"baz", "Invalid ID"));
+        assert_eq!(1, log.suppressed());
+        assert_eq!(1, log.packets().len());
+        assert_eq!(("a", b"bar".to_vec()), log.packets()[0]);
+    }
+
+    #[test]
+    fn test_pending_requests() {
+        let clock = MockClock::new();
+        let mut p = PendingRequests::with_timeout(Duration::from_secs(10));
+        p.set_clock(Arc::new(clock.clone()));
+        p.insert(1u64, "a", Some(42u64));
+        p.insert(2, "b", None);
+        p.insert(3, "c", Some(44));
+        clock.advance(Duration::from_secs(1));
+
+        assert_eq!(Err(ResponseError::UnknownRequest), p.check(&"a", &4, &42));
+        assert_eq!(Err(ResponseError::WrongAddress), p.check(&"b", &1, &42));
+        assert_eq!(Ok(Duration::from_secs(1)), p.check(&"a", &1, &42));
+        assert_eq!(Err(ResponseError::UnknownRequest), p.check(&"a", &1, &42));
+        assert_eq!(Ok(Duration::from_secs(1)), p.check(&"b", &2, &43));
+        assert_eq!(Err(ResponseError::IdConflict(44)), p.check(&"c", &3, &45));
+        assert!(p.is_empty());
+    }
+
+    #[test]
+    fn test_pending_requests_expire() {
+        let clock = MockClock::new();
+        let mut p = PendingRequests::with_timeout(Duration::from_secs(10));
+        p.set_clock(Arc::new(clock.clone()));
+        p.insert(1u64, "a", None);
+        clock.advance(Duration::from_secs(5));
+        p.insert(2, "b", None);
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(Err(ResponseError::UnknownRequest), p.check(&"a", &1, &42));
+        assert_eq!(vec![(1, "a")], p.expire());
+        assert_eq!(1, p.len());
+    }
+
+    #[test]
+    fn test_pending_requests_same_ip() {
+        let address = |s: &str| -> SocketAddr { s.parse().unwrap() };
+        let mut p = PendingRequests::new();
+        p.set_address_matcher(same_ip);
+        p.insert(1u64, address("10.0.0.1:1"), None);
+        assert_eq!(Err(ResponseError::WrongAddress),
+                   p.check(&address("10.0.0.2:1"), &1, &42));
+        assert!(p.check(&address("[::ffff:10.0.0.1]:2"), &1, &42).is_ok());
+        // The transaction ID must still match
+        p.insert(2, address("10.0.0.1:1"), None);
+        assert_eq!(Err(ResponseError::UnknownRequest),
+                   p.check(&address("10.0.0.1:1"), &3, &42));
+    }
+
+    #[test]
+    fn test_pending_requests_duplicate() {
+        let mut p = PendingRequests::new();
+        p.set_address_matcher(same_ip);
+        let address = |s: &str| -> SocketAddr { s.parse().unwrap() };
+        assert!(p.insert(1u64, address("10.0.0.1:1"), None));
+        // Another address cannot take over the request ID
+        assert!(!p.insert(1, address("10.0.0.2:1"), None));
+        assert_eq!(1, p.len());
+        assert_eq!(Err(ResponseError::WrongAddress),
+                   p.check(&address("10.0.0.2:1"), &1, &42));
+        assert!(p.check(&address("10.0.0.1:2"), &1, &42).is_ok());
+        // The ID can be reused once the request is done
+        assert!(p.insert(1, address("10.0.0.2:1"), None));
+    }
+
+    #[test]
+    fn test_pending_requests_limit() {
+        let clock = MockClock::new();
+        let mut p = PendingRequests::with_limits(Duration::from_secs(10), 2);
+        p.set_clock(Arc::new(clock.clone()));
+        assert!(p.insert(1u64, "a", None));
+        assert!(p.insert(2, "b", None));
+        assert!(p.check(&"a", &1, &42).is_ok());
+        assert!(p.insert(3, "c", None));
+        assert!(p.insert(4, "d", None));
+        assert_eq!(2, p.len());
+        assert_eq!(Err(ResponseError::UnknownRequest), p.check(&"b", &2, &42));
+        assert!(p.check(&"c", &3, &42).is_ok());
+        assert!(p.check(&"d", &4, &42).is_ok());
+        assert!(p.order.is_empty());
+    }
+
+    #[test]
+    fn test_decode_limits() {
+        let too_deep = |depth: usize| {
+            let nested = format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+            let err = JsonCodec::new().decode::<Vec<u32>>(nested.as_bytes()).unwrap_err();
+            err.to_string().contains("too deep")
+        };
+        assert!(!too_deep(32));
+        assert!(too_deep(33));
+        // Brackets in strings do not count
+        let quoted = format!("\"\\\"{}\"", "[".repeat(100));
+        assert!(JsonCodec::new().decode::<String>(quoted.as_bytes()).is_ok());
+        let large = format!("\"{}\"", "a".repeat(70000));
+        assert!(JsonCodec::new().decode::<String>(large.as_bytes()).is_err());
+        assert!(JsonCodec::default().decode::<String>(large.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_decode_custom_limits() {
+        let codec = JsonCodec::with_limits(10, 2);
+        assert_eq!(vec![vec![1u32]], codec.decode::<Vec<Vec<u32>>>(b"[[1]]").unwrap());
+        assert!(codec.decode::<Vec<Vec<Vec<u32>>>>(b"[[[]]]").is_err());
+        assert!(codec.decode::<Vec<u32>>(b"[1,2,3,4,5]").is_err());
+        // Limits above the defaults are allowed too
+        let codec = JsonCodec::with_limits(1 << 20, 64);
+        let nested = format!("{}{}", "[".repeat(40), "]".repeat(40));
+        let err = codec.decode::<Vec<u32>>(nested.as_bytes()).unwrap_err();
+        assert!(!err.to_string().contains("too deep"));
+        let large = format!("\"{}\"", "a".repeat(70000));
+        assert!(codec.decode::<String>(large.as_bytes()).is_ok());
+    }
+
+    #[test]
+    fn test_decode_unbalanced() {
+        let unbalanced = |data: &str| {
+            let err = JsonCodec::new().decode::<Vec<u32>>(data.as_bytes()).unwrap_err();
+            err.to_string().contains("unbalanced")
+        };
+        assert!(unbalanced("]"));
+        assert!(unbalanced("[]}"));
+        // Closers must not make room for deeper nesting
+        assert!(unbalanced(&format!("]]{}{}", "[".repeat(33), "]".repeat(33))));
+    }
 }
@@ -0,0 +1,251 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+//! Re-publication of values originated by this node.
+//!
+//! Nodes holding replicas leave the network over time, so values have to be
+//! stored again on the current closest nodes periodically.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::{GenericAPI, GenericId, Node};
+use super::clock::{Clock, SystemClock};
+
+
+static REPUBLISH_INTERVAL: u64 = 60 * 60;
+
+
+/// Set of values to re-publish periodically.
+pub struct PublishSet<TId, TValue> {
+    interval: Duration,
+    entries: HashMap<TId, PublishEntry<TValue>>,
+    clock: Arc<dyn Clock>,
+}
+
+/// Value with the time it was last published.
+struct PublishEntry<TValue> {
+    value: TValue,
+    published_at: Option<Instant>,
+}
+
+
+impl<TId, TValue> PublishSet<TId, TValue>
+        where TId: GenericId,
+              TValue: Clone {
+    /// Create an empty set with the default interval of 1 hour.
+    pub fn new() -> PublishSet<TId, TValue> {
+        PublishSet::with_interval(Duration::from_secs(REPUBLISH_INTERVAL))
+    }
+
+    /// Create an empty set with a given re-publication interval.
+    pub fn with_interval(interval: Duration) -> PublishSet<TId, TValue> {
+        PublishSet {
+            interval,
+            entries: HashMap::new(),
+            clock: Arc::new(SystemClock)
+        }
+    }
+
+    /// Get the re-publication interval.
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Set the re-publication interval.
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    /// Set the clock used to decide when values are due.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Add or replace a value, it will be published on the next run.
+    pub fn add(&mut self, id: TId, value: TValue) {
+        let entry = PublishEntry {
+            value,
+            published_at: None
+        };
+        self.entries.insert(id, entry);
+    }
+
+    /// Add or replace a value that was just published elsewhere.
+    ///
+    /// It will be published again after the interval.
+    pub fn add_published(&mut self, id: TId, value: TValue) {
+        let entry = PublishEntry {
+            value,
+            published_at: Some(self.clock.now())
+        };
+        self.entries.insert(id, entry);
+    }
+
+    /// Stop re-publishing a value.
+    pub fn remove(&mut self, id: &TId) -> Option<TValue> {
+        self.entries.remove(id).map(|entry| entry.value)
+    }
+
+    /// Check if a value is in the set.
+    pub fn contains(&self, id: &TId) -> bool {
+        self.entries.contains_key(id)
+    }
+
+    /// Number of values in the set.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether the set is empty.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Return values due for publication and mark them as published.
+    pub fn due(&mut self) -> Vec<(TId, TValue)> {
+        let now = self.clock.now();
+        let interval = self.interval;
+        self.entries.iter_mut()
+            .filter(|(_, entry)| match entry.published_at {
+                Some(published_at) => now.duration_since(published_at) >= interval,
+                None => true
+            })
+            .map(|(id, entry)| {
+                entry.published_at = Some(now);
+                (id.clone(), entry.value.clone())
+            })
+            .collect()
+    }
+
+    /// Store due values on the nodes closest to them.
+    ///
+    /// Runs `find_node` for every due value and then `store` on every node
+    /// found. Expects `api` to call `find_node` callbacks before returning.
+    pub fn republish<TAddr, TAPI>(&mut self, api: &mut TAPI)
+            where TAPI: GenericAPI<TId, TAddr, TValue=TValue> {
+        for (id, value) in self.due() {
+            let mut nodes: Vec<Node<TId, TAddr>> = vec![];
+            api.find_node(&id, |found| nodes = found);
+            debug!("Re-publishing value {:?} on {} nodes", id, nodes.len());
+            for node in &nodes {
+                api.store(node, &id, value.clone());
+            }
+        }
+    }
+}
+
+impl<TId, TValue> Default for PublishSet<TId, TValue>
+        where TId: GenericId,
+              TValue: Clone {
+    fn default() -> PublishSet<TId, TValue> {
+        PublishSet::new()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::net;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::super::{GenericAPI, Node};
+    use super::super::mock::MockClock;
+    use super::PublishSet;
+
+    use super::super::utils::test;
+    type TestsIdType = test::IdType;
+
+
+    struct DummyAPI {
+        stored: Vec<(TestsIdType, TestsIdType, i32)>
+    }
+
+    impl GenericAPI<TestsIdType, net::SocketAddr> for DummyAPI {
+        type TValue = i32;
+        fn ping<F>(&mut self, node: &Node<TestsIdType, net::SocketAddr>, callback: F)
+                where F: FnOnce(&Node<TestsIdType, net::SocketAddr>, bool) {
+            callback(node, true);
+        }
+        fn find_node<F>(&mut self, _id: &TestsIdType, callback: F)
+                where F: FnOnce(Vec<Node<TestsIdType, net::SocketAddr>>) {
+            callback(vec![test::new_node(test::make_id(1)),
+                          test::new_node(test::make_id(2))]);
+        }
+        fn find_value<F>(&mut self, _id: &TestsIdType, callback: F)
+                where F: FnOnce(Option<Self::TValue>, Vec<Node<TestsIdType, net::SocketAddr>>) {
+            callback(None, vec![]);
+        }
+        fn store(&mut self, node: &Node<TestsIdType, net::SocketAddr>, id: &TestsIdType, value: Self::TValue) {
+            self.stored.push((node.id.clone(), id.clone(), value));
+        }
+    }
+
+    #[test]
+    fn test_add_remove() {
+        let mut p = PublishSet::<TestsIdType, i32>::new();
+        assert!(p.is_empty());
+        p.add(test::make_id(42), 1);
+        assert!(p.contains(&test::make_id(42)));
+        assert_eq!(1, p.len());
+        assert_eq!(Some(1), p.remove(&test::make_id(42)));
+        assert!(p.is_empty());
+    }
+
+    #[test]
+    fn test_due() {
+        let mut p = PublishSet::<TestsIdType, i32>::new();
+        p.add(test::make_id(42), 1);
+        assert_eq!(vec![(test::make_id(42), 1)], p.due());
+        assert!(p.due().is_empty());
+
+        p.set_interval(Duration::from_secs(0));
+        assert_eq!(vec![(test::make_id(42), 1)], p.due());
+    }
+
+    #[test]
+    fn test_add_published() {
+        let clock = MockClock::new();
+        let mut p = PublishSet::<TestsIdType, i32>::new();
+        p.set_clock(Arc::new(clock.clone()));
+        p.add_published(test::make_id(42), 1);
+        assert!(p.due().is_empty());
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(vec![(test::make_id(42), 1)], p.due());
+    }
+
+    #[test]
+    fn test_due_clock() {
+        let clock = MockClock::new();
+        let mut p = PublishSet::<TestsIdType, i32>::new();
+        p.set_clock(Arc::new(clock.clone()));
+        p.add(test::make_id(42), 1);
+        assert_eq!(1, p.due().len());
+        clock.advance(Duration::from_secs(3599));
+        assert!(p.due().is_empty());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(1, p.due().len());
+    }
+
+    #[test]
+    fn test_republish() {
+        let mut p = PublishSet::<TestsIdType, i32>::new();
+        let mut api = DummyAPI { stored: vec![] };
+        p.add(test::make_id(42), 1);
+        p.republish(&mut api);
+        assert_eq!(vec![(test::make_id(1), test::make_id(42), 1),
+                        (test::make_id(2), test::make_id(42), 1)],
+                   api.stored);
+
+        p.republish(&mut api);
+        assert_eq!(2, api.stored.len());
+    }
+}
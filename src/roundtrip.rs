@@ -0,0 +1,202 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+//! Round-trip checks for wire encodings, behind the `test-support` feature.
+//!
+//! Forks and alternative codecs can feed generated values through
+//! `check_round_trip`, and check with `check_unknown_keys` that keys added
+//! by newer versions survive, to verify that they stay compatible with
+//! `protocol::JsonCodec`.
+
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+use rand::Rng;
+use rustc_serialize::{Decodable, Encodable};
+use rustc_serialize::json::Json;
+
+use super::{GenericId, Node};
+use super::protocol::{JsonCodec, WireCodec};
+
+
+/// Generate a node with a random ID of `hash_size` bits and a random
+/// IPv4 or IPv6 address.
+pub fn random_node<TId, R>(rng: &mut R, hash_size: usize) -> Node<TId, SocketAddr>
+        where TId: GenericId,
+              R: Rng {
+    let port = rng.gen();
+    let address = if rng.gen() {
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(rng.gen::<u32>()), port))
+    }
+    else {
+        let mut octets = [0u8; 16];
+        rng.fill_bytes(&mut octets);
+        SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(octets), port, 0, 0))
+    };
+    Node {
+        id: TId::gen(hash_size),
+        address
+    }
+}
+
+/// Generate a JSON tree nested at most `depth` levels deep.
+///
+/// Numbers are integers or binary fractions, so that every tree survives
+/// the text encoding exactly.
+pub fn random_json<R: Rng>(rng: &mut R, depth: usize) -> Json {
+    let kinds = if depth == 0 { 6 } else { 8 };
+    match rng.gen_range(0, kinds) {
+        0 => Json::Null,
+        1 => Json::Boolean(rng.gen()),
+        2 => Json::U64(rng.gen()),
+        3 => Json::I64(-rng.gen_range(1, i64::MAX)),
+        4 => Json::F64(f64::from(rng.gen::<i32>()) / 4.0),
+        5 => Json::String(random_string(rng)),
+        6 => {
+            let len = rng.gen_range(0, 4);
+            Json::Array((0..len).map(|_| random_json(rng, depth - 1)).collect())
+        },
+        _ => {
+            let len = rng.gen_range(0, 4);
+            Json::Object((0..len)
+                .map(|_| (random_string(rng), random_json(rng, depth - 1)))
+                .collect())
+        }
+    }
+}
+
+/// Check that a value decodes from its encoding and encodes the same way
+/// again.
+///
+/// Comparing the encodings works for types without `PartialEq`, e.g.
+/// `Node`. `WireCodec::encode_into` must produce the same bytes as
+/// `WireCodec::encode`.
+pub fn check_round_trip<C, T>(codec: &C, value: &T) -> io::Result<()>
+        where C: WireCodec,
+              T: Encodable + Decodable + Debug {
+    let encoded = codec.encode(value)?;
+    let mut buffer = vec![];
+    codec.encode_into(value, &mut buffer)?;
+    if buffer != encoded {
+        return Err(mismatch("encode_into differs from encode", value));
+    }
+    let decoded: T = codec.decode(&encoded)?;
+    if codec.encode(&decoded)? != encoded {
+        return Err(mismatch("value changed in a round trip", value));
+    }
+    Ok(())
+}
+
+/// Check that keys unknown to a type survive in JSON trees and do not
+/// break decoding.
+///
+/// Every object in the encoding of `value` gets the keys of `extra`
+/// (an object) that it does not have yet. The result must parse into the
+/// same tree again, and decode into a value encoded like the original.
+pub fn check_unknown_keys<T>(codec: &JsonCodec, value: &T, extra: &Json) -> io::Result<()>
+        where T: Encodable + Decodable + Debug {
+    let extra = match *extra {
+        Json::Object(ref extra) => extra,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                       "extra keys must be an object"))
+    };
+    let encoded = codec.encode(value)?;
+    let mut tree = codec.parse(&encoded)?;
+    add_keys(&mut tree, extra);
+    let extended = codec.encode(&tree)?;
+    if codec.parse(&extended)? != tree {
+        return Err(mismatch("unknown keys were not preserved", value));
+    }
+    let decoded: T = codec.decode(&extended)?;
+    if codec.encode(&decoded)? != encoded {
+        return Err(mismatch("unknown keys changed the value", value));
+    }
+    Ok(())
+}
+
+fn add_keys(tree: &mut Json, extra: &BTreeMap<String, Json>) {
+    match *tree {
+        Json::Object(ref mut object) => {
+            for value in object.values_mut() {
+                add_keys(value, extra);
+            }
+            for (key, value) in extra {
+                object.entry(key.clone()).or_insert_with(|| value.clone());
+            }
+        },
+        Json::Array(ref mut array) => {
+            for value in array {
+                add_keys(value, extra);
+            }
+        },
+        _ => {}
+    }
+}
+
+fn random_string<R: Rng>(rng: &mut R) -> String {
+    // Quotes, escapes and non-ASCII characters need care in encoders
+    let alphabet = ['a', 'z', '0', ' ', '"', '\\', '/', '\n', '\u{1}', '\u{e9}', '\u{1f600}'];
+    let len = rng.gen_range(0, 8);
+    (0..len).map(|_| alphabet[rng.gen_range(0, alphabet.len())]).collect()
+}
+
+fn mismatch<T: Debug>(reason: &str, value: &T) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {:?}", reason, value))
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::net::SocketAddr;
+
+    use rand::{SeedableRng, XorShiftRng};
+    use rustc_serialize::json::Json;
+
+    use super::super::Node;
+    use super::super::protocol::JsonCodec;
+    use super::{check_round_trip, check_unknown_keys, random_json, random_node};
+
+    use super::super::utils::test;
+    type TestsIdType = test::IdType;
+
+
+    #[test]
+    fn test_round_trip() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let codec = JsonCodec::new();
+        for _ in 0..100 {
+            let nodes: Vec<Node<TestsIdType, SocketAddr>> =
+                (0..3).map(|_| random_node(&mut rng, 160)).collect();
+            check_round_trip(&codec, &nodes).unwrap();
+            let tree = random_json(&mut rng, 4);
+            check_round_trip(&codec, &tree.to_string()).unwrap();
+            assert_eq!(tree, codec.parse(tree.to_string().as_bytes()).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_unknown_keys() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let codec = JsonCodec::new();
+        let mut extra = BTreeMap::new();
+        extra.insert("future".to_string(), Json::Array(vec![random_json(&mut rng, 2)]));
+        let extra = Json::Object(extra);
+        let nodes: Vec<Node<TestsIdType, SocketAddr>> =
+            (0..3).map(|_| random_node(&mut rng, 160)).collect();
+        check_unknown_keys(&codec, &nodes, &extra).unwrap();
+        assert!(check_unknown_keys(&codec, &nodes, &Json::Null).is_err());
+        // Maps take every key, so they cannot tolerate unknown ones
+        let mut map = BTreeMap::new();
+        map.insert("known".to_string(), 42u32);
+        assert!(check_unknown_keys(&codec, &map, &extra).is_err());
+    }
+}
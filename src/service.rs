@@ -9,14 +9,52 @@
 
 //! Protocol-agnostic service implementation
 
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::marker;
-use std::collections::HashMap;
+use std::mem;
+use std::net::SocketAddr;
 use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
 
-use super::{GenericId, GenericNodeTable, Node};
+use super::{GenericId, GenericNodeTable, GenericStorage, Indexer, MemoryStorage,
+            Node, StorageStats};
+use super::anomaly::{Anomaly, AnomalyDetector};
+use super::clock::{Clock, SystemClock};
+use super::flood::{FloodCheck, FloodDetector, FloodSource};
+use super::metrics::{self, Histogram, Metrics, NoopMetrics};
+use super::protocol::MAX_EXTENSION_SIZE;
 
 
 static MAX_NODE_COUNT: usize = 16;
+static MAX_SAMPLE_COUNT: usize = 20;
+static SAMPLE_INTERVAL: u64 = 300;
+static DATA_TTL: u64 = 2 * 60 * 60;
+// Estimated bookkeeping overhead of a collection entry in bytes
+static ENTRY_OVERHEAD: usize = 16;
+// Share of the memory budget to shrink to once it is exceeded
+static BUDGET_TARGET: f64 = 0.9;
+static MAX_BANNED_IDS: usize = 10000;
+
+
+/// Handler of a custom method, gets the sender and the encoded arguments
+/// and returns the encoded result.
+///
+/// See `protocol::encode_payload` and `protocol::decode_payload`.
+pub type MethodHandler<TId, TAddr> =
+    Box<dyn FnMut(&Node<TId, TAddr>, &[u8]) -> Vec<u8> + Send>;
+
+/// Handler of extension blobs, gets the sender and the blob from a request
+/// and returns the blob to attach to the response, if any.
+pub type ExtensionHandler<TId, TAddr> =
+    Box<dyn FnMut(&Node<TId, TAddr>, &[u8]) -> Option<Vec<u8>> + Send>;
+
+/// Verifier of values from store requests, gets the sender, the ID and
+/// the value and returns whether to store it.
+///
+/// E.g. when values are peer addresses, it can try connecting to them.
+pub type StoreVerifier<TId, TAddr, TData> =
+    Box<dyn FnMut(&Node<TId, TAddr>, &TId, &TData) -> bool + Send>;
 
 
 /// Result of the find operations - either data or nodes closest to it.
@@ -27,71 +65,304 @@ pub enum FindResult<TId, TAddr, TData> {
     Nothing
 }
 
+/// Result of the sample operation - random IDs of the stored values.
+#[derive(Debug)]
+pub struct SampleResult<TId, TAddr> {
+    /// Random subset of IDs of the stored values.
+    pub ids: Vec<TId>,
+    /// Total number of stored values.
+    pub num: usize,
+    /// Time until the sample is refreshed.
+    pub interval: Duration,
+    /// Nodes closest to the requested target.
+    pub nodes: Vec<Node<TId, TAddr>>
+}
+
+/// Summary of the service state.
+#[derive(Clone, Debug)]
+pub struct HealthReport {
+    /// Number of nodes in the node table.
+    pub table_size: usize,
+    /// Whether some buckets are full and `clean_up` should be called.
+    pub clean_needed: bool,
+    /// Storage statistics.
+    pub storage: StorageStats
+}
+
+/// Estimated memory used by a part of the service.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// Number of entries.
+    pub entries: usize,
+    /// Estimated number of bytes used by the entries.
+    pub bytes: usize
+}
+
+/// Estimated memory used by the service, see `Service::memory_report`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryReport {
+    /// Nodes in the node table.
+    pub table: MemoryUsage,
+    /// Stored values.
+    pub storage: MemoryUsage,
+    /// Response time histograms of nodes.
+    pub rtt: MemoryUsage,
+    /// Transaction log.
+    pub transactions: MemoryUsage
+}
+
+/// Numbers of requests received, by method.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RequestCounters {
+    pub ping: usize,
+    pub find_node: usize,
+    pub find_value: usize,
+    pub store: usize,
+    /// Store requests rejected by the storage.
+    pub store_rejected: usize,
+    pub sample: usize,
+    /// Requests for registered custom methods.
+    pub custom: usize,
+    /// Requests for methods nobody registered.
+    pub unknown_method: usize
+}
+
+/// Numbers of requests sent to other nodes, by outcome.
+///
+/// Requests are counted once complete, see `Service::record_transaction`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TransactionCounters {
+    /// Completed requests by method name.
+    pub requests: BTreeMap<&'static str, usize>,
+    pub responses: usize,
+    pub timeouts: usize,
+    pub errors: usize
+}
+
+/// Snapshot of the service statistics, see `Service::stats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Requests received from other nodes.
+    pub received: RequestCounters,
+    /// Requests sent to other nodes.
+    pub sent: TransactionCounters,
+    /// Number of nodes in the node table.
+    pub table_size: usize,
+    /// Number of stored values.
+    pub stored_items: usize,
+    /// Bytes received, see `Service::record_traffic`.
+    pub bytes_received: u64,
+    /// Bytes sent, see `Service::record_traffic`.
+    pub bytes_sent: u64
+}
+
+/// Reason for rejecting a node ID, see `Handler::check_id`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdRejection {
+    /// All bits of the ID are zero.
+    Zero,
+    /// The ID is the same as ours.
+    Own,
+    /// The ID was banned, or seen from a banned address.
+    Banned
+}
+
+/// Numbers of node IDs rejected, by reason.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IdRejections {
+    pub zero: usize,
+    pub own: usize,
+    pub banned: usize
+}
+
+/// Outcome of a request sent to another node.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Response,
+    Timeout,
+    Error(String)
+}
+
+/// Record of a completed request sent to another node.
+#[derive(Clone, Debug)]
+pub struct TransactionRecord<TId, TAddr> {
+    /// Name of the request method.
+    pub method: &'static str,
+    /// Node the request was sent to.
+    pub node: Node<TId, TAddr>,
+    /// When the request was sent.
+    pub sent_at: Instant,
+    /// When the response arrived or the request was given up.
+    pub completed_at: Instant,
+    pub outcome: Outcome
+}
+
+/// Event happening in the service.
+#[derive(Clone, Debug)]
+pub enum Event<TId, TAddr> {
+    /// New node was added to the node table.
+    NodeAdded(Node<TId, TAddr>),
+    /// Node was removed from the node table during clean up.
+    NodeRemoved(Node<TId, TAddr>),
+    /// Value with the given ID was stored on this node.
+    ValueStored(TId),
+    /// Queries from a source are dropped from now on, see
+    /// `Handler::should_answer`.
+    FloodDetected(FloodSource),
+    /// Address was banned or ID distrusted, see `Handler::is_trusted`.
+    AnomalyDetected(Anomaly<TId>),
+    /// Node ID was banned, see `Handler::ban_id`.
+    Banned(TId),
+    /// Our address as seen by other nodes has changed, see
+    /// `Service::set_external_address`.
+    ExternalAddressChanged(TAddr)
+}
+
 /// Handler - implementation of DHT requests.
-pub struct Handler<TId, TAddr, TNodeTable, TData>
+pub struct Handler<TId, TAddr, TNodeTable, TData, TStorage>
         where TId: GenericId,
               TNodeTable: GenericNodeTable<TId, TAddr>,
-              TData: Send + Sync + Clone {
-    _phantom: marker::PhantomData<TAddr>,
+              TData: Send + Sync + Clone,
+              TStorage: GenericStorage<TId, TData> {
+    _phantom: marker::PhantomData<(TAddr, TData)>,
     node_id: TId,
     table: Arc<RwLock<TNodeTable>>,
-    data: Arc<RwLock<HashMap<TId, TData>>>,
+    data: Arc<RwLock<TStorage>>,
     clean_needed: bool,
+    sample: Option<(Instant, Vec<TId>)>,
+    sample_interval: Duration,
+    metrics: Arc<dyn Metrics>,
+    subscribers: Vec<mpsc::Sender<Event<TId, TAddr>>>,
+    counters: RequestCounters,
+    clock: Arc<dyn Clock>,
+    indexer: Option<Arc<RwLock<Indexer<TId>>>>,
+    methods: HashMap<String, MethodHandler<TId, TAddr>>,
+    extension_handler: Option<ExtensionHandler<TId, TAddr>>,
+    store_verifier: Option<StoreVerifier<TId, TAddr, TData>>,
+    flood: Option<FloodDetector>,
+    anomaly: Option<AnomalyDetector<TId>>,
+    banned_ids: HashSet<TId>,
+    // Banned IDs, oldest first
+    ban_order: VecDeque<TId>,
+    id_rejections: IdRejections,
 }
 
 /// Protocol agnostic DHT service.
 ///
 /// Its type parameters are `TNodeTable` - the node table implementation
-/// (see e.g. `KNodeTable`) and `TData` - stored data type.
+/// (see e.g. `KNodeTable`), `TData` - stored data type and `TStorage` -
+/// the storage implementation (`MemoryStorage` by default).
 ///
 /// The service starts a network listening loop in a separate thread.
-pub struct Service<TId, TAddr, TNodeTable, TData>
+pub struct Service<TId, TAddr, TNodeTable, TData,
+                   TStorage = MemoryStorage<TId, TData>>
         where TId: GenericId,
               TNodeTable: GenericNodeTable<TId, TAddr>,
-              TData: Send + Sync + Clone {
-    handler: Handler<TId, TAddr, TNodeTable, TData>,
+              TData: Send + Sync + Clone,
+              TStorage: GenericStorage<TId, TData> {
+    handler: Handler<TId, TAddr, TNodeTable, TData, TStorage>,
     node_id: TId,
     table: Arc<RwLock<TNodeTable>>,
-    data: Arc<RwLock<HashMap<TId, TData>>>
+    data: Arc<RwLock<TStorage>>,
+    data_ttl: Duration,
+    rtt: Histogram,
+    // Response times of nodes with the time of the last update
+    node_rtt: HashMap<TId, (Histogram, Instant)>,
+    transactions: VecDeque<TransactionRecord<TId, TAddr>>,
+    transaction_log_size: usize,
+    transaction_counters: TransactionCounters,
+    external_address: Option<TAddr>,
+    bytes_received: u64,
+    bytes_sent: u64,
+    memory_budget: Option<usize>
 }
 
 
-impl<TId, TAddr, TNodeTable, TData> Service<TId, TAddr, TNodeTable, TData>
+impl MemoryReport {
+    /// Estimated number of bytes used by all parts.
+    pub fn total(&self) -> usize {
+        self.table.bytes + self.storage.bytes + self.rtt.bytes + self.transactions.bytes
+    }
+}
+
+impl<TId, TAddr, TNodeTable, TData, TStorage> Service<TId, TAddr, TNodeTable, TData, TStorage>
         where TId: GenericId,
-              TAddr: Send + Sync,
+              TAddr: Clone + Send + Sync,
               TNodeTable: GenericNodeTable<TId, TAddr>,
-              TData: Send + Sync + Clone {
+              TData: Send + Sync + Clone,
+              TStorage: GenericStorage<TId, TData> {
     /// Create a service with a random ID.
-    pub fn new(node_table: TNodeTable) -> Service<TId, TAddr, TNodeTable, TData> {
+    pub fn new(node_table: TNodeTable)
+            -> Service<TId, TAddr, TNodeTable, TData, TStorage>
+            where TStorage: Default {
         let node_id = node_table.random_id();
         Service::new_with_id(node_table, node_id)
     }
     /// Create a service with a given ID.
     pub fn new_with_id(node_table: TNodeTable, node_id: TId)
-            -> Service<TId, TAddr, TNodeTable, TData> {
+            -> Service<TId, TAddr, TNodeTable, TData, TStorage>
+            where TStorage: Default {
+        Service::new_with_storage(node_table, node_id, TStorage::default())
+    }
+    /// Create a service with a given ID and storage.
+    pub fn new_with_storage(node_table: TNodeTable, node_id: TId,
+                            storage: TStorage)
+            -> Service<TId, TAddr, TNodeTable, TData, TStorage> {
         let table = Arc::new(RwLock::new(node_table));
-        let data = Arc::new(RwLock::new(HashMap::new()));
+        let data = Arc::new(RwLock::new(storage));
         let handler = Handler {
             _phantom: marker::PhantomData,
             node_id: node_id.clone(),
             table: table.clone(),
             data: data.clone(),
-            clean_needed: false
+            clean_needed: false,
+            sample: None,
+            sample_interval: Duration::from_secs(SAMPLE_INTERVAL),
+            metrics: Arc::new(NoopMetrics),
+            subscribers: vec![],
+            counters: RequestCounters::default(),
+            clock: Arc::new(SystemClock),
+            indexer: None,
+            methods: HashMap::new(),
+            extension_handler: None,
+            store_verifier: None,
+            flood: None,
+            anomaly: None,
+            banned_ids: HashSet::new(),
+            ban_order: VecDeque::new(),
+            id_rejections: IdRejections::default()
         };
         Service {
-            handler: handler,
-            node_id: node_id,
-            table: table,
-            data: data
+            handler,
+            node_id,
+            table,
+            data,
+            data_ttl: Duration::from_secs(DATA_TTL),
+            rtt: Histogram::new(),
+            node_rtt: HashMap::new(),
+            transactions: VecDeque::new(),
+            transaction_log_size: 0,
+            transaction_counters: TransactionCounters::default(),
+            external_address: None,
+            bytes_received: 0,
+            bytes_sent: 0,
+            memory_budget: None
         }
     }
 
+    /// Get a mutable reference to the request handler.
+    ///
+    /// Protocol implementations pass incoming requests to it.
+    pub fn handler_mut(&mut self) -> &mut Handler<TId, TAddr, TNodeTable, TData, TStorage> {
+        &mut self.handler
+    }
+
     /// Get an immutable reference to the node table.
-    pub fn node_table(&self) -> RwLockReadGuard<TNodeTable> {
+    pub fn node_table(&self) -> RwLockReadGuard<'_, TNodeTable> {
         self.table.read().unwrap()
     }
     /// Get a mutable reference to the node table.
-    pub fn node_table_mut(&mut self) -> RwLockWriteGuard<TNodeTable> {
+    pub fn node_table_mut(&mut self) -> RwLockWriteGuard<'_, TNodeTable> {
         self.table.write().unwrap()
     }
     /// Get the current node ID.
@@ -99,15 +370,302 @@ impl<TId, TAddr, TNodeTable, TData> Service<TId, TAddr, TNodeTable, TData>
         &self.node_id
     }
     /// Get an immutable reference to the data.
-    pub fn stored_data(&self)
-            -> RwLockReadGuard<HashMap<TId, TData>> {
+    pub fn stored_data(&self) -> RwLockReadGuard<'_, TStorage> {
         self.data.read().unwrap()
     }
-    /// Get an immutable reference to the data.
-    pub fn stored_data_mut(&mut self)
-            -> RwLockWriteGuard<HashMap<TId, TData>> {
+    /// Get a mutable reference to the data.
+    pub fn stored_data_mut(&mut self) -> RwLockWriteGuard<'_, TStorage> {
         self.data.write().unwrap()
     }
+    /// Set how often the sample of stored IDs is refreshed.
+    pub fn set_sample_interval(&mut self, interval: Duration) {
+        self.handler.sample_interval = interval;
+        self.handler.sample = None;
+    }
+    /// Set the metrics recorder, `NoopMetrics` by default.
+    pub fn set_metrics(&mut self, metrics: Arc<dyn Metrics>) {
+        self.handler.metrics = metrics;
+    }
+    /// Get the clock used by the service.
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        self.handler.clock.clone()
+    }
+    /// Set the clock, `SystemClock` by default.
+    ///
+    /// The storage keeps its own clock, e.g. see `MemoryStorage::set_clock`.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.handler.clock = clock;
+        self.handler.sample = None;
+    }
+    /// Set the index of value IDs.
+    ///
+    /// IDs from find_value and store requests are recorded in the index,
+    /// and sample responses are taken from it instead of the storage.
+    pub fn set_indexer(&mut self, indexer: Arc<RwLock<Indexer<TId>>>) {
+        self.handler.indexer = Some(indexer);
+        self.handler.sample = None;
+    }
+    /// Subscribe to the service events.
+    ///
+    /// Events are sent until the receiver is dropped.
+    pub fn subscribe(&mut self) -> mpsc::Receiver<Event<TId, TAddr>> {
+        let (sender, receiver) = mpsc::channel();
+        self.handler.subscribers.push(sender);
+        receiver
+    }
+    /// Get our address as seen by other nodes, if known.
+    pub fn external_address(&self) -> Option<&TAddr> {
+        self.external_address.as_ref()
+    }
+    /// Set our address as seen by other nodes, e.g. once most responses
+    /// report the same one.
+    ///
+    /// Subscribers get `Event::ExternalAddressChanged` if it differs from
+    /// the previous one.
+    pub fn set_external_address(&mut self, address: TAddr)
+            where TAddr: PartialEq {
+        if self.external_address.as_ref() == Some(&address) {
+            return;
+        }
+        self.handler.emit(Event::ExternalAddressChanged(address.clone()));
+        self.external_address = Some(address);
+    }
+    /// Get how long the stored values are kept.
+    pub fn data_ttl(&self) -> Duration {
+        self.data_ttl
+    }
+    /// Set how long the stored values are kept.
+    ///
+    /// Values older than this are removed by `clean_up`.
+    pub fn set_data_ttl(&mut self, ttl: Duration) {
+        self.data_ttl = ttl;
+    }
+    /// Record time it took a node to respond to our request.
+    ///
+    /// Nodes not in the node table only count for the global histogram.
+    pub fn record_rtt(&mut self, id: &TId, rtt: Duration) {
+        self.handler.metrics.observe(metrics::RTT, rtt);
+        self.rtt.record(rtt);
+        if self.node_table().contains(id) {
+            let now = self.handler.clock.now();
+            let entry = self.node_rtt.entry(id.clone())
+                .or_insert_with(|| (Histogram::default(), now));
+            entry.0.record(rtt);
+            entry.1 = now;
+        }
+    }
+    /// Set how many completed transactions to keep, 0 (default) disables.
+    pub fn set_transaction_log_size(&mut self, size: usize) {
+        self.transaction_log_size = size;
+        while self.transactions.len() > size {
+            self.transactions.pop_front();
+        }
+    }
+    /// Record a completed request sent to another node.
+    ///
+    /// Response times of successful requests are also passed to `record_rtt`.
+    pub fn record_transaction(&mut self, record: TransactionRecord<TId, TAddr>) {
+        {
+            let counters = &mut self.transaction_counters;
+            *counters.requests.entry(record.method).or_insert(0) += 1;
+            let name = match record.outcome {
+                Outcome::Response => {
+                    counters.responses += 1;
+                    metrics::RESPONSES_RECEIVED
+                },
+                Outcome::Timeout => {
+                    counters.timeouts += 1;
+                    metrics::REQUEST_TIMEOUTS
+                },
+                Outcome::Error(_) => {
+                    counters.errors += 1;
+                    metrics::REQUEST_ERRORS
+                }
+            };
+            self.handler.metrics.increment(name, 1);
+        }
+        if record.outcome == Outcome::Response {
+            let rtt = record.completed_at.duration_since(record.sent_at);
+            self.record_rtt(&record.node.id, rtt);
+        }
+        if self.transaction_log_size == 0 {
+            return;
+        }
+        if self.transactions.len() == self.transaction_log_size {
+            self.transactions.pop_front();
+        }
+        self.transactions.push_back(record);
+    }
+    /// Get the recently completed transactions, oldest first.
+    pub fn transaction_log(&self) -> Vec<TransactionRecord<TId, TAddr>> {
+        self.transactions.iter().cloned().collect()
+    }
+    /// Get the histogram of response times of all nodes.
+    pub fn rtt_histogram(&self) -> &Histogram {
+        &self.rtt
+    }
+    /// Get the histogram of response times of a node.
+    ///
+    /// Only nodes still present in the node table are tracked.
+    pub fn node_rtt_histogram(&self, id: &TId) -> Option<&Histogram> {
+        self.node_rtt.get(id).map(|(histogram, _)| histogram)
+    }
+    /// Get when a node last responded to our request, see `record_rtt`.
+    ///
+    /// Only nodes still present in the node table are tracked.
+    pub fn node_last_response(&self, id: &TId) -> Option<Instant> {
+        self.node_rtt.get(id).map(|&(_, updated_at)| updated_at)
+    }
+    /// Get numbers of requests received since creation or the last reset.
+    pub fn request_counters(&self) -> RequestCounters {
+        self.handler.counters.clone()
+    }
+    /// Reset numbers of requests received.
+    pub fn reset_request_counters(&mut self) {
+        self.handler.counters = RequestCounters::default();
+    }
+    /// Record bytes received from and sent to the network.
+    pub fn record_traffic(&mut self, received: usize, sent: usize) {
+        self.bytes_received += received as u64;
+        self.bytes_sent += sent as u64;
+        self.handler.metrics.increment(metrics::BYTES_RECEIVED, received as u64);
+        self.handler.metrics.increment(metrics::BYTES_SENT, sent as u64);
+    }
+    /// Get a snapshot of the statistics, e.g. to display the health
+    /// of the DHT.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            received: self.request_counters(),
+            sent: self.transaction_counters.clone(),
+            table_size: self.node_table().len(),
+            stored_items: self.stored_data().stats().items,
+            bytes_received: self.bytes_received,
+            bytes_sent: self.bytes_sent
+        }
+    }
+    /// Get numbers of node IDs rejected since creation.
+    pub fn id_rejections(&self) -> IdRejections {
+        self.handler.id_rejections.clone()
+    }
+    /// Get a summary of the service state.
+    pub fn health(&self) -> HealthReport {
+        HealthReport {
+            table_size: self.node_table().len(),
+            clean_needed: self.clean_needed(),
+            storage: self.stored_data().stats()
+        }
+    }
+    /// Get the memory budget in bytes.
+    pub fn memory_budget(&self) -> Option<usize> {
+        self.memory_budget
+    }
+    /// Limit memory used by the node table, the stored values, response
+    /// time histograms and the transaction log, `None` (default) disables.
+    ///
+    /// The usage is estimated from the sizes of the entry types, heap data
+    /// owned by IDs and values is not counted. See `enforce_memory_budget`.
+    pub fn set_memory_budget(&mut self, budget: Option<usize>) {
+        self.memory_budget = budget;
+    }
+    /// Evict entries if the estimated memory usage exceeds the budget.
+    ///
+    /// Every part is shrunk by the same factor, so that the total usage
+    /// drops somewhat below the budget. The oldest transactions, values and
+    /// the least recently updated response time histograms are evicted
+    /// first, see `GenericStorage::evict_oldest`. Nodes are only evicted
+    /// when the node table gives them out with `pop_oldest`. Returns the
+    /// number of entries evicted.
+    ///
+    /// Called by `clean_up`.
+    pub fn enforce_memory_budget(&mut self) -> usize {
+        let budget = match self.memory_budget {
+            Some(budget) => budget,
+            None => return 0
+        };
+        let report = self.memory_report();
+        let total = report.total();
+        if total <= budget {
+            return 0;
+        }
+        let ratio = budget as f64 * BUDGET_TARGET / total as f64;
+        let target = |usage: MemoryUsage| (usage.entries as f64 * ratio) as usize;
+        let keep_nodes = target(report.table);
+        let keep_items = target(report.storage);
+        let keep_rtt = target(report.rtt);
+        let keep_transactions = target(report.transactions);
+        let mut evicted = 0;
+
+        {
+            let mut node_table = self.table.write().unwrap();
+            while node_table.len() > keep_nodes {
+                let oldest = node_table.pop_oldest();
+                if oldest.is_empty() {
+                    break;
+                }
+                for node in oldest {
+                    if node_table.len() < keep_nodes {
+                        node_table.update(&node);
+                    }
+                    else {
+                        evicted += 1;
+                        self.node_rtt.remove(&node.id);
+                        self.handler.emit(Event::NodeRemoved(node));
+                    }
+                }
+            }
+        }
+
+        {
+            let mut data = self.data.write().unwrap();
+            let excess = data.stats().items.saturating_sub(keep_items);
+            if excess > 0 {
+                evicted += data.evict_oldest(excess).count();
+            }
+        }
+
+        let excess = self.node_rtt.len().saturating_sub(keep_rtt);
+        if excess > 0 {
+            let mut updated: Vec<(Instant, TId)> = self.node_rtt.iter()
+                .map(|(id, &(_, updated_at))| (updated_at, id.clone()))
+                .collect();
+            updated.sort_by_key(|&(updated_at, _)| updated_at);
+            for (_, id) in updated.into_iter().take(excess) {
+                self.node_rtt.remove(&id);
+                evicted += 1;
+            }
+        }
+
+        while self.transactions.len() > keep_transactions {
+            self.transactions.pop_front();
+            evicted += 1;
+        }
+
+        debug!("Evicted {} entries to fit into {} bytes", evicted, budget);
+        self.handler.metrics.increment(metrics::MEMORY_EVICTED, evicted as u64);
+        evicted
+    }
+    /// Estimate memory used by the node table, the stored values, the
+    /// response time histograms and the transaction log.
+    ///
+    /// Every entry is counted as the size of its type plus the estimated
+    /// overhead of the collection holding it. Heap data owned by IDs,
+    /// addresses and values is not counted.
+    pub fn memory_report(&self) -> MemoryReport {
+        let usage = |entries: usize, size: usize| MemoryUsage {
+            entries,
+            bytes: entries * size
+        };
+        MemoryReport {
+            table: usage(self.node_table().len(),
+                         mem::size_of::<Node<TId, TAddr>>() + ENTRY_OVERHEAD),
+            storage: usage(self.stored_data().stats().items,
+                           mem::size_of::<(TId, TData, Instant)>() + ENTRY_OVERHEAD),
+            rtt: usage(self.node_rtt.len(),
+                       mem::size_of::<(TId, Histogram, Instant)>() + ENTRY_OVERHEAD),
+            transactions: usage(self.transactions.len(),
+                                mem::size_of::<TransactionRecord<TId, TAddr>>())
+        }
+    }
     /// Check if some buckets are full already.
     pub fn clean_needed(&self) -> bool {
         self.handler.clean_needed
@@ -115,72 +673,389 @@ impl<TId, TAddr, TNodeTable, TData> Service<TId, TAddr, TNodeTable, TData>
 
     /// Try to clean up the table by checking the oldest records.
     ///
+    /// Also removes stored values older than the data TTL and enforces
+    /// the memory budget.
+    ///
     /// Should be called periodically, especially when clean_needed is true.
     pub fn clean_up<TCheck>(&mut self, mut check: TCheck)
             where TCheck: FnMut(&Node<TId, TAddr>) -> bool {
         {
-            let mut node_table = self.node_table_mut();
+            let mut node_table = self.table.write().unwrap();
             let oldest = node_table.pop_oldest();
+            let mut removed = 0;
             for node in oldest {
                 if check(&node) {
                     node_table.update(&node);
                 }
+                else {
+                    debug!("Removing unresponsive node {:?}", node.id);
+                    removed += 1;
+                    self.node_rtt.remove(&node.id);
+                    self.handler.emit(Event::NodeRemoved(node));
+                }
             }
+            self.handler.metrics.increment(metrics::TABLE_NODES_REMOVED, removed);
         }
         self.handler.clean_needed = false;
+
+        let ttl = self.data_ttl;
+        let mut data = self.data.write().unwrap();
+        let expired = data.expire_iter(ttl).count();
+        if expired > 0 {
+            debug!("Removed {} values older than {:?}", expired, ttl);
+        }
+        self.handler.metrics.increment(metrics::STORAGE_EXPIRED, expired as u64);
+        drop(data);
+
+        self.enforce_memory_budget();
+        let items = self.stored_data().stats().items;
+        self.handler.metrics.gauge(metrics::STORAGE_ITEMS, items as f64);
+        let nodes = self.node_table().len();
+        self.handler.metrics.gauge(metrics::TABLE_NODES, nodes as f64);
     }
 }
 
-impl<TId, TAddr, TNodeTable, TData> Handler<TId, TAddr, TNodeTable, TData>
+impl<TId, TAddr, TNodeTable, TData, TStorage> Handler<TId, TAddr, TNodeTable, TData, TStorage>
         where TId: GenericId,
+              TAddr: Clone,
               TNodeTable: GenericNodeTable<TId, TAddr>,
-              TData: Send + Sync + Clone {
+              TData: Send + Sync + Clone,
+              TStorage: GenericStorage<TId, TData> {
     /// Process the ping request.
     ///
     /// Essentially remembers the incoming node and returns true.
     pub fn on_ping(&mut self, sender: &Node<TId, TAddr>) -> bool {
+        self.metrics.increment(metrics::PING_REQUESTS, 1);
+        self.counters.ping += 1;
+        debug!("Ping request from {:?}", sender.id);
         self.update(sender);
         true
     }
     /// Process the find request.
     pub fn on_find_node(&mut self, sender: &Node<TId, TAddr>, id: &TId) -> Vec<Node<TId, TAddr>> {
-        let res = self.table.read().unwrap().find(&id, MAX_NODE_COUNT);
+        self.metrics.increment(metrics::FIND_NODE_REQUESTS, 1);
+        self.counters.find_node += 1;
+        let res = self.table.read().unwrap().find(id, MAX_NODE_COUNT);
+        debug!("Find node request for {:?} from {:?}, found {} nodes",
+               id, sender.id, res.len());
         self.update(sender);
         res
     }
     /// Find a value or the closes nodes.
     pub fn on_find_value(&mut self, sender: &Node<TId, TAddr>, id: &TId)
             -> FindResult<TId, TAddr, TData> {
+        self.metrics.increment(metrics::FIND_VALUE_REQUESTS, 1);
+        self.counters.find_value += 1;
+        debug!("Find value request for {:?} from {:?}", id, sender.id);
         self.update(sender);
+        self.index(id);
         let data = self.data.read().unwrap();
         let table = self.table.read().unwrap();
-        let res = match data.get(&id) {
-            Some(value) => FindResult::Value(value.clone()),
-            None => FindResult::ClosestNodes(table.find(&id, MAX_NODE_COUNT))
+        match data.get(id) {
+            Some(value) => FindResult::Value(value),
+            None => FindResult::ClosestNodes(table.find(id, MAX_NODE_COUNT))
+        }
+    }
+
+    /// Store a value on this node.
+    ///
+    /// Returns false if the storage rejected the value.
+    pub fn on_store(&mut self, sender: &Node<TId, TAddr>, id: &TId, value: TData) -> bool {
+        self.metrics.increment(metrics::STORE_REQUESTS, 1);
+        self.counters.store += 1;
+        debug!("Store request for {:?} from {:?}", id, sender.id);
+        if self.check_id(&sender.id).is_err() {
+            self.counters.store_rejected += 1;
+            return false;
+        }
+        self.update(sender);
+        self.index(id);
+        let verified = match self.store_verifier {
+            Some(ref mut verifier) => verifier(sender, id, &value),
+            None => true
         };
-        res
+        if !verified {
+            debug!("Not storing unverified value {:?} from {:?}", id, sender.id);
+            self.metrics.increment(metrics::STORE_UNVERIFIED, 1);
+            self.counters.store_rejected += 1;
+            return false;
+        }
+        let stored = self.data.write().unwrap().put(id.clone(), value);
+        if stored {
+            self.emit(Event::ValueStored(id.clone()));
+        }
+        else {
+            self.counters.store_rejected += 1;
+        }
+        stored
+    }
+
+    /// Set the verifier of values from store requests.
+    ///
+    /// Rejected values count as `store_rejected` in the request counters.
+    pub fn set_store_verifier(&mut self, verifier: StoreVerifier<TId, TAddr, TData>) {
+        self.store_verifier = Some(verifier);
+    }
+
+    /// Register a handler for a custom method.
+    ///
+    /// Replaces the handler previously registered under the same name.
+    pub fn register_method(&mut self, name: &str, handler: MethodHandler<TId, TAddr>) {
+        self.methods.insert(name.to_string(), handler);
+    }
+
+    /// Process a request for a custom method.
+    ///
+    /// Returns `None` if no handler is registered for the method, protocols
+    /// should respond with their "method unknown" error then.
+    pub fn on_custom(&mut self, sender: &Node<TId, TAddr>, method: &str, payload: &[u8])
+            -> Option<Vec<u8>> {
+        debug!("Custom request {} from {:?}", method, sender.id);
+        self.update(sender);
+        match self.methods.get_mut(method) {
+            Some(handler) => {
+                self.counters.custom += 1;
+                Some(handler(sender, payload))
+            },
+            None => {
+                debug!("Unknown method {} requested by {:?}", method, sender.id);
+                self.counters.unknown_method += 1;
+                None
+            }
+        }
+    }
+
+    /// Set the handler of extension blobs attached to requests.
+    pub fn set_extension_handler(&mut self, handler: ExtensionHandler<TId, TAddr>) {
+        self.extension_handler = Some(handler);
+    }
+
+    /// Process an extension blob attached to a request.
+    ///
+    /// Protocols call it for every request with a blob, in addition to
+    /// the method handler, and attach the result to the response. Blobs
+    /// larger than `protocol::MAX_EXTENSION_SIZE` are ignored both ways.
+    pub fn on_extension(&mut self, sender: &Node<TId, TAddr>, blob: &[u8]) -> Option<Vec<u8>> {
+        if blob.len() > MAX_EXTENSION_SIZE {
+            debug!("Ignoring extension of {} bytes from {:?}", blob.len(), sender.id);
+            return None;
+        }
+        let handler = self.extension_handler.as_mut()?;
+        match handler(sender, blob) {
+            Some(ref res) if res.len() > MAX_EXTENSION_SIZE => {
+                warn!("Not sending extension of {} bytes to {:?}, at most {} allowed",
+                      res.len(), sender.id, MAX_EXTENSION_SIZE);
+                None
+            },
+            res => res
+        }
+    }
+
+    /// Return a random sample of the stored IDs and the closest nodes.
+    ///
+    /// The sample is cached and only refreshed once per sample interval.
+    pub fn on_sample(&mut self, sender: &Node<TId, TAddr>, target: &TId)
+            -> SampleResult<TId, TAddr> {
+        self.metrics.increment(metrics::SAMPLE_REQUESTS, 1);
+        self.counters.sample += 1;
+        debug!("Sample request for {:?} from {:?}", target, sender.id);
+        self.update(sender);
+        let data = self.data.read().unwrap();
+        let now = self.clock.now();
+        let expired = match self.sample {
+            Some((created, _)) => now.duration_since(created) >= self.sample_interval,
+            None => true
+        };
+        let indexer = self.indexer.as_ref().map(|indexer| indexer.read().unwrap());
+        if expired {
+            debug!("Refreshing sample of stored IDs");
+            let ids = match indexer {
+                Some(ref indexer) => indexer.sample(MAX_SAMPLE_COUNT),
+                None => data.sample(MAX_SAMPLE_COUNT)
+            };
+            self.sample = Some((now, ids));
+        }
+        let (created, ref ids) = *self.sample.as_ref().unwrap();
+        SampleResult {
+            ids: ids.clone(),
+            num: indexer.map_or_else(|| data.stats().items, |indexer| indexer.len()),
+            interval: self.sample_interval - now.duration_since(created),
+            nodes: self.table.read().unwrap().find(target, MAX_NODE_COUNT)
+        }
+    }
+
+    fn index(&self, id: &TId) {
+        if let Some(ref indexer) = self.indexer {
+            indexer.write().unwrap().record(id.clone());
+        }
+    }
+
+    /// Check that a node ID is not zero, not ours and not banned.
+    ///
+    /// Done for all senders of requests. Protocol implementations should
+    /// also check responders and nodes returned in responses, e.g. with
+    /// `sanitize`. Rejections are counted, see `Service::id_rejections`.
+    pub fn check_id(&mut self, id: &TId) -> Result<(), IdRejection> {
+        let (reason, metric) = if id.is_zero() {
+            self.id_rejections.zero += 1;
+            (IdRejection::Zero, metrics::ZERO_IDS_REJECTED)
+        }
+        else if *id == self.node_id {
+            self.id_rejections.own += 1;
+            (IdRejection::Own, metrics::OWN_IDS_REJECTED)
+        }
+        else if self.banned_ids.contains(id) {
+            self.id_rejections.banned += 1;
+            (IdRejection::Banned, metrics::BANNED_IDS_REJECTED)
+        }
+        else {
+            return Ok(());
+        };
+        debug!("Rejecting node ID {:?}: {:?}", id, reason);
+        self.metrics.increment(metric, 1);
+        Err(reason)
+    }
+
+    /// Remove nodes with rejected IDs, e.g. from a find response.
+    pub fn sanitize(&mut self, nodes: &mut Vec<Node<TId, TAddr>>) {
+        nodes.retain(|node| self.check_id(&node.id).is_ok());
+    }
+
+    /// Ban a node ID, it is rejected by `check_id` from now on.
+    ///
+    /// The node is removed from the node table. At most 10000 IDs are
+    /// banned at a time, the oldest ban is lifted to make room for a new
+    /// one. Subscribers get `Event::Banned` if the ID was not banned before.
+    pub fn ban_id(&mut self, id: TId) {
+        if self.banned_ids.contains(&id) {
+            return;
+        }
+        if self.banned_ids.len() >= MAX_BANNED_IDS {
+            if let Some(oldest) = self.ban_order.pop_front() {
+                debug!("Lifting the oldest ban of {:?}", oldest);
+                self.banned_ids.remove(&oldest);
+            }
+        }
+        self.banned_ids.insert(id.clone());
+        self.ban_order.push_back(id.clone());
+        if self.table.write().unwrap().remove(&id).is_some() {
+            debug!("Removed banned node {:?} from the table", id);
+        }
+        self.emit(Event::Banned(id));
     }
 
     fn update(&mut self, node: &Node<TId, TAddr>) {
-        if node.id == self.node_id {
+        if self.check_id(&node.id).is_err() {
             return
         }
 
-        if ! self.table.write().unwrap().update(&node) {
+        let (added, updated) = {
+            let mut table = self.table.write().unwrap();
+            // Tables do not evict nodes on update, so a new node grows them
+            let before = table.len();
+            let updated = table.update(node);
+            (table.len() > before, updated)
+        };
+        if ! updated {
+            self.metrics.increment(metrics::TABLE_UPDATES_REJECTED, 1);
+            debug!("No space for node {:?}, clean up needed", node.id);
             self.clean_needed = true;
         }
+        else if added && ! self.subscribers.is_empty() {
+            self.emit(Event::NodeAdded(node.clone()));
+        }
+    }
+
+    fn emit(&mut self, event: Event<TId, TAddr>) {
+        self.subscribers.retain(|s| s.send(event.clone()).is_ok());
+    }
+}
+
+impl<TId, TNodeTable, TData, TStorage> Handler<TId, SocketAddr, TNodeTable, TData, TStorage>
+        where TId: GenericId,
+              TNodeTable: GenericNodeTable<TId, SocketAddr>,
+              TData: Send + Sync + Clone,
+              TStorage: GenericStorage<TId, TData> {
+    /// Set the detector of query floods.
+    pub fn set_flood_detector(&mut self, detector: FloodDetector) {
+        self.flood = Some(detector);
+    }
+
+    /// Set the detector of nodes switching IDs or addresses.
+    pub fn set_anomaly_detector(&mut self, detector: AnomalyDetector<TId>) {
+        self.anomaly = Some(detector);
+    }
+
+    /// Get the detector of nodes switching IDs or addresses, e.g. to ban
+    /// an address manually.
+    pub fn anomaly_detector_mut(&mut self) -> Option<&mut AnomalyDetector<TId>> {
+        self.anomaly.as_mut()
+    }
+
+    /// Record the sender of a request or a response and check whether it
+    /// is trusted.
+    ///
+    /// Protocol implementations should call it for every packet and ignore
+    /// packets from untrusted senders. IDs of untrusted senders are banned,
+    /// see `check_id`. Always true if no anomaly detector is set.
+    pub fn is_trusted(&mut self, sender: &Node<TId, SocketAddr>) -> bool {
+        let (anomaly, trusted) = match self.anomaly {
+            Some(ref mut detector) => (detector.observe(sender), detector.is_trusted(sender)),
+            None => return true
+        };
+        if let Some(anomaly) = anomaly {
+            self.metrics.increment(metrics::ANOMALIES_DETECTED, 1);
+            self.emit(Event::AnomalyDetected(anomaly));
+        }
+        if !trusted {
+            self.metrics.increment(metrics::UNTRUSTED_DROPPED, 1);
+            self.ban_id(sender.id.clone());
+        }
+        trusted
+    }
+
+    /// Check whether to answer a find_node, find_value or sample query.
+    ///
+    /// Protocol implementations should call it before passing a query to
+    /// the handler, and drop the query without a response if it returns
+    /// false. Always true if no flood detector is set.
+    pub fn should_answer(&mut self, sender: &Node<TId, SocketAddr>) -> bool {
+        let check = match self.flood {
+            Some(ref mut detector) => detector.record(&sender.address),
+            None => return true
+        };
+        match check {
+            FloodCheck::Answer => true,
+            FloodCheck::Drop => {
+                self.metrics.increment(metrics::FLOOD_DROPPED, 1);
+                false
+            },
+            FloodCheck::Engage(source) => {
+                self.metrics.increment(metrics::FLOOD_DROPPED, 1);
+                self.emit(Event::FloodDetected(source));
+                false
+            }
+        }
     }
 }
 
 
 #[cfg(test)]
 pub mod test {
+    use std::collections::HashMap;
     use std::net;
-    use super::super::{GenericNodeTable, Node};
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::time::{Duration, Instant};
+    use super::super::{GenericNodeTable, GenericStorage, Indexer, MemoryStorage, Node};
+    use super::super::metrics::{self, Metrics};
+    use super::super::anomaly::{Anomaly, AnomalyDetector};
+    use super::super::flood::{FloodDetector, FloodSource};
+    use super::super::mock::{MockClock, MockNodeTable};
     use super::super::utils::test;
     type TestsIdType = test::IdType;
 
-    use super::{FindResult, Service};
+    use super::{Event, FindResult, IdRejection, IdRejections, Outcome, RequestCounters,
+                Service, Stats, TransactionRecord};
 
 
     struct DummyNodeTable {
@@ -227,6 +1102,32 @@ pub mod test {
             self.node = None;
             result
         }
+
+        fn len(&self) -> usize {
+            if self.node.is_some() { 1 } else { 0 }
+        }
+
+        fn remove(&mut self, id: &TestsIdType) -> Option<Node<TestsIdType, net::SocketAddr>> {
+            if self.node.as_ref().is_some_and(|node| node.id == *id) {
+                self.node.take()
+            }
+            else {
+                None
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct DummyMetrics {
+        pub counters: Mutex<HashMap<&'static str, u64>>
+    }
+
+    impl Metrics for DummyMetrics {
+        fn increment(&self, name: &'static str, value: u64) {
+            *self.counters.lock().unwrap().entry(name).or_insert(0) += value;
+        }
+        fn gauge(&self, _name: &'static str, _value: f64) { }
+        fn observe(&self, _name: &'static str, _value: Duration) { }
     }
 
     #[test]
@@ -251,7 +1152,7 @@ pub mod test {
         assert!(svc.handler.on_find_node(&node, &node.id).is_empty());
         let result = svc.handler.on_find_node(&node, &node.id);
         assert_eq!(1, result.len());
-        assert_eq!(test::make_id(43), result.get(0).unwrap().id)
+        assert_eq!(test::make_id(43), result[0].id)
     }
 
     #[test]
@@ -271,7 +1172,7 @@ pub mod test {
 
         let mut result = svc.handler.on_find_node(&node, &node.id);
         assert_eq!(1, result.len());
-        assert_eq!(test::make_id(43), result.get(0).unwrap().id);
+        assert_eq!(test::make_id(43), result[0].id);
 
         let mut flag = false;
         svc.clean_up(|node| {
@@ -284,7 +1185,7 @@ pub mod test {
 
         result = svc.handler.on_find_node(&node, &node.id);
         assert_eq!(1, result.len());
-        assert_eq!(test::make_id(43), result.get(0).unwrap().id);
+        assert_eq!(test::make_id(43), result[0].id);
 
         flag = false;
         svc.clean_up(|node| {
@@ -307,7 +1208,7 @@ pub mod test {
         let id2: TestsIdType = test::make_id(43);
 
         svc.handler.on_ping(&node);
-        svc.stored_data_mut().insert(id1.clone(), "foobar".to_string());
+        svc.stored_data_mut().put(id1.clone(), "foobar".to_string());
 
         {
             let res1 = svc.handler.on_find_value(&node, &id1);
@@ -325,4 +1226,558 @@ pub mod test {
             }
         }
     }
+
+    #[test]
+    fn test_sample() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        let node = test::new_node(test::make_id(43));
+
+        let res = svc.handler.on_sample(&node, &node.id);
+        assert!(res.ids.is_empty());
+        assert_eq!(0, res.num);
+        assert!(res.interval <= Duration::from_secs(300));
+        assert_eq!(1, res.nodes.len());
+
+        // The sample is cached until the interval passes
+        svc.stored_data_mut().put(test::make_id(44), "foobar".to_string());
+        assert!(svc.handler.on_sample(&node, &node.id).ids.is_empty());
+
+        svc.set_sample_interval(Duration::from_secs(0));
+        let res = svc.handler.on_sample(&node, &node.id);
+        assert_eq!(vec![test::make_id(44)], res.ids);
+        assert_eq!(1, res.num);
+        assert_eq!(Duration::from_secs(0), res.interval);
+    }
+
+    #[test]
+    fn test_sample_indexer() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        let indexer = Arc::new(RwLock::new(Indexer::new()));
+        indexer.write().unwrap().record(test::make_id(45));
+        svc.set_indexer(indexer.clone());
+        let node = test::new_node(test::make_id(43));
+
+        svc.handler.on_store(&node, &test::make_id(44), "foobar".to_string());
+        svc.handler.on_find_value(&node, &test::make_id(46));
+        assert_eq!(3, indexer.read().unwrap().len());
+        let mut res = svc.handler.on_sample(&node, &node.id);
+        res.ids.sort();
+        assert_eq!(vec![test::make_id(44), test::make_id(45), test::make_id(46)], res.ids);
+        assert_eq!(3, res.num);
+    }
+
+    #[test]
+    fn test_sample_clock() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        let clock = MockClock::new();
+        svc.set_clock(Arc::new(clock.clone()));
+        let node = test::new_node(test::make_id(43));
+
+        assert!(svc.handler.on_sample(&node, &node.id).ids.is_empty());
+        svc.stored_data_mut().put(test::make_id(44), "foobar".to_string());
+        clock.advance(Duration::from_secs(200));
+        let res = svc.handler.on_sample(&node, &node.id);
+        assert!(res.ids.is_empty());
+        assert_eq!(Duration::from_secs(100), res.interval);
+
+        clock.advance(Duration::from_secs(100));
+        assert_eq!(vec![test::make_id(44)], svc.handler.on_sample(&node, &node.id).ids);
+    }
+
+    #[test]
+    fn test_clean_up_expires_data() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        assert_eq!(Duration::from_secs(7200), svc.data_ttl());
+
+        svc.stored_data_mut().put(test::make_id(44), "foobar".to_string());
+        svc.clean_up(|_| true);
+        assert!(svc.stored_data().get(&test::make_id(44)).is_some());
+
+        svc.set_data_ttl(Duration::from_secs(0));
+        svc.clean_up(|_| true);
+        assert!(svc.stored_data().get(&test::make_id(44)).is_none());
+    }
+
+    #[test]
+    fn test_metrics() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        let m = Arc::new(DummyMetrics::default());
+        svc.set_metrics(m.clone());
+
+        svc.handler.on_ping(&test::new_node(test::make_id(43)));
+        svc.handler.on_ping(&test::new_node(test::make_id(44)));
+        svc.handler.on_find_node(&test::new_node(test::make_id(43)), &test::make_id(43));
+        svc.clean_up(|_| false);
+        let sent_at = Instant::now();
+        svc.record_transaction(TransactionRecord {
+            method: "ping",
+            node: test::new_node(test::make_id(43)),
+            sent_at,
+            completed_at: sent_at,
+            outcome: Outcome::Timeout
+        });
+        svc.record_traffic(100, 60);
+
+        let counters = m.counters.lock().unwrap();
+        assert_eq!(2, counters[metrics::PING_REQUESTS]);
+        assert_eq!(1, counters[metrics::FIND_NODE_REQUESTS]);
+        assert_eq!(2, counters[metrics::TABLE_UPDATES_REJECTED]);
+        assert_eq!(1, counters[metrics::TABLE_NODES_REMOVED]);
+        assert_eq!(1, counters[metrics::REQUEST_TIMEOUTS]);
+        assert!(!counters.contains_key(metrics::RESPONSES_RECEIVED));
+        assert_eq!((100, 60), (counters[metrics::BYTES_RECEIVED], counters[metrics::BYTES_SENT]));
+    }
+
+    #[test]
+    fn test_events() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        let events = svc.subscribe();
+        let node = test::new_node(test::make_id(43));
+
+        svc.handler.on_ping(&node);
+        svc.handler.on_ping(&node);
+        assert!(svc.handler.on_store(&node, &test::make_id(44), "foobar".to_string()));
+        svc.clean_up(|_| false);
+
+        let received: Vec<_> = events.try_iter().collect();
+        assert_eq!(3, received.len());
+        match received[0] {
+            Event::NodeAdded(ref n) => assert_eq!(test::make_id(43), n.id),
+            ref other => panic!("wrong event {:?}", other)
+        }
+        match received[1] {
+            Event::ValueStored(ref id) => assert_eq!(test::make_id(44), *id),
+            ref other => panic!("wrong event {:?}", other)
+        }
+        match received[2] {
+            Event::NodeRemoved(ref n) => assert_eq!(test::make_id(43), n.id),
+            ref other => panic!("wrong event {:?}", other)
+        }
+
+        drop(events);
+        svc.handler.on_ping(&test::new_node(test::make_id(45)));
+        assert!(svc.handler.subscribers.is_empty());
+    }
+
+    #[test]
+    fn test_external_address() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        let events = svc.subscribe();
+        let first: net::SocketAddr = "192.0.2.1:8008".parse().unwrap();
+        let second: net::SocketAddr = "192.0.2.2:8008".parse().unwrap();
+        assert_eq!(None, svc.external_address());
+
+        svc.set_external_address(first);
+        svc.set_external_address(first);
+        svc.set_external_address(second);
+        assert_eq!(Some(&second), svc.external_address());
+        let received: Vec<_> = events.try_iter().collect();
+        assert_eq!(2, received.len());
+        match received[1] {
+            Event::ExternalAddressChanged(address) => assert_eq!(second, address),
+            ref other => panic!("wrong event {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_health() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        let health = svc.health();
+        assert_eq!(0, health.table_size);
+        assert!(!health.clean_needed);
+        assert_eq!(0, health.storage.items);
+
+        svc.handler.on_ping(&test::new_node(test::make_id(43)));
+        svc.handler.on_ping(&test::new_node(test::make_id(44)));
+        svc.stored_data_mut().put(test::make_id(45), "foobar".to_string());
+        let health = svc.health();
+        assert_eq!(1, health.table_size);
+        assert!(health.clean_needed);
+        assert_eq!(1, health.storage.items);
+    }
+
+    #[test]
+    fn test_request_counters() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        let node = test::new_node(test::make_id(43));
+        assert_eq!(RequestCounters::default(), svc.request_counters());
+
+        svc.handler.on_ping(&node);
+        svc.handler.on_find_node(&node, &node.id);
+        svc.handler.on_find_value(&node, &node.id);
+        svc.handler.on_store(&node, &node.id, "foobar".to_string());
+        let counters = svc.request_counters();
+        assert_eq!(1, counters.ping);
+        assert_eq!(1, counters.find_node);
+        assert_eq!(1, counters.find_value);
+        assert_eq!(1, counters.store);
+        assert_eq!(0, counters.store_rejected);
+        assert_eq!(0, counters.sample);
+
+        svc.reset_request_counters();
+        assert_eq!(RequestCounters::default(), svc.request_counters());
+    }
+
+    #[test]
+    fn test_custom_method() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        let node = test::new_node(test::make_id(43));
+        svc.handler_mut().register_method("echo", Box::new(|sender, payload| {
+            let mut res = sender.id.clone();
+            res.extend_from_slice(payload);
+            res
+        }));
+
+        assert_eq!(Some(vec![43, 1, 2]), svc.handler.on_custom(&node, "echo", &[1, 2]));
+        assert_eq!(None, svc.handler.on_custom(&node, "missing", &[]));
+        let counters = svc.request_counters();
+        assert_eq!(1, counters.custom);
+        assert_eq!(1, counters.unknown_method);
+    }
+
+    #[test]
+    fn test_extension() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        let node = test::new_node(test::make_id(43));
+        assert_eq!(None, svc.handler.on_extension(&node, b"hi"));
+
+        svc.handler_mut().set_extension_handler(Box::new(|_, blob| match blob {
+            b"big" => Some(vec![0; 1000]),
+            b"" => None,
+            _ => Some(blob.iter().rev().cloned().collect())
+        }));
+        assert_eq!(Some(b"ih".to_vec()), svc.handler.on_extension(&node, b"hi"));
+        assert_eq!(None, svc.handler.on_extension(&node, b""));
+        assert_eq!(None, svc.handler.on_extension(&node, b"big"));
+        assert_eq!(None, svc.handler.on_extension(&node, &[1; 1000]));
+    }
+
+    #[test]
+    fn test_rtt() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        let node = test::new_node(test::make_id(43));
+        svc.handler.on_ping(&node);
+
+        svc.record_rtt(&node.id, Duration::from_millis(10));
+        svc.record_rtt(&node.id, Duration::from_millis(30));
+        svc.record_rtt(&test::make_id(44), Duration::from_millis(20));
+        assert_eq!(3, svc.rtt_histogram().count());
+        assert_eq!(Some(Duration::from_millis(20)), svc.rtt_histogram().mean());
+        assert_eq!(2, svc.node_rtt_histogram(&node.id).unwrap().count());
+        // Unknown nodes do not get histograms
+        assert!(svc.node_rtt_histogram(&test::make_id(44)).is_none());
+        assert_eq!(1, svc.node_rtt.len());
+
+        svc.clean_up(|_| false);
+        assert!(svc.node_rtt_histogram(&node.id).is_none());
+        assert_eq!(3, svc.rtt_histogram().count());
+    }
+
+    #[test]
+    fn test_transaction_log() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        let sent_at = Instant::now();
+        let record = |outcome| TransactionRecord {
+            method: "ping",
+            node: test::new_node(test::make_id(43)),
+            sent_at,
+            completed_at: sent_at + Duration::from_millis(10),
+            outcome
+        };
+
+        svc.record_transaction(record(Outcome::Response));
+        assert!(svc.transaction_log().is_empty());
+        assert_eq!(1, svc.rtt_histogram().count());
+
+        svc.set_transaction_log_size(2);
+        svc.record_transaction(record(Outcome::Timeout));
+        svc.record_transaction(record(Outcome::Error("oops".to_string())));
+        svc.record_transaction(record(Outcome::Response));
+        let log = svc.transaction_log();
+        assert_eq!(2, log.len());
+        assert_eq!(Outcome::Error("oops".to_string()), log[0].outcome);
+        assert_eq!(Outcome::Response, log[1].outcome);
+        assert_eq!(2, svc.rtt_histogram().count());
+
+        svc.set_transaction_log_size(1);
+        assert_eq!(Outcome::Response, svc.transaction_log()[0].outcome);
+    }
+
+    #[test]
+    fn test_stats() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        let sent_at = Instant::now();
+        let record = |method, outcome| TransactionRecord {
+            method,
+            node: test::new_node(test::make_id(43)),
+            sent_at,
+            completed_at: sent_at + Duration::from_millis(10),
+            outcome
+        };
+        assert_eq!(Stats::default(), svc.stats());
+
+        svc.record_transaction(record("ping", Outcome::Response));
+        svc.record_transaction(record("ping", Outcome::Timeout));
+        svc.record_transaction(record("find_node", Outcome::Error("oops".to_string())));
+        svc.handler.on_ping(&test::new_node(test::make_id(44)));
+        svc.record_traffic(100, 60);
+        svc.record_traffic(20, 0);
+
+        let stats = svc.stats();
+        assert_eq!(Some(&2), stats.sent.requests.get("ping"));
+        assert_eq!(Some(&1), stats.sent.requests.get("find_node"));
+        assert_eq!((1, 1, 1), (stats.sent.responses, stats.sent.timeouts, stats.sent.errors));
+        assert_eq!(1, stats.received.ping);
+        assert_eq!((120, 60), (stats.bytes_received, stats.bytes_sent));
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let clock = MockClock::new();
+        let mut storage = MemoryStorage::new();
+        storage.set_clock(Arc::new(clock.clone()));
+        let mut svc: Service<TestsIdType, net::SocketAddr,
+                             MockNodeTable<TestsIdType, net::SocketAddr>, String> =
+            Service::new_with_storage(MockNodeTable::new(test::make_id(0)),
+                                      test::make_id(0), storage);
+        let nodes: Vec<_> = (1..5).map(|i| test::new_node(test::make_id(i))).collect();
+        for node in &nodes {
+            svc.node_table_mut().update(node);
+        }
+        svc.node_table_mut().set_oldest(nodes[..2].to_vec());
+        svc.set_transaction_log_size(100);
+        svc.set_clock(Arc::new(clock.clone()));
+        let events = svc.subscribe();
+        for i in 0..100 {
+            svc.stored_data_mut().put(test::make_id(i), "foobar".to_string());
+            svc.record_rtt(&test::make_id(i), Duration::from_millis(10));
+            let sent_at = Instant::now();
+            svc.record_transaction(TransactionRecord {
+                method: "ping",
+                node: test::new_node(test::make_id(i)),
+                sent_at,
+                completed_at: sent_at,
+                outcome: Outcome::Timeout
+            });
+            clock.advance(Duration::from_secs(60));
+        }
+        assert_eq!(0, svc.enforce_memory_budget());
+
+        let report = svc.memory_report();
+        assert_eq!(4, report.table.entries);
+        assert_eq!(100, report.storage.entries);
+        let usage = report.total();
+        svc.set_memory_budget(Some(usage / 2));
+        // Only the nodes in the table have response time histograms
+        assert_eq!(4, svc.node_rtt.len());
+        assert_eq!(2 + 55 + 1 + 55, svc.enforce_memory_budget());
+        assert!(svc.memory_report().total() <= usage / 2);
+        assert_eq!(2, svc.node_table().len());
+        assert!(matches!(events.try_recv(), Ok(Event::NodeRemoved(_))));
+        assert_eq!(45, svc.stored_data().stats().items);
+        assert!(svc.stored_data().get(&test::make_id(55)).is_some());
+        assert!(svc.stored_data().get(&test::make_id(54)).is_none());
+        // The histogram of node 4 was updated last
+        assert_eq!(1, svc.node_rtt.len());
+        assert!(svc.node_rtt_histogram(&test::make_id(4)).is_some());
+        let log = svc.transaction_log();
+        assert_eq!(45, log.len());
+        assert_eq!(test::make_id(99), log[44].node.id);
+    }
+
+    #[test]
+    fn test_flood() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        let node = test::new_node(test::make_id(43));
+        assert!(svc.handler.should_answer(&node));
+
+        let clock = MockClock::new();
+        let mut detector = FloodDetector::with_limits(Duration::from_secs(10), 2, 100);
+        detector.set_clock(Arc::new(clock.clone()));
+        svc.handler_mut().set_flood_detector(detector);
+        let events = svc.subscribe();
+        assert!(svc.handler.should_answer(&node));
+        assert!(svc.handler.should_answer(&node));
+        assert!(!svc.handler.should_answer(&node));
+        assert!(!svc.handler.should_answer(&node));
+        match events.try_recv() {
+            Ok(Event::FloodDetected(FloodSource::Address(ip))) =>
+                assert_eq!(node.address.ip(), ip),
+            other => panic!("unexpected {:?}", other)
+        }
+        assert!(events.try_recv().is_err());
+        // Another port on the same address is dropped as well
+        assert!(!svc.handler.should_answer(&test::new_node_with_port(test::make_id(44), 1)));
+    }
+
+    #[test]
+    fn test_anomaly() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        assert!(svc.handler.is_trusted(&test::new_node(test::make_id(43))));
+
+        svc.handler_mut().set_anomaly_detector(
+            AnomalyDetector::with_limits(Duration::from_secs(60), 1, 10));
+        let events = svc.subscribe();
+        assert!(svc.handler.is_trusted(&test::new_node(test::make_id(43))));
+        assert!(!svc.handler.is_trusted(&test::new_node(test::make_id(44))));
+        match events.try_recv() {
+            Ok(Event::AnomalyDetected(Anomaly::ManyIds(ip, 2))) =>
+                assert_eq!("127.0.0.1".parse::<net::IpAddr>().unwrap(), ip),
+            other => panic!("unexpected {:?}", other)
+        }
+        match events.try_recv() {
+            Ok(Event::Banned(id)) => assert_eq!(test::make_id(44), id),
+            other => panic!("unexpected {:?}", other)
+        }
+        assert!(!svc.handler.is_trusted(&test::new_node(test::make_id(43))));
+        match events.try_recv() {
+            Ok(Event::Banned(id)) => assert_eq!(test::make_id(43), id),
+            other => panic!("unexpected {:?}", other)
+        }
+
+        let ip = "127.0.0.1".parse().unwrap();
+        let detector = svc.handler_mut().anomaly_detector_mut().unwrap();
+        assert!(detector.unban(&ip));
+        assert!(detector.is_trusted(&test::new_node(test::make_id(43))));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_store_verifier() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        let node = test::new_node(test::make_id(43));
+        svc.handler_mut().set_store_verifier(Box::new(|sender, _id, value: &String| {
+            sender.address.port() == 8008 && value.starts_with("good")
+        }));
+
+        assert!(svc.handler.on_store(&node, &test::make_id(44), "good".to_string()));
+        assert!(!svc.handler.on_store(&node, &test::make_id(45), "bad".to_string()));
+        let other = test::new_node_with_port(test::make_id(46), 1);
+        assert!(!svc.handler.on_store(&other, &test::make_id(46), "good".to_string()));
+        assert!(svc.stored_data().get(&test::make_id(44)).is_some());
+        assert!(svc.stored_data().get(&test::make_id(45)).is_none());
+        assert_eq!(2, svc.request_counters().store_rejected);
+    }
+
+    #[test]
+    fn test_check_id() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        let own = test::new_node(test::make_id(42));
+        let zero = test::new_node(test::make_id(0));
+        let banned = test::new_node(test::make_id(44));
+        svc.handler.ban_id(banned.id.clone());
+
+        assert_eq!(Ok(()), svc.handler.check_id(&test::make_id(43)));
+        assert_eq!(Err(IdRejection::Own), svc.handler.check_id(&own.id));
+        svc.handler.on_ping(&zero);
+        assert!(svc.node_table().node.is_none());
+        assert!(!svc.handler.on_store(&banned, &test::make_id(45), "foobar".to_string()));
+        assert!(svc.stored_data().get(&test::make_id(45)).is_none());
+
+        let mut nodes = vec![zero, test::new_node(test::make_id(43)), own, banned];
+        svc.handler.sanitize(&mut nodes);
+        assert_eq!(vec![test::make_id(43)], nodes.into_iter().map(|n| n.id).collect::<Vec<_>>());
+        assert_eq!(IdRejections { zero: 2, own: 2, banned: 2 }, svc.id_rejections());
+        assert_eq!(1, svc.request_counters().store_rejected);
+    }
+
+    #[test]
+    fn test_untrusted_ids_banned() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        let mut detector = AnomalyDetector::new();
+        detector.ban("127.0.0.1".parse().unwrap());
+        svc.handler_mut().set_anomaly_detector(detector);
+        let events = svc.subscribe();
+        let node = test::new_node(test::make_id(43));
+        assert!(!svc.handler.is_trusted(&node));
+        assert!(!svc.handler.is_trusted(&node));
+        assert_eq!(Err(IdRejection::Banned), svc.handler.check_id(&node.id));
+        // Reported only once
+        let received: Vec<_> = events.try_iter().collect();
+        assert_eq!(1, received.len());
+        match received[0] {
+            Event::Banned(ref id) => assert_eq!(node.id, *id),
+            ref other => panic!("wrong event {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_ban_id_removes_node() {
+        let mut svc: Service<TestsIdType, net::SocketAddr,
+                             MockNodeTable<TestsIdType, net::SocketAddr>, String> =
+            Service::new(MockNodeTable::new(test::make_id(42)));
+        let nodes: Vec<_> = (43..46).map(|i| test::new_node(test::make_id(i))).collect();
+        for node in &nodes {
+            svc.node_table_mut().update(node);
+        }
+        svc.handler.ban_id(test::make_id(44));
+        assert_eq!(2, svc.node_table().len());
+        assert!(!svc.node_table().contains(&test::make_id(44)));
+        let found = svc.node_table().find(&test::make_id(44), 3);
+        assert!(found.iter().all(|node| node.id != test::make_id(44)));
+        // Not added back when seen again
+        svc.handler.on_ping(&nodes[1]);
+        assert!(!svc.node_table().contains(&test::make_id(44)));
+    }
+
+    #[test]
+    fn test_ban_id_lifts_oldest() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        let id = |i: usize| vec![1, (i >> 8) as u8, i as u8];
+        for i in 0..super::MAX_BANNED_IDS {
+            svc.handler.ban_id(id(i));
+        }
+        assert_eq!(Err(IdRejection::Banned), svc.handler.check_id(&id(0)));
+        let events = svc.subscribe();
+        svc.handler.ban_id(id(super::MAX_BANNED_IDS));
+        assert_eq!(Err(IdRejection::Banned),
+                   svc.handler.check_id(&id(super::MAX_BANNED_IDS)));
+        assert_eq!(Ok(()), svc.handler.check_id(&id(0)));
+        assert_eq!(Err(IdRejection::Banned), svc.handler.check_id(&id(1)));
+        assert_eq!(super::MAX_BANNED_IDS, svc.handler.banned_ids.len());
+        match events.try_recv() {
+            Ok(Event::Banned(banned)) => assert_eq!(id(super::MAX_BANNED_IDS), banned),
+            other => panic!("wrong event {:?}", other)
+        }
+    }
 }
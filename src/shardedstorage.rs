@@ -0,0 +1,252 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+//! Storage split into independently locked shards.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use std::vec;
+
+use rand;
+use rand::Rng;
+
+use super::{GenericId, MemoryStorage};
+use super::base::{GenericStorage, StorageStats};
+use super::clock::Clock;
+
+
+/// Storage for values accessed from several threads at once.
+///
+/// Values are split between a number of `MemoryStorage` shards by the hash
+/// of their IDs, every shard has its own lock. Clones share the same
+/// values and all methods take `&self`, so e.g. `Service` can answer
+/// requests using one clone while other threads store values or expire
+/// old ones using others.
+pub struct ShardedStorage<TId, TData> {
+    shards: Arc<Vec<Mutex<MemoryStorage<TId, TData>>>>,
+}
+
+
+impl<TId, TData> ShardedStorage<TId, TData>
+        where TId: GenericId,
+              TData: Send + Sync + Clone {
+    /// Create an empty storage with a given number of shards.
+    pub fn new(shards: usize) -> ShardedStorage<TId, TData> {
+        assert!(shards > 0);
+        ShardedStorage {
+            shards: Arc::new((0..shards).map(|_| Mutex::new(MemoryStorage::new())).collect())
+        }
+    }
+
+    /// Create an empty storage accepting at most `max_items` values.
+    ///
+    /// The quota is split evenly between the shards, so a shard may reject
+    /// values before the whole storage is full.
+    pub fn with_quota(shards: usize, max_items: usize) -> ShardedStorage<TId, TData> {
+        assert!(shards > 0);
+        let per_shard = max_items.div_ceil(shards);
+        ShardedStorage {
+            shards: Arc::new((0..shards)
+                .map(|_| Mutex::new(MemoryStorage::with_quota(per_shard)))
+                .collect())
+        }
+    }
+
+    /// Set the clock used to track the age of values.
+    pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+        for shard in self.shards.iter() {
+            shard.lock().unwrap().set_clock(clock.clone());
+        }
+    }
+
+    /// Get a value by its ID.
+    pub fn get(&self, id: &TId) -> Option<TData> {
+        self.shard(id).get(id)
+    }
+
+    /// Store or update a value, returns false if it was rejected.
+    pub fn put(&self, id: TId, value: TData) -> bool {
+        self.shard(&id).put(id, value)
+    }
+
+    /// Get IDs of at most `count` stored values, chosen uniformly at random.
+    pub fn sample(&self, count: usize) -> Vec<TId> {
+        let mut ids: Vec<TId> = self.shards.iter()
+            .flat_map(|shard| shard.lock().unwrap().sample(usize::MAX))
+            .collect();
+        rand::thread_rng().shuffle(&mut ids);
+        ids.truncate(count);
+        ids
+    }
+
+    /// Remove values stored more than `max_age` ago.
+    ///
+    /// Shards are locked one by one, so other threads are only blocked
+    /// while their shard is swept.
+    pub fn expire(&self, max_age: Duration) -> Vec<(TId, TData)> {
+        self.shards.iter()
+            .flat_map(|shard| shard.lock().unwrap().expire_iter(max_age))
+            .collect()
+    }
+
+    /// Remove about `count` of the oldest values.
+    ///
+    /// Every shard evicts its oldest values in proportion to its size,
+    /// rounded up, so up to one more value per shard may be removed.
+    pub fn evict_oldest(&self, count: usize) -> Vec<(TId, TData)> {
+        let total = self.stats().items;
+        if total == 0 || count == 0 {
+            return Vec::new();
+        }
+        self.shards.iter()
+            .flat_map(|shard| {
+                let mut shard = shard.lock().unwrap();
+                let share = (shard.stats().items * count).div_ceil(total);
+                shard.evict_oldest(share)
+            })
+            .collect()
+    }
+
+    /// Get the statistics summed over all shards.
+    pub fn stats(&self) -> StorageStats {
+        let mut res = StorageStats::default();
+        for shard in self.shards.iter() {
+            let stats = shard.lock().unwrap().stats();
+            res.items += stats.items;
+            res.puts_accepted += stats.puts_accepted;
+            res.puts_over_quota += stats.puts_over_quota;
+            res.gets_served += stats.gets_served;
+            res.expired += stats.expired;
+        }
+        res
+    }
+
+    fn shard(&self, id: &TId) -> MutexGuard<'_, MemoryStorage<TId, TData>> {
+        let mut hasher = DefaultHasher::new();
+        id.hash(&mut hasher);
+        let index = (hasher.finish() % self.shards.len() as u64) as usize;
+        self.shards[index].lock().unwrap()
+    }
+}
+
+impl<TId, TData> Clone for ShardedStorage<TId, TData> {
+    fn clone(&self) -> ShardedStorage<TId, TData> {
+        ShardedStorage {
+            shards: self.shards.clone()
+        }
+    }
+}
+
+impl<TId, TData> GenericStorage<TId, TData> for ShardedStorage<TId, TData>
+        where TId: GenericId,
+              TData: Send + Sync + Clone {
+    fn get(&self, id: &TId) -> Option<TData> {
+        ShardedStorage::get(self, id)
+    }
+
+    fn put(&mut self, id: TId, value: TData) -> bool {
+        ShardedStorage::put(self, id, value)
+    }
+
+    fn sample(&self, count: usize) -> Vec<TId> {
+        ShardedStorage::sample(self, count)
+    }
+
+    fn expire_iter(&mut self, max_age: Duration) -> vec::IntoIter<(TId, TData)> {
+        self.expire(max_age).into_iter()
+    }
+
+    fn evict_oldest(&mut self, count: usize) -> vec::IntoIter<(TId, TData)> {
+        ShardedStorage::evict_oldest(self, count).into_iter()
+    }
+
+    fn stats(&self) -> StorageStats {
+        ShardedStorage::stats(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::super::base::GenericStorage;
+    use super::super::mock::MockClock;
+    use super::ShardedStorage;
+
+
+    #[test]
+    fn test_concurrent_put_get() {
+        let s = ShardedStorage::<u64, u64>::new(4);
+        let threads: Vec<_> = (0..4).map(|t| {
+            let s = s.clone();
+            thread::spawn(move || {
+                for i in 0..100 {
+                    assert!(s.put(t * 100 + i, i));
+                }
+            })
+        }).collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(Some(42), s.get(&342));
+        assert_eq!(None, s.get(&400));
+        let stats = s.stats();
+        assert_eq!(400, stats.items);
+        assert_eq!(400, stats.puts_accepted);
+        assert_eq!(1, stats.gets_served);
+        let mut sample = s.sample(500);
+        sample.sort();
+        assert_eq!((0..400).collect::<Vec<u64>>(), sample);
+        assert_eq!(10, s.sample(10).len());
+    }
+
+    #[test]
+    fn test_quota() {
+        let s = ShardedStorage::<u64, u64>::with_quota(2, 10);
+        let accepted = (0..100).filter(|&i| s.put(i, i)).count();
+        assert_eq!(10, accepted);
+        assert_eq!(90, s.stats().puts_over_quota);
+    }
+
+    #[test]
+    fn test_shared_expire() {
+        let clock = MockClock::new();
+        let shared = ShardedStorage::<u64, String>::new(3);
+        shared.set_clock(Arc::new(clock.clone()));
+        let mut storage = shared.clone();
+        assert!(storage.put(1, "foo".to_string()));
+        clock.advance(Duration::from_secs(3600));
+        shared.put(2, "bar".to_string());
+
+        let expired: Vec<_> = storage.expire_iter(Duration::from_secs(3600)).collect();
+        assert_eq!(vec![(1, "foo".to_string())], expired);
+        assert_eq!(Some("bar".to_string()), storage.get(&2));
+        assert_eq!(1, shared.stats().expired);
+    }
+
+    #[test]
+    fn test_evict_oldest() {
+        let clock = MockClock::new();
+        let mut storage = ShardedStorage::<u64, u64>::new(4);
+        storage.set_clock(Arc::new(clock.clone()));
+        for i in 0..100 {
+            storage.put(i, i);
+            clock.advance(Duration::from_secs(1));
+        }
+        let evicted = GenericStorage::evict_oldest(&mut storage, 50).count();
+        assert!((50..54).contains(&evicted), "{}", evicted);
+        assert_eq!(100 - evicted, storage.stats().items);
+        assert_eq!(Some(99), storage.get(&99));
+        assert_eq!(None, storage.get(&0));
+    }
+}
@@ -0,0 +1,552 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+//! Network simulator.
+//!
+//! Runs many in-process nodes, each with its own `Service` and `KNodeTable`,
+//! on a simulated network with latency, packet loss, NAT and churn. Time is
+//! virtual, so that lookup convergence and table maintenance can be checked
+//! on hundreds of nodes in a unit test.
+//!
+//! There is no wire protocol in this crate yet, so requests are passed
+//! directly to `service::Handler` of the receiving node, while the network
+//! decides whether they (and the responses) get through and how long it takes.
+//!
+//! Besides plain lookups, values can be announced by one node and looked up
+//! by another to check routing end to end.
+
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use super::{GenericNodeTable, GenericStorage, KNodeTable, MemoryStorage, Node,
+            Service, SizeEstimator};
+use super::service::{FindResult, Handler};
+
+
+type SimService = Service<u64, usize, KNodeTable<u64, usize>, Vec<u8>>;
+type SimHandler = Handler<u64, usize, KNodeTable<u64, usize>, Vec<u8>,
+                          MemoryStorage<u64, Vec<u8>>>;
+
+
+/// Distribution of one-way latencies.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Latency {
+    /// Every datagram takes the same time.
+    Fixed(Duration),
+    /// Uniformly distributed between the two values.
+    Uniform(Duration, Duration),
+    /// Minimum plus an exponentially distributed delay with the given mean.
+    Exponential(Duration, Duration),
+}
+
+/// Simulation settings.
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+    /// Latency of every datagram.
+    pub latency: Latency,
+    /// Probability of a datagram being lost.
+    pub loss: f64,
+    /// Share of nodes behind NAT, they only receive datagrams from nodes
+    /// they have sent something to before.
+    pub nat: f64,
+    /// Probability of a node leaving or rejoining the network on every step.
+    pub churn: f64,
+    /// Time to wait for a response before giving up.
+    pub timeout: Duration,
+    /// Number of requests sent in parallel during a lookup.
+    pub parallelism: usize,
+    /// Number of closest nodes a lookup is looking for.
+    pub lookup_size: usize,
+}
+
+/// Result of a simulated lookup.
+#[derive(Clone, Debug)]
+pub struct LookupResult {
+    /// IDs of the closest responsive nodes found, closest first.
+    pub closest: Vec<u64>,
+    /// Number of requests sent.
+    pub queries: usize,
+    /// Number of requests that got no response.
+    pub timeouts: usize,
+    /// Number of rounds of parallel requests.
+    pub rounds: usize,
+    /// Value found by `find_value`.
+    pub value: Option<Vec<u8>>,
+    /// Virtual time the lookup took.
+    pub elapsed: Duration,
+}
+
+/// Simulated network of DHT nodes.
+///
+/// Node addresses are their indexes, the first node is used for
+/// bootstrapping and is never behind NAT.
+pub struct Simulation {
+    config: SimConfig,
+    services: Vec<SimService>,
+    nodes: Vec<NodeState>,
+    indexes: HashMap<u64, usize>,
+    /// Pairs of (source, destination) indexes that exchanged datagrams.
+    contacted: HashSet<(usize, usize)>,
+    rng: XorShiftRng,
+    now: Duration,
+    estimator: SizeEstimator<u64>,
+}
+
+struct NodeState {
+    id: u64,
+    online: bool,
+    behind_nat: bool,
+}
+
+
+impl Default for SimConfig {
+    fn default() -> SimConfig {
+        SimConfig {
+            latency: Latency::Uniform(Duration::from_millis(10),
+                                      Duration::from_millis(100)),
+            loss: 0.0,
+            nat: 0.0,
+            churn: 0.0,
+            timeout: Duration::from_secs(1),
+            parallelism: 3,
+            lookup_size: 8
+        }
+    }
+}
+
+impl LookupResult {
+    /// Share of `expected` IDs that the lookup found.
+    pub fn precision(&self, expected: &[u64]) -> f64 {
+        if expected.is_empty() {
+            return 1.0;
+        }
+        let found = expected.iter()
+            .filter(|id| self.closest.contains(id))
+            .count();
+        found as f64 / expected.len() as f64
+    }
+}
+
+impl Simulation {
+    /// Create `count` nodes and bootstrap them one by one.
+    ///
+    /// Every node learns about the first one and looks up its own ID.
+    /// The same `seed` always yields the same simulation.
+    pub fn new(count: usize, config: SimConfig, seed: u32) -> Simulation {
+        assert!(count > 0);
+        let mut rng = XorShiftRng::from_seed([seed, 0x193a_6754, 0xa8a7_d469, 0x9783_0e05]);
+        let mut ids = HashSet::new();
+        let mut nodes = Vec::with_capacity(count);
+        while nodes.len() < count {
+            let id: u64 = rng.gen();
+            if id == 0 || !ids.insert(id) {
+                continue;
+            }
+            let behind_nat = !nodes.is_empty() && rng.gen::<f64>() < config.nat;
+            nodes.push(NodeState {
+                id,
+                online: true,
+                behind_nat
+            });
+        }
+        let services = nodes.iter()
+            .map(|node| Service::new_with_id(KNodeTable::new(node.id), node.id))
+            .collect();
+        let indexes = nodes.iter().enumerate()
+            .map(|(index, node)| (node.id, index))
+            .collect();
+        let mut sim = Simulation {
+            config,
+            services,
+            nodes,
+            indexes,
+            contacted: HashSet::new(),
+            rng,
+            now: Duration::from_secs(0),
+            estimator: SizeEstimator::new(64)
+        };
+
+        let bootstrap = sim.node(0);
+        for index in 1..count {
+            sim.services[index].node_table_mut().update(&bootstrap);
+            let id = sim.nodes[index].id;
+            sim.lookup(index, id);
+        }
+        sim
+    }
+
+    /// Number of nodes, including offline ones.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Whether there are no nodes at all.
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Current virtual time.
+    pub fn now(&self) -> Duration {
+        self.now
+    }
+
+    /// ID of the node with a given index.
+    pub fn node_id(&self, index: usize) -> u64 {
+        self.nodes[index].id
+    }
+
+    /// Whether the node with a given index is online.
+    pub fn is_online(&self, index: usize) -> bool {
+        self.nodes[index].online
+    }
+
+    /// Take a node offline or bring it back.
+    pub fn set_online(&mut self, index: usize, online: bool) {
+        self.nodes[index].online = online;
+    }
+
+    /// Get the service of the node with a given index.
+    pub fn service(&self, index: usize) -> &SimService {
+        &self.services[index]
+    }
+
+    /// Share of node table entries pointing to online nodes.
+    pub fn table_health(&self) -> f64 {
+        let (mut total, mut online) = (0, 0);
+        for (index, service) in self.services.iter().enumerate() {
+            if !self.nodes[index].online {
+                continue;
+            }
+            let table = service.node_table();
+            let id = self.nodes[index].id;
+            for node in table.find(&id, table.len().max(1)) {
+                total += 1;
+                if self.nodes[node.address].online {
+                    online += 1;
+                }
+            }
+        }
+        if total == 0 { 1.0 } else { online as f64 / total as f64 }
+    }
+
+    /// IDs of the online nodes closest to `target`, excluding `from`.
+    ///
+    /// Nodes behind NAT are skipped as well, so this is what an ideal
+    /// lookup from `from` would return.
+    pub fn expected_closest(&self, from: usize, target: u64) -> Vec<u64> {
+        let mut ids: Vec<u64> = self.nodes.iter().enumerate()
+            .filter(|&(index, node)| index != from && node.online && !node.behind_nat)
+            .map(|(_, node)| node.id)
+            .collect();
+        ids.sort_by_key(|id| id ^ target);
+        ids.truncate(self.config.lookup_size);
+        ids
+    }
+
+    /// Run an iterative lookup of `target` from the node `from`.
+    pub fn lookup(&mut self, from: usize, target: u64) -> LookupResult {
+        let res = self.iterate(from, target, false);
+        self.estimator.observe(&target, &res.closest);
+        res
+    }
+
+    /// Network size estimated from the recent lookups.
+    pub fn size_estimate(&self) -> Option<f64> {
+        self.estimator.estimate()
+    }
+
+    /// Store `value` under `key` on the closest nodes found from `from`.
+    ///
+    /// Returns the number of nodes that accepted the value.
+    pub fn announce(&mut self, from: usize, key: u64, value: Vec<u8>) -> usize {
+        let closest = self.lookup(from, key).closest;
+        let mut stored = 0;
+        for id in closest {
+            let to = self.node(self.indexes[&id]);
+            let value = value.clone();
+            if let Some((true, _)) = self.request(from, &to, |handler, sender| {
+                handler.on_store(sender, &key, value)
+            }) {
+                stored += 1;
+            }
+        }
+        debug!("Value {:?} announced by node {} stored on {} nodes", key, from, stored);
+        stored
+    }
+
+    /// Look up the value stored under `key` from the node `from`.
+    ///
+    /// The result has `value` set if the value was found.
+    pub fn find_value(&mut self, from: usize, key: u64) -> LookupResult {
+        let local = self.services[from].stored_data().get(&key);
+        match local {
+            Some(value) => LookupResult {
+                closest: vec![],
+                value: Some(value),
+                queries: 0,
+                timeouts: 0,
+                rounds: 0,
+                elapsed: Duration::from_secs(0)
+            },
+            None => self.iterate(from, key, true)
+        }
+    }
+
+    /// Check that the node `from` finds `value` stored under `key`.
+    pub fn can_find(&mut self, from: usize, key: u64, value: &[u8]) -> bool {
+        self.find_value(from, key).value.as_deref() == Some(value)
+    }
+
+    /// Simulate node churn and let every online node clean up its table.
+    ///
+    /// Unresponsive nodes are detected by a ping, which is subject
+    /// to the same loss and NAT rules as the other requests.
+    pub fn step(&mut self) {
+        for node in self.nodes.iter_mut().skip(1) {
+            if self.rng.gen::<f64>() < self.config.churn {
+                node.online = !node.online;
+            }
+        }
+
+        let nodes = &self.nodes;
+        let contacted = &mut self.contacted;
+        let rng = &mut self.rng;
+        let loss = self.config.loss;
+        for (index, service) in self.services.iter_mut().enumerate() {
+            if !nodes[index].online {
+                continue;
+            }
+            service.clean_up(|node| {
+                contacted.insert((index, node.address));
+                reachable(nodes, contacted, index, node.address)
+                    && rng.gen::<f64>() >= loss
+                    && rng.gen::<f64>() >= loss
+            });
+        }
+    }
+
+    fn node(&self, index: usize) -> Node<u64, usize> {
+        Node {
+            id: self.nodes[index].id,
+            address: index
+        }
+    }
+
+    fn latency(&mut self) -> Duration {
+        match self.config.latency {
+            Latency::Fixed(value) => value,
+            Latency::Uniform(min, max) => {
+                let (min, max) = (min.as_micros() as u64, max.as_micros() as u64);
+                let value = if max > min { self.rng.gen_range(min, max + 1) } else { min };
+                Duration::from_micros(value)
+            },
+            Latency::Exponential(min, mean) => {
+                let sample = -(1.0 - self.rng.gen::<f64>()).ln();
+                min + Duration::from_secs_f64(mean.as_secs_f64() * sample)
+            }
+        }
+    }
+
+    fn iterate(&mut self, from: usize, target: u64, want_value: bool) -> LookupResult {
+        let size = self.config.lookup_size;
+        let own_id = self.nodes[from].id;
+        let mut candidates = self.services[from].node_table().find(&target, size);
+        let mut queried = HashSet::new();
+        let mut failed = HashSet::new();
+        let mut res = LookupResult {
+            closest: vec![],
+            value: None,
+            queries: 0,
+            timeouts: 0,
+            rounds: 0,
+            elapsed: Duration::from_secs(0)
+        };
+
+        while res.value.is_none() {
+            candidates.retain(|node| !failed.contains(&node.id));
+            candidates.sort_by_key(|node| node.id ^ target);
+            candidates.dedup_by_key(|node| node.id);
+            let batch: Vec<Node<u64, usize>> = candidates.iter()
+                .take(size)
+                .filter(|node| !queried.contains(&node.id))
+                .take(self.config.parallelism)
+                .cloned()
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+
+            res.rounds += 1;
+            let mut round_time = Duration::from_secs(0);
+            for node in batch {
+                queried.insert(node.id);
+                res.queries += 1;
+                let response = self.request(from, &node, |handler, sender| {
+                    if want_value {
+                        handler.on_find_value(sender, &target)
+                    }
+                    else {
+                        FindResult::ClosestNodes(handler.on_find_node(sender, &target))
+                    }
+                });
+                match response {
+                    Some((FindResult::Value(value), rtt)) => {
+                        round_time = cmp::max(round_time, rtt);
+                        res.value = Some(value);
+                    },
+                    Some((FindResult::ClosestNodes(found), rtt)) => {
+                        round_time = cmp::max(round_time, rtt);
+                        candidates.extend(found.into_iter()
+                                          .filter(|n| n.id != own_id));
+                    },
+                    Some((FindResult::Nothing, rtt)) => {
+                        round_time = cmp::max(round_time, rtt);
+                    },
+                    None => {
+                        round_time = cmp::max(round_time, self.config.timeout);
+                        res.timeouts += 1;
+                        failed.insert(node.id);
+                    }
+                }
+            }
+            self.now += round_time;
+            res.elapsed += round_time;
+        }
+
+        res.closest = candidates.iter().take(size).map(|node| node.id).collect();
+        debug!("Lookup of {:?} from node {} took {} queries in {} rounds",
+               target, from, res.queries, res.rounds);
+        res
+    }
+
+    /// Pass a request to the handler of `to`, return its result and
+    /// the round trip time if both the request and the response got through.
+    fn request<R, F>(&mut self, from: usize, to: &Node<u64, usize>, handle: F)
+            -> Option<(R, Duration)>
+            where F: FnOnce(&mut SimHandler, &Node<u64, usize>) -> R {
+        self.contacted.insert((from, to.address));
+        if !reachable(&self.nodes, &self.contacted, from, to.address)
+                || self.rng.gen::<f64>() < self.config.loss {
+            return None;
+        }
+        let sender = self.node(from);
+        let res = handle(self.services[to.address].handler_mut(), &sender);
+        if self.rng.gen::<f64>() < self.config.loss {
+            return None;
+        }
+        self.services[from].node_table_mut().update(to);
+        let rtt = self.latency() + self.latency();
+        Some((res, rtt))
+    }
+}
+
+/// Whether a datagram from `from` gets to `to`.
+fn reachable(nodes: &[NodeState], contacted: &HashSet<(usize, usize)>,
+             from: usize, to: usize) -> bool {
+    let node = &nodes[to];
+    node.online && (!node.behind_nat || contacted.contains(&(to, from)))
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::super::GenericNodeTable;
+    use super::{Latency, SimConfig, Simulation};
+
+
+    fn average_precision(sim: &mut Simulation, lookups: usize) -> f64 {
+        let mut total = 0.0;
+        for i in 0..lookups {
+            let from = (i * 7919) % sim.len();
+            if !sim.is_online(from) {
+                continue;
+            }
+            let target = sim.node_id((from + 1) % sim.len()) ^ 0xffff;
+            let expected = sim.expected_closest(from, target);
+            total += sim.lookup(from, target).precision(&expected);
+        }
+        total / lookups as f64
+    }
+
+    #[test]
+    fn test_lookup_converges() {
+        let mut sim = Simulation::new(200, SimConfig::default(), 42);
+        assert_eq!(200, sim.len());
+        assert!(sim.now() > Duration::from_secs(0));
+        assert!(sim.service(1).node_table().len() > 10);
+        assert_eq!(1.0, average_precision(&mut sim, 20));
+    }
+
+    #[test]
+    fn test_lookup_result() {
+        let config = SimConfig {
+            latency: Latency::Fixed(Duration::from_millis(50)),
+            .. SimConfig::default()
+        };
+        let mut sim = Simulation::new(50, config, 1);
+        let target = sim.node_id(10);
+        let res = sim.lookup(20, target);
+        assert_eq!(target, res.closest[0]);
+        assert_eq!(0, res.timeouts);
+        assert_eq!(Duration::from_millis(100) * res.rounds as u32, res.elapsed);
+    }
+
+    #[test]
+    fn test_find_announced() {
+        let mut sim = Simulation::new(1000, SimConfig::default(), 5);
+        let key = sim.node_id(500) ^ 0xdead_beef;
+        assert!(!sim.can_find(250, key, b"value"));
+        assert_eq!(8, sim.announce(10, key, b"value".to_vec()));
+        for &from in &[0, 10, 250, 999] {
+            assert!(sim.can_find(from, key, b"value"));
+        }
+        assert!(sim.find_value(250, key ^ 1).value.is_none());
+        let estimate = sim.size_estimate().unwrap();
+        assert!(estimate > 500.0 && estimate < 2000.0, "{}", estimate);
+    }
+
+    #[test]
+    fn test_loss_churn_nat() {
+        let config = SimConfig {
+            latency: Latency::Exponential(Duration::from_millis(5),
+                                          Duration::from_millis(50)),
+            loss: 0.05,
+            nat: 0.2,
+            churn: 0.05,
+            .. SimConfig::default()
+        };
+        let mut sim = Simulation::new(300, config, 7);
+        for _ in 0..2 {
+            sim.step();
+        }
+        assert!(sim.table_health() < 1.0);
+        assert!(average_precision(&mut sim, 30) > 0.6);
+    }
+
+    #[test]
+    fn test_clean_up_offline() {
+        let mut sim = Simulation::new(100, SimConfig::default(), 3);
+        // The first node knows the most nodes, its oldest entries are
+        // checked first.
+        assert!(sim.service(0).clean_needed());
+        let before = sim.service(0).node_table().len();
+        for index in 1..50 {
+            sim.set_online(index, false);
+        }
+        for _ in 0..10 {
+            sim.step();
+        }
+        assert!(sim.service(0).node_table().len() < before);
+    }
+}
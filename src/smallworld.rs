@@ -0,0 +1,241 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+//! Experimental Freenet-style small-world routing.
+//!
+//! Nodes have locations on a ring and only talk to their neighbors, which
+//! are given by the network, not chosen by the DHT. Requests are routed
+//! to the neighbor closest to the target location. To make it work, pairs
+//! of nodes swap their locations when that brings them closer to their
+//! neighbors, so that the locations eventually reflect the shape of the
+//! network (see "Searching in a Small World" by Oskar Sandberg).
+//!
+//! Locations are `u64` IDs with `RingDistance`, so `KNodeTable` can also
+//! be used with them.
+
+use std::cmp;
+
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use super::Distance;
+
+
+/// Circular distance on a ring of 2^64 locations.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RingDistance;
+
+/// Result of routing a request.
+#[derive(Clone, Debug)]
+pub struct Route {
+    /// Nodes visited, starting from the source.
+    pub path: Vec<usize>,
+    /// Whether the last node has the target location.
+    pub found: bool,
+}
+
+/// Network of nodes with fixed links and swappable locations.
+pub struct SmallWorld {
+    locations: Vec<u64>,
+    neighbors: Vec<Vec<usize>>,
+    rng: XorShiftRng,
+    swaps: usize,
+}
+
+
+impl Distance<u64> for RingDistance {
+    fn distance(&self, id1: &u64, id2: &u64) -> u64 {
+        cmp::min(id1.wrapping_sub(*id2), id2.wrapping_sub(*id1))
+    }
+}
+
+impl SmallWorld {
+    /// Create a network with given links between nodes and random locations.
+    ///
+    /// Links are symmetric, `neighbors[i]` lists nodes `i` is linked to.
+    pub fn new(neighbors: Vec<Vec<usize>>, seed: u32) -> SmallWorld {
+        let mut rng = XorShiftRng::from_seed([seed, 1, 2, 3]);
+        let locations = (0..neighbors.len()).map(|_| rng.gen()).collect();
+        SmallWorld {
+            locations,
+            neighbors,
+            rng,
+            swaps: 0
+        }
+    }
+
+    /// Create a network similar to social ones: nodes are linked to the
+    /// closest ones in some hidden space, and to `long_links` random nodes
+    /// picked with probability inversely proportional to the distance.
+    pub fn generate(count: usize, long_links: usize, seed: u32) -> SmallWorld {
+        assert!(count > 2);
+        let mut rng = XorShiftRng::from_seed([seed, 3, 2, 1]);
+        let mut neighbors = vec![vec![]; count];
+        let link = |neighbors: &mut Vec<Vec<usize>>, a: usize, b: usize| {
+            if a != b && !neighbors[a].contains(&b) {
+                neighbors[a].push(b);
+                neighbors[b].push(a);
+            }
+        };
+        for i in 0..count {
+            link(&mut neighbors, i, (i + 1) % count);
+        }
+        // Harmonic distribution of distances in 1..count/2
+        let max_distance = (count / 2) as f64;
+        for i in 0..count {
+            for _ in 0..long_links {
+                let distance = max_distance.powf(rng.gen::<f64>()) as usize;
+                let other = if rng.gen() { i + distance } else { i + count - distance };
+                link(&mut neighbors, i, other % count);
+            }
+        }
+        SmallWorld::new(neighbors, seed)
+    }
+
+    /// Number of nodes.
+    pub fn len(&self) -> usize {
+        self.locations.len()
+    }
+
+    /// Whether the network has no nodes.
+    pub fn is_empty(&self) -> bool {
+        self.locations.is_empty()
+    }
+
+    /// Location of a node.
+    pub fn location(&self, index: usize) -> u64 {
+        self.locations[index]
+    }
+
+    /// Neighbors of a node.
+    pub fn neighbors(&self, index: usize) -> &[usize] {
+        &self.neighbors[index]
+    }
+
+    /// Number of swaps accepted so far.
+    pub fn swaps(&self) -> usize {
+        self.swaps
+    }
+
+    /// Route from `from` towards `target` location.
+    ///
+    /// Every hop goes to the neighbor closest to the target which was not
+    /// visited yet, even if it is further than the current node, like
+    /// Freenet does. Stops at the target, at a node without unvisited
+    /// neighbors or after `max_hops` hops.
+    pub fn route(&self, from: usize, target: u64, max_hops: usize) -> Route {
+        let mut path = vec![from];
+        let mut current = from;
+        for _ in 0..max_hops {
+            if self.locations[current] == target {
+                break;
+            }
+            let next = self.neighbors[current].iter().cloned()
+                .filter(|n| !path.contains(n))
+                .min_by_key(|&n| RingDistance.distance(&self.locations[n], &target));
+            match next {
+                Some(next) => {
+                    current = next;
+                    path.push(current);
+                },
+                None => break
+            }
+        }
+        Route {
+            found: self.locations[current] == target,
+            path
+        }
+    }
+
+    /// Share of routes between random nodes that reached their target.
+    pub fn success_rate(&mut self, routes: usize, max_hops: usize) -> f64 {
+        let mut found = 0;
+        for _ in 0..routes {
+            let from = self.rng.gen_range(0, self.len());
+            let to = self.rng.gen_range(0, self.len());
+            if self.route(from, self.locations[to], max_hops).found {
+                found += 1;
+            }
+        }
+        found as f64 / routes as f64
+    }
+
+    /// Propose location swaps between `count` random pairs of nodes.
+    ///
+    /// A swap is accepted if it makes the product of distances between
+    /// the two nodes and their neighbors smaller, otherwise with probability
+    /// equal to the ratio of the products. Returns the number of swaps
+    /// accepted.
+    pub fn swap_steps(&mut self, count: usize) -> usize {
+        let mut accepted = 0;
+        for _ in 0..count {
+            let a = self.rng.gen_range(0, self.len());
+            let b = self.rng.gen_range(0, self.len());
+            if a == b {
+                continue;
+            }
+            let (loc_a, loc_b) = (self.locations[a], self.locations[b]);
+            // Logarithms of the products, to avoid overflows
+            let before = self.log_distances(a, loc_a, b) + self.log_distances(b, loc_b, a);
+            let after = self.log_distances(a, loc_b, b) + self.log_distances(b, loc_a, a);
+            if after <= before || self.rng.gen::<f64>() < (before - after).exp() {
+                self.locations.swap(a, b);
+                accepted += 1;
+            }
+        }
+        self.swaps += accepted;
+        accepted
+    }
+
+    /// Sum of log distances from `location` to neighbors of `index`,
+    /// not counting `other` which is swapping with it.
+    fn log_distances(&self, index: usize, location: u64, other: usize) -> f64 {
+        self.neighbors[index].iter()
+            .filter(|&&n| n != other)
+            .map(|&n| (RingDistance.distance(&location, &self.locations[n]) as f64 + 1.0).ln())
+            .sum()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::super::Distance;
+    use super::{RingDistance, SmallWorld};
+
+
+    #[test]
+    fn test_ring_distance() {
+        assert_eq!(0, RingDistance.distance(&42, &42));
+        assert_eq!(2, RingDistance.distance(&1, &u64::MAX));
+        assert_eq!(1 << 63, RingDistance.distance(&0, &(1 << 63)));
+    }
+
+    #[test]
+    fn test_route() {
+        let mut w = SmallWorld::new(vec![vec![1], vec![0, 2], vec![1]], 1);
+        w.locations = vec![10, 20, 30];
+        let route = w.route(0, 30, 10);
+        assert!(route.found);
+        assert_eq!(vec![0, 1, 2], route.path);
+        let route = w.route(2, 12, 10);
+        assert!(!route.found);
+        assert_eq!(vec![2, 1, 0], route.path);
+        assert_eq!(vec![2, 1], w.route(2, 12, 1).path);
+    }
+
+    #[test]
+    fn test_swapping_improves_routing() {
+        let mut w = SmallWorld::generate(200, 2, 42);
+        let before = w.success_rate(200, 10);
+        w.swap_steps(50000);
+        assert!(w.swaps() > 0);
+        let after = w.success_rate(200, 10);
+        assert!(after > before + 0.2, "{} -> {}", before, after);
+    }
+}
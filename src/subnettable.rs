@@ -0,0 +1,262 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+//! Limits on the number of nodes from the same subnet.
+//!
+//! Subnets are /24 for IPv4 and /48 for IPv6 addresses. An attacker
+//! controlling one subnet can easily generate IDs for every bucket, so
+//! per-bucket limits do not stop it from filling the table or from
+//! dominating the nodes returned from lookups.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use super::{GenericId, GenericNodeTable, Node};
+
+
+static MAX_TABLE_NODES_PER_SUBNET: usize = 16;
+static MAX_FOUND_NODES_PER_SUBNET: usize = 2;
+
+
+/// Node table wrapper limiting the number of nodes from the same subnet.
+///
+/// At most a given number of nodes from a subnet are accepted into the
+/// whole table, and at most a (smaller) number of them are returned from
+/// `find`.
+pub struct SubnetCappedTable<TId, TNodeTable> {
+    inner: TNodeTable,
+    max_in_table: usize,
+    max_in_find: usize,
+    subnets: HashMap<IpAddr, usize>,
+    members: HashMap<TId, IpAddr>,
+}
+
+
+impl<TId, TNodeTable> SubnetCappedTable<TId, TNodeTable>
+        where TId: GenericId,
+              TNodeTable: GenericNodeTable<TId, SocketAddr> {
+    /// Wrap an empty node table with the default limits.
+    ///
+    /// 16 nodes per subnet are accepted, 2 of them returned from `find`.
+    pub fn new(inner: TNodeTable) -> SubnetCappedTable<TId, TNodeTable> {
+        SubnetCappedTable::with_caps(inner, MAX_TABLE_NODES_PER_SUBNET,
+                                     MAX_FOUND_NODES_PER_SUBNET)
+    }
+
+    /// Wrap an empty node table with given limits.
+    ///
+    /// `max_in_table` -- number of nodes per subnet accepted into the table.
+    /// `max_in_find` -- number of nodes per subnet returned from `find`.
+    pub fn with_caps(inner: TNodeTable, max_in_table: usize,
+                     max_in_find: usize) -> SubnetCappedTable<TId, TNodeTable> {
+        assert!(inner.is_empty());
+        assert!(max_in_table > 0 && max_in_find > 0);
+        SubnetCappedTable {
+            inner,
+            max_in_table,
+            max_in_find,
+            subnets: HashMap::new(),
+            members: HashMap::new()
+        }
+    }
+
+    /// Get the wrapped node table.
+    pub fn inner(&self) -> &TNodeTable {
+        &self.inner
+    }
+
+    /// Number of nodes in the table from the subnet of an address.
+    pub fn subnet_count(&self, address: &SocketAddr) -> usize {
+        self.subnets.get(&subnet(address)).cloned().unwrap_or(0)
+    }
+
+    fn forget(&mut self, id: &TId) {
+        if let Some(old) = self.members.remove(id) {
+            let count = self.subnets.get_mut(&old).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                self.subnets.remove(&old);
+            }
+        }
+    }
+}
+
+impl<TId, TNodeTable> GenericNodeTable<TId, SocketAddr> for SubnetCappedTable<TId, TNodeTable>
+        where TId: GenericId,
+              TNodeTable: GenericNodeTable<TId, SocketAddr> {
+    fn random_id(&self) -> TId {
+        self.inner.random_id()
+    }
+
+    fn update(&mut self, node: &Node<TId, SocketAddr>) -> bool {
+        let subnet = subnet(&node.address);
+        if self.members.get(&node.id) == Some(&subnet) {
+            return self.inner.update(node);
+        }
+        if self.subnets.get(&subnet).cloned().unwrap_or(0) >= self.max_in_table {
+            debug!("Not adding node {:?} - too many nodes from {}", node, subnet);
+            return false;
+        }
+        if !self.inner.update(node) {
+            return false;
+        }
+        self.forget(&node.id);
+        self.members.insert(node.id.clone(), subnet);
+        *self.subnets.entry(subnet).or_insert(0) += 1;
+        true
+    }
+
+    fn find(&self, id: &TId, count: usize) -> Vec<Node<TId, SocketAddr>> {
+        // Ask for more nodes until enough are left after capping
+        let mut fetch = count;
+        loop {
+            let mut found = self.inner.find(id, fetch);
+            let exhausted = found.len() < fetch;
+            cap_per_subnet(&mut found, self.max_in_find);
+            if found.len() >= count || exhausted {
+                found.truncate(count);
+                return found;
+            }
+            fetch *= 2;
+        }
+    }
+
+    fn pop_oldest(&mut self) -> Vec<Node<TId, SocketAddr>> {
+        let oldest = self.inner.pop_oldest();
+        for node in &oldest {
+            self.forget(&node.id);
+        }
+        oldest
+    }
+
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    fn contains(&self, id: &TId) -> bool {
+        self.inner.contains(id)
+    }
+
+    fn remove(&mut self, id: &TId) -> Option<Node<TId, SocketAddr>> {
+        let res = self.inner.remove(id);
+        if res.is_some() {
+            self.forget(id);
+        }
+        res
+    }
+}
+
+/// Remove nodes from a list, e.g. a lookup shortlist, so that at most
+/// `max_per_subnet` nodes are left from every subnet.
+///
+/// The order is kept, so the first nodes from a subnet are left.
+pub fn cap_per_subnet<TId>(nodes: &mut Vec<Node<TId, SocketAddr>>, max_per_subnet: usize) {
+    let mut counts = HashMap::new();
+    nodes.retain(|node| {
+        let count = counts.entry(subnet(&node.address)).or_insert(0);
+        *count += 1;
+        *count <= max_per_subnet
+    });
+}
+
+/// The /24 or /48 subnet of an address, as its first address.
+pub fn subnet(address: &SocketAddr) -> IpAddr {
+    match address.ip().to_canonical() {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::V4(Ipv4Addr::new(a, b, c, 0))
+        },
+        IpAddr::V6(ip) => {
+            let s = ip.segments();
+            IpAddr::V6(Ipv6Addr::new(s[0], s[1], s[2], 0, 0, 0, 0, 0))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use super::super::{GenericNodeTable, Node};
+    use super::super::mock::MockNodeTable;
+    use super::{SubnetCappedTable, cap_per_subnet};
+
+    use super::super::utils::test;
+    type TestsIdType = test::IdType;
+
+
+    fn node(id: u8, address: &str) -> Node<TestsIdType, SocketAddr> {
+        Node { id: test::make_id(id), address: address.parse().unwrap() }
+    }
+
+    #[test]
+    fn test_table_cap() {
+        let mut t = SubnetCappedTable::with_caps(MockNodeTable::new(test::make_id(0)), 2, 2);
+        assert!(t.update(&node(1, "10.0.0.1:1")));
+        assert!(t.update(&node(2, "10.0.0.2:1")));
+        assert!(!t.update(&node(3, "10.0.0.3:1")));
+        assert!(t.update(&node(3, "10.0.1.3:1")));
+        // Updating a known node does not count twice
+        assert!(t.update(&node(2, "10.0.0.2:2")));
+        assert_eq!(2, t.subnet_count(&"10.0.0.42:1".parse().unwrap()));
+        // IPv4-mapped IPv6 addresses are in the same subnet
+        assert!(!t.update(&node(4, "[::ffff:10.0.0.4]:1")));
+        // Moving a node to another subnet frees its place
+        assert!(t.update(&node(2, "10.0.1.2:1")));
+        assert!(t.update(&node(4, "[::ffff:10.0.0.4]:1")));
+        assert_eq!(4, t.len());
+
+        assert!(t.update(&node(5, "[2001:db8:1:2::1]:1")));
+        assert!(t.update(&node(6, "[2001:db8:1:3::1]:1")));
+        assert!(!t.update(&node(7, "[2001:db8:1:ffff::1]:1")));
+    }
+
+    #[test]
+    fn test_pop_oldest_frees_subnet() {
+        let mut t = SubnetCappedTable::with_caps(MockNodeTable::new(test::make_id(0)), 1, 1);
+        assert!(t.update(&node(1, "10.0.0.1:1")));
+        t.inner.set_oldest(vec![node(1, "10.0.0.1:1")]);
+        assert_eq!(1, t.pop_oldest().len());
+        assert_eq!(0, t.subnet_count(&"10.0.0.1:1".parse().unwrap()));
+        assert!(t.update(&node(2, "10.0.0.2:1")));
+    }
+
+    #[test]
+    fn test_remove_frees_subnet() {
+        let mut t = SubnetCappedTable::with_caps(MockNodeTable::new(test::make_id(0)), 1, 1);
+        assert!(t.update(&node(1, "10.0.0.1:1")));
+        assert!(t.remove(&test::make_id(2)).is_none());
+        assert_eq!(test::make_id(1), t.remove(&test::make_id(1)).unwrap().id);
+        assert_eq!(0, t.subnet_count(&"10.0.0.1:1".parse().unwrap()));
+        assert!(t.update(&node(2, "10.0.0.2:1")));
+    }
+
+    #[test]
+    fn test_find_cap() {
+        let mut t = SubnetCappedTable::with_caps(MockNodeTable::new(test::make_id(0)), 10, 1);
+        for i in 1..5 {
+            assert!(t.update(&node(i, "10.0.0.1:1")));
+        }
+        assert!(t.update(&node(8, "10.0.1.1:1")));
+        assert!(t.update(&node(9, "10.0.2.1:1")));
+        let found: Vec<_> = t.find(&test::make_id(1), 3).into_iter().map(|n| n.id).collect();
+        assert_eq!(vec![test::make_id(1), test::make_id(9), test::make_id(8)], found);
+        assert_eq!(3, t.find(&test::make_id(1), 10).len());
+    }
+
+    #[test]
+    fn test_cap_per_subnet() {
+        let mut nodes = vec![node(1, "10.0.0.1:1"), node(2, "10.0.1.1:1"),
+                             node(3, "10.0.0.2:1"), node(4, "10.0.0.3:1")];
+        cap_per_subnet(&mut nodes, 2);
+        let ids: Vec<_> = nodes.into_iter().map(|n| n.id).collect();
+        assert_eq!(vec![test::make_id(1), test::make_id(2), test::make_id(3)], ids);
+    }
+}
@@ -0,0 +1,1641 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+//! Datagram transports.
+//!
+//! `Transport` abstracts sending and receiving datagrams. `UdpTransport` uses
+//! an OS socket, `MemoryNetwork` provides in-process transports for tests,
+//! `Socks5Transport` relays datagrams through a SOCKS5 proxy, `FaultyTransport`
+//! injects failures into any other transport, `SharedTransport` lets
+//! several DHT instances use one transport and `RateLimitedTransport` limits
+//! outgoing traffic with priorities.
+//!
+//! Datagrams can also be sent and received in batches, on Linux
+//! `UdpTransport` does it with one system call per batch.
+
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs,
+               UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::clock::{Clock, SystemClock};
+
+
+static MAX_DATAGRAM_SIZE: usize = 65536;
+static MAX_POOLED_BUFFERS: usize = 64;
+static SOCKS_VERSION: u8 = 5;
+static SOCKS_NO_AUTH: u8 = 0;
+static SOCKS_PASSWORD_AUTH: u8 = 2;
+static SOCKS_UDP_ASSOCIATE: u8 = 3;
+static SOCKS_IPV4: u8 = 1;
+static SOCKS_DOMAIN: u8 = 3;
+static SOCKS_IPV6: u8 = 4;
+
+
+/// Trait representing a datagram transport.
+pub trait Transport : Send {
+    /// Address type.
+    type Addr: Clone + Send;
+    /// Send a datagram to a given address.
+    fn send_to(&mut self, data: &[u8], addr: &Self::Addr) -> io::Result<()>;
+    /// Receive a datagram if one is available.
+    ///
+    /// Returns the datagram size and its source, or `None` if there is
+    /// nothing to receive right now. Datagrams larger than `buffer`
+    /// are truncated.
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<Option<(usize, Self::Addr)>>;
+    /// Get the local address.
+    fn local_addr(&self) -> io::Result<Self::Addr>;
+    /// Send several datagrams, e.g. requests of one lookup step.
+    ///
+    /// Returns the number of datagrams sent from the start of the batch,
+    /// an error is only returned if none were sent. The default
+    /// implementation calls `send_to` for every datagram.
+    fn send_batch(&mut self, batch: &[(&[u8], Self::Addr)]) -> io::Result<usize> {
+        for (sent, &(data, ref addr)) in batch.iter().enumerate() {
+            if let Err(e) = self.send_to(data, addr) {
+                return if sent == 0 { Err(e) } else { Ok(sent) };
+            }
+        }
+        Ok(batch.len())
+    }
+    /// Receive as many datagrams as available and fit into `batch`.
+    ///
+    /// Previous contents of the batch are discarded. Returns the number
+    /// of datagrams received. The default implementation calls `recv_from`
+    /// for every datagram.
+    fn recv_batch(&mut self, batch: &mut RecvBatch<Self::Addr>) -> io::Result<usize> {
+        batch.fill(|buffer| self.recv_from(buffer))
+    }
+}
+
+/// Reusable buffers for `Transport::recv_batch`.
+pub struct RecvBatch<TAddr> {
+    buffers: Vec<Vec<u8>>,
+    received: Vec<(usize, TAddr)>,
+}
+
+/// Transport over a non-blocking UDP socket.
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+/// Transport relaying datagrams through a SOCKS5 proxy (UDP ASSOCIATE).
+///
+/// The proxy keeps the association while the control connection is open,
+/// i.e. until the transport is dropped.
+pub struct Socks5Transport {
+    // Only kept open, the proxy drops the association when it is closed
+    _control: TcpStream,
+    socket: UdpSocket,
+    relay: SocketAddr,
+    // Reused for every datagram to avoid allocations
+    send_buffer: Vec<u8>,
+    recv_buffer: Vec<u8>,
+}
+
+/// Network of in-process transports.
+///
+/// In the default mode datagrams are delivered immediately on sending.
+/// With manual delivery they are queued until the test calls `deliver`.
+pub struct MemoryNetwork<TAddr> {
+    state: Arc<Mutex<NetworkState<TAddr>>>,
+}
+
+/// Transport attached to a `MemoryNetwork`.
+pub struct MemoryTransport<TAddr>
+        where TAddr: Eq + Hash {
+    address: TAddr,
+    state: Arc<Mutex<NetworkState<TAddr>>>,
+}
+
+/// Datagram with its source and destination.
+struct Datagram<TAddr> {
+    source: TAddr,
+    destination: TAddr,
+    data: Vec<u8>,
+}
+
+struct NetworkState<TAddr> {
+    inboxes: HashMap<TAddr, VecDeque<(Vec<u8>, TAddr)>>,
+    pending: VecDeque<Datagram<TAddr>>,
+    manual_delivery: bool,
+    dropped: usize,
+}
+
+/// Direction of a datagram passing through `FaultyTransport`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Outgoing,
+    Incoming
+}
+
+/// What `FaultyTransport` does with a datagram.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// Pass the datagram as is.
+    Deliver,
+    /// Silently lose the datagram.
+    Drop,
+    /// Pass the datagram twice.
+    Duplicate,
+    /// Hold the datagram for some time, datagrams passed meanwhile
+    /// overtake it.
+    Delay(Duration),
+    /// Pass only the given number of first bytes.
+    Truncate(usize)
+}
+
+/// Number of datagrams affected by each kind of fault.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FaultStats {
+    /// Datagrams lost.
+    pub dropped: usize,
+    /// Datagrams passed twice.
+    pub duplicated: usize,
+    /// Datagrams held for some time.
+    pub delayed: usize,
+    /// Datagrams cut short.
+    pub truncated: usize
+}
+
+/// Transport wrapper injecting failures.
+///
+/// Every datagram sent or received is passed to the policy, which decides
+/// on the `Fault` to apply. Delayed datagrams are released on the next
+/// `send_to` or `recv_from` call after their time comes.
+pub struct FaultyTransport<T: Transport> {
+    inner: T,
+    policy: FaultPolicy<T::Addr>,
+    clock: Arc<dyn Clock>,
+    delayed: Vec<(Instant, Direction, Vec<u8>, T::Addr)>,
+    incoming: VecDeque<(Vec<u8>, T::Addr)>,
+    stats: FaultStats,
+    // Reused for every datagram to avoid allocations
+    buffer: Vec<u8>,
+}
+
+type FaultPolicy<TAddr> = Box<dyn FnMut(Direction, &[u8], &TAddr) -> Fault + Send>;
+
+/// Transport shared by several DHT instances, e.g. with different node IDs
+/// or on different networks.
+///
+/// Every incoming datagram is passed to the classifier, which returns
+/// the index of the instance it belongs to.
+pub struct SharedTransport<T: Transport> {
+    state: Arc<Mutex<SharedState<T>>>,
+}
+
+/// Transport of one instance on a `SharedTransport`.
+pub struct InstanceTransport<T: Transport> {
+    index: usize,
+    state: Arc<Mutex<SharedState<T>>>,
+}
+
+struct SharedState<T: Transport> {
+    inner: T,
+    classifier: Classifier<T::Addr>,
+    inboxes: Vec<VecDeque<(Vec<u8>, T::Addr)>>,
+    unclaimed: usize,
+    buffer: Vec<u8>,
+    pool: BufferPool,
+}
+
+/// Buffers of datagrams already consumed, reused for new ones.
+#[derive(Default)]
+struct BufferPool {
+    free: Vec<Vec<u8>>,
+}
+
+type Classifier<TAddr> = Box<dyn FnMut(&[u8], &TAddr) -> Option<usize> + Send>;
+
+/// Class of outgoing traffic, from the most to the least important.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Priority {
+    /// Requests of lookups started by the user.
+    Lookup,
+    /// Responses to requests of other nodes.
+    Response,
+    /// Pings, bucket refreshes, republishing and other maintenance.
+    Maintenance
+}
+
+/// Limiter of outgoing packets and bytes per second.
+///
+/// Both limits are token buckets holding at most one second worth of
+/// traffic. Lower priorities cannot use the tokens reserved for higher
+/// ones: responses leave a quarter of the buckets to lookups, maintenance
+/// leaves a half to lookups and responses. The reserve never takes so
+/// much that full buckets cannot pass a datagram, so every priority gets
+/// through eventually. Clones share the same buckets, so one limiter can
+/// cover all transports of a process.
+#[derive(Clone)]
+pub struct RateLimiter {
+    state: Arc<Mutex<LimiterState>>,
+}
+
+struct LimiterState {
+    packets_per_sec: f64,
+    bytes_per_sec: f64,
+    packets: f64,
+    bytes: f64,
+    refilled_at: Instant,
+    denied: HashMap<Priority, usize>,
+    clock: Arc<dyn Clock>,
+}
+
+/// Transport wrapper sending datagrams only if a `RateLimiter` allows it.
+///
+/// Datagrams over the limit are not queued, `send_to` fails with
+/// `ErrorKind::WouldBlock` instead.
+pub struct RateLimitedTransport<T: Transport> {
+    inner: T,
+    limiter: RateLimiter,
+    priority: Priority,
+}
+
+
+impl UdpTransport {
+    /// Bind a new socket to a given address.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<UdpTransport> {
+        UdpTransport::from_socket(UdpSocket::bind(addr)?)
+    }
+
+    /// Use an existing socket, e.g. one shared with other protocols.
+    ///
+    /// The socket is switched to the non-blocking mode.
+    pub fn from_socket(socket: UdpSocket) -> io::Result<UdpTransport> {
+        socket.set_nonblocking(true)?;
+        Ok(UdpTransport { socket })
+    }
+
+    /// Get the underlying socket.
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+}
+
+impl Transport for UdpTransport {
+    type Addr = SocketAddr;
+
+    fn send_to(&mut self, data: &[u8], addr: &SocketAddr) -> io::Result<()> {
+        self.socket.send_to(data, addr).map(|_| ())
+    }
+
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+        match self.socket.recv_from(buffer) {
+            Ok(res) => Ok(Some(res)),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Uses one `sendmmsg` call per up to 64 datagrams.
+    #[cfg(target_os = "linux")]
+    fn send_batch(&mut self, batch: &[(&[u8], SocketAddr)]) -> io::Result<usize> {
+        let mut sent = 0;
+        for chunk in batch.chunks(mmsg::MAX_BATCH_SIZE) {
+            match mmsg::send(&self.socket, chunk) {
+                Ok(count) => {
+                    sent += count;
+                    if count < chunk.len() {
+                        break;
+                    }
+                },
+                Err(e) => {
+                    if sent == 0 {
+                        return Err(e);
+                    }
+                    break;
+                }
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Uses one `recvmmsg` call per up to 64 datagrams.
+    #[cfg(target_os = "linux")]
+    fn recv_batch(&mut self, batch: &mut RecvBatch<SocketAddr>) -> io::Result<usize> {
+        batch.received.clear();
+        while batch.received.len() < batch.buffers.len() {
+            let start = batch.received.len();
+            let end = cmp::min(start + mmsg::MAX_BATCH_SIZE, batch.buffers.len());
+            match mmsg::recv(&self.socket, &mut batch.buffers[start..end]) {
+                Ok(received) => {
+                    let count = received.len();
+                    batch.received.extend(received);
+                    if count < end - start {
+                        break;
+                    }
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    if batch.received.is_empty() {
+                        return Err(e);
+                    }
+                    break;
+                }
+            }
+        }
+        Ok(batch.received.len())
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+impl Socks5Transport {
+    /// Associate with a proxy not requiring authentication.
+    pub fn connect<A: ToSocketAddrs>(proxy: A) -> io::Result<Socks5Transport> {
+        Socks5Transport::connect_with_auth(proxy, None)
+    }
+
+    /// Associate with a proxy, authenticating with a user name and password
+    /// if given.
+    pub fn connect_with_auth<A: ToSocketAddrs>(proxy: A, credentials: Option<(&str, &str)>)
+            -> io::Result<Socks5Transport> {
+        let mut control = TcpStream::connect(proxy)?;
+        let proxy_addr = control.peer_addr()?;
+
+        let method = if credentials.is_some() { SOCKS_PASSWORD_AUTH } else { SOCKS_NO_AUTH };
+        control.write_all(&[SOCKS_VERSION, 1, method])?;
+        let mut reply = [0u8; 2];
+        control.read_exact(&mut reply)?;
+        if reply[0] != SOCKS_VERSION || reply[1] != method {
+            return Err(socks_error("proxy rejected the authentication method"));
+        }
+        if let Some((user, password)) = credentials {
+            if user.len() > 255 || password.len() > 255 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "user name or password too long"));
+            }
+            let mut request = vec![1, user.len() as u8];
+            request.extend_from_slice(user.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+            control.write_all(&request)?;
+            control.read_exact(&mut reply)?;
+            if reply[1] != 0 {
+                return Err(socks_error("proxy rejected the credentials"));
+            }
+        }
+
+        let unspecified = match proxy_addr {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        };
+        let socket = UdpSocket::bind(SocketAddr::new(unspecified, 0))?;
+        let mut request = vec![SOCKS_VERSION, SOCKS_UDP_ASSOCIATE, 0];
+        push_socks_addr(&mut request, &socket.local_addr()?);
+        control.write_all(&request)?;
+        let mut reply = [0u8; 3];
+        control.read_exact(&mut reply)?;
+        if reply[0] != SOCKS_VERSION || reply[1] != 0 {
+            return Err(socks_error(&format!("proxy refused UDP ASSOCIATE with code {}",
+                                            reply[1])));
+        }
+        let mut relay = read_socks_addr(&mut control)?;
+        if relay.ip().is_unspecified() {
+            relay.set_ip(proxy_addr.ip());
+        }
+        debug!("SOCKS5 proxy {} relays datagrams via {}", proxy_addr, relay);
+
+        socket.set_nonblocking(true)?;
+        Ok(Socks5Transport {
+            _control: control,
+            socket,
+            relay,
+            send_buffer: vec![],
+            recv_buffer: vec![0u8; MAX_DATAGRAM_SIZE]
+        })
+    }
+
+    /// Address the proxy relays datagrams through.
+    pub fn relay_addr(&self) -> SocketAddr {
+        self.relay
+    }
+}
+
+impl Transport for Socks5Transport {
+    type Addr = SocketAddr;
+
+    fn send_to(&mut self, data: &[u8], addr: &SocketAddr) -> io::Result<()> {
+        self.send_buffer.clear();
+        self.send_buffer.extend_from_slice(&[0, 0, 0]);  // reserved, no fragmentation
+        push_socks_addr(&mut self.send_buffer, addr);
+        self.send_buffer.extend_from_slice(data);
+        self.socket.send_to(&self.send_buffer, self.relay).map(|_| ())
+    }
+
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+        let received = &mut self.recv_buffer;
+        loop {
+            let (size, source) = match self.socket.recv_from(received) {
+                Ok(res) => res,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(e)
+            };
+            if source != self.relay || size < 3 || received[2] != 0 {
+                debug!("Dropping datagram from {} not relayed by the proxy", source);
+                continue;
+            }
+            let mut payload = &received[3..size];
+            let addr = match read_socks_addr(&mut payload) {
+                Ok(addr) => addr,
+                Err(e) => {
+                    debug!("Dropping datagram with invalid SOCKS5 header: {}", e);
+                    continue;
+                }
+            };
+            let size = cmp::min(payload.len(), buffer.len());
+            buffer[..size].copy_from_slice(&payload[..size]);
+            return Ok(Some((size, addr)));
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+fn socks_error(message: &str) -> io::Error {
+    io::Error::other(format!("SOCKS5: {}", message))
+}
+
+fn push_socks_addr(output: &mut Vec<u8>, addr: &SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            output.push(SOCKS_IPV4);
+            output.extend_from_slice(&ip.octets());
+        },
+        IpAddr::V6(ip) => {
+            output.push(SOCKS_IPV6);
+            output.extend_from_slice(&ip.octets());
+        }
+    }
+    output.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+fn read_socks_addr<R: Read>(input: &mut R) -> io::Result<SocketAddr> {
+    let mut kind = [0u8; 1];
+    input.read_exact(&mut kind)?;
+    let ip = if kind[0] == SOCKS_IPV4 {
+        let mut octets = [0u8; 4];
+        input.read_exact(&mut octets)?;
+        IpAddr::V4(Ipv4Addr::from(octets))
+    }
+    else if kind[0] == SOCKS_IPV6 {
+        let mut octets = [0u8; 16];
+        input.read_exact(&mut octets)?;
+        IpAddr::V6(Ipv6Addr::from(octets))
+    }
+    else if kind[0] == SOCKS_DOMAIN {
+        return Err(socks_error("domain names are not supported"));
+    }
+    else {
+        return Err(socks_error(&format!("unknown address type {}", kind[0])));
+    };
+    let mut port = [0u8; 2];
+    input.read_exact(&mut port)?;
+    Ok(SocketAddr::new(ip, u16::from_be_bytes(port)))
+}
+
+impl<TAddr> RecvBatch<TAddr> {
+    /// Create buffers for `count` datagrams of at most `size` bytes.
+    pub fn new(count: usize, size: usize) -> RecvBatch<TAddr> {
+        assert!(count > 0);
+        RecvBatch {
+            buffers: vec![vec![0u8; size]; count],
+            received: Vec::with_capacity(count)
+        }
+    }
+
+    /// Maximum number of datagrams in a batch.
+    pub fn capacity(&self) -> usize {
+        self.buffers.len()
+    }
+
+    /// Number of datagrams received.
+    pub fn len(&self) -> usize {
+        self.received.len()
+    }
+
+    /// Whether no datagrams were received.
+    pub fn is_empty(&self) -> bool {
+        self.received.is_empty()
+    }
+
+    /// Iterate over received datagrams and their sources.
+    pub fn iter(&self) -> impl Iterator<Item=(&[u8], &TAddr)> {
+        self.received.iter().zip(self.buffers.iter())
+            .map(|(&(size, ref source), buffer)| (&buffer[..size], source))
+    }
+
+    /// Discard the datagrams and fill the buffers one by one, until
+    /// `receive` returns `None` or the batch is full.
+    ///
+    /// This is the building block for `Transport::recv_batch` implementations.
+    pub fn fill<F>(&mut self, mut receive: F) -> io::Result<usize>
+            where F: FnMut(&mut [u8]) -> io::Result<Option<(usize, TAddr)>> {
+        self.received.clear();
+        while self.received.len() < self.buffers.len() {
+            let index = self.received.len();
+            match receive(&mut self.buffers[index]) {
+                Ok(Some(res)) => self.received.push(res),
+                Ok(None) => break,
+                Err(e) => {
+                    if self.received.is_empty() {
+                        return Err(e);
+                    }
+                    break;
+                }
+            }
+        }
+        Ok(self.received.len())
+    }
+}
+
+impl<TAddr> MemoryNetwork<TAddr>
+        where TAddr: Clone + Eq + Hash + Send {
+    /// Create an empty network.
+    pub fn new() -> MemoryNetwork<TAddr> {
+        let state = NetworkState {
+            inboxes: HashMap::new(),
+            pending: VecDeque::new(),
+            manual_delivery: false,
+            dropped: 0
+        };
+        MemoryNetwork {
+            state: Arc::new(Mutex::new(state))
+        }
+    }
+
+    /// Create a transport with a given address.
+    ///
+    /// Panics if the address is already in use.
+    pub fn bind(&self, address: TAddr) -> MemoryTransport<TAddr> {
+        let mut state = self.state.lock().unwrap();
+        assert!(!state.inboxes.contains_key(&address), "address already in use");
+        state.inboxes.insert(address.clone(), VecDeque::new());
+        MemoryTransport {
+            address,
+            state: self.state.clone()
+        }
+    }
+
+    /// Switch between immediate and manual delivery.
+    pub fn set_manual_delivery(&self, manual: bool) {
+        self.state.lock().unwrap().manual_delivery = manual;
+    }
+
+    /// Number of datagrams sent but not delivered yet.
+    pub fn pending(&self) -> usize {
+        self.state.lock().unwrap().pending.len()
+    }
+
+    /// Number of datagrams sent to addresses nobody is bound to.
+    pub fn dropped(&self) -> usize {
+        self.state.lock().unwrap().dropped
+    }
+
+    /// Deliver at most `count` pending datagrams in the order they were sent.
+    ///
+    /// Returns the number of datagrams processed.
+    pub fn deliver(&self, count: usize) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let count = cmp::min(count, state.pending.len());
+        for _ in 0..count {
+            let datagram = state.pending.pop_front().unwrap();
+            state.deliver(datagram);
+        }
+        count
+    }
+
+    /// Deliver all pending datagrams.
+    pub fn deliver_all(&self) -> usize {
+        self.deliver(usize::MAX)
+    }
+}
+
+impl<TAddr> Default for MemoryNetwork<TAddr>
+        where TAddr: Clone + Eq + Hash + Send {
+    fn default() -> MemoryNetwork<TAddr> {
+        MemoryNetwork::new()
+    }
+}
+
+impl<TAddr> Clone for MemoryNetwork<TAddr> {
+    fn clone(&self) -> MemoryNetwork<TAddr> {
+        MemoryNetwork {
+            state: self.state.clone()
+        }
+    }
+}
+
+impl<TAddr> NetworkState<TAddr>
+        where TAddr: Eq + Hash {
+    fn deliver(&mut self, datagram: Datagram<TAddr>) {
+        match self.inboxes.get_mut(&datagram.destination) {
+            Some(inbox) => inbox.push_back((datagram.data, datagram.source)),
+            None => self.dropped += 1
+        }
+    }
+}
+
+impl<TAddr> Transport for MemoryTransport<TAddr>
+        where TAddr: Clone + Eq + Hash + Send {
+    type Addr = TAddr;
+
+    fn send_to(&mut self, data: &[u8], addr: &TAddr) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let datagram = Datagram {
+            source: self.address.clone(),
+            destination: addr.clone(),
+            data: data.to_vec()
+        };
+        if state.manual_delivery {
+            state.pending.push_back(datagram);
+        }
+        else {
+            state.deliver(datagram);
+        }
+        Ok(())
+    }
+
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<Option<(usize, TAddr)>> {
+        let mut state = self.state.lock().unwrap();
+        let inbox = state.inboxes.get_mut(&self.address).unwrap();
+        Ok(inbox.pop_front().map(|(data, source)| {
+            let size = cmp::min(data.len(), buffer.len());
+            buffer[..size].copy_from_slice(&data[..size]);
+            (size, source)
+        }))
+    }
+
+    fn local_addr(&self) -> io::Result<TAddr> {
+        Ok(self.address.clone())
+    }
+}
+
+impl<TAddr> Drop for MemoryTransport<TAddr>
+        where TAddr: Eq + Hash {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.inboxes.remove(&self.address);
+        }
+    }
+}
+
+impl<T: Transport> FaultyTransport<T> {
+    /// Wrap a transport with a given policy.
+    pub fn new<F>(inner: T, policy: F) -> FaultyTransport<T>
+            where F: FnMut(Direction, &[u8], &T::Addr) -> Fault + Send + 'static {
+        FaultyTransport {
+            inner,
+            policy: Box::new(policy),
+            clock: Arc::new(SystemClock),
+            delayed: vec![],
+            incoming: VecDeque::new(),
+            stats: FaultStats::default(),
+            buffer: vec![0u8; MAX_DATAGRAM_SIZE]
+        }
+    }
+
+    /// Set the clock used for delays.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Replace the policy.
+    pub fn set_policy<F>(&mut self, policy: F)
+            where F: FnMut(Direction, &[u8], &T::Addr) -> Fault + Send + 'static {
+        self.policy = Box::new(policy);
+    }
+
+    /// Get statistics of the faults applied so far.
+    pub fn stats(&self) -> FaultStats {
+        self.stats.clone()
+    }
+
+    /// Number of delayed datagrams not released yet.
+    pub fn delayed(&self) -> usize {
+        self.delayed.len()
+    }
+
+    /// Get the wrapped transport back, delayed datagrams are lost.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn release_due(&mut self) {
+        let now = self.clock.now();
+        let (mut due, rest): (Vec<_>, Vec<_>) = self.delayed.drain(..)
+            .partition(|&(release_at, _, _, _)| release_at <= now);
+        self.delayed = rest;
+        // Sort is stable, so datagrams delayed equally keep their order
+        due.sort_by_key(|&(release_at, _, _, _)| release_at);
+        for (_, direction, data, addr) in due {
+            match direction {
+                Direction::Outgoing => if let Err(e) = self.inner.send_to(&data, &addr) {
+                    // Keep sending the rest, like a real network losing one
+                    debug!("Dropping a delayed datagram: {}", e);
+                },
+                Direction::Incoming => self.incoming.push_back((data, addr))
+            }
+        }
+    }
+
+    /// Receive datagrams until some pass the policy, returns false if
+    /// there was nothing to receive.
+    fn receive_incoming(&mut self, received: &mut [u8]) -> io::Result<bool> {
+        while self.incoming.is_empty() {
+            let (size, source) = match self.inner.recv_from(received)? {
+                Some(res) => res,
+                None => return Ok(false)
+            };
+            for datagram in self.apply(Direction::Incoming, &received[..size], &source) {
+                self.incoming.push_back((datagram, source.clone()));
+            }
+        }
+        Ok(true)
+    }
+
+    /// Apply the policy, return the datagrams to pass right away.
+    fn apply(&mut self, direction: Direction, data: &[u8], addr: &T::Addr)
+            -> Vec<Vec<u8>> {
+        match (self.policy)(direction, data, addr) {
+            Fault::Deliver => vec![data.to_vec()],
+            Fault::Drop => {
+                self.stats.dropped += 1;
+                vec![]
+            },
+            Fault::Duplicate => {
+                self.stats.duplicated += 1;
+                vec![data.to_vec(), data.to_vec()]
+            },
+            Fault::Delay(delay) => {
+                self.stats.delayed += 1;
+                let release_at = self.clock.now() + delay;
+                self.delayed.push((release_at, direction, data.to_vec(), addr.clone()));
+                vec![]
+            },
+            Fault::Truncate(size) => {
+                self.stats.truncated += 1;
+                vec![data[..cmp::min(size, data.len())].to_vec()]
+            }
+        }
+    }
+}
+
+impl<T: Transport> SharedTransport<T> {
+    /// Share a transport, `classifier` returns `None` for datagrams that
+    /// do not belong to any instance.
+    pub fn new<F>(inner: T, classifier: F) -> SharedTransport<T>
+            where F: FnMut(&[u8], &T::Addr) -> Option<usize> + Send + 'static {
+        let state = SharedState {
+            inner,
+            classifier: Box::new(classifier),
+            inboxes: vec![],
+            unclaimed: 0,
+            buffer: vec![0u8; MAX_DATAGRAM_SIZE],
+            pool: BufferPool::default()
+        };
+        SharedTransport {
+            state: Arc::new(Mutex::new(state))
+        }
+    }
+
+    /// Create a transport for the next instance.
+    ///
+    /// Instances get indexes 0, 1 and so on in the order of creation.
+    pub fn instance(&self) -> InstanceTransport<T> {
+        let mut state = self.state.lock().unwrap();
+        state.inboxes.push(VecDeque::new());
+        InstanceTransport {
+            index: state.inboxes.len() - 1,
+            state: self.state.clone()
+        }
+    }
+
+    /// Number of datagrams dropped because no instance claimed them.
+    pub fn unclaimed(&self) -> usize {
+        self.state.lock().unwrap().unclaimed
+    }
+}
+
+impl<T: Transport> InstanceTransport<T> {
+    /// Index of the instance.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+impl<T: Transport> Transport for InstanceTransport<T> {
+    type Addr = T::Addr;
+
+    fn send_to(&mut self, data: &[u8], addr: &T::Addr) -> io::Result<()> {
+        self.state.lock().unwrap().inner.send_to(data, addr)
+    }
+
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<Option<(usize, T::Addr)>> {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        while state.inboxes[self.index].is_empty() {
+            let (size, source) = match state.inner.recv_from(&mut state.buffer)? {
+                Some(res) => res,
+                None => return Ok(None)
+            };
+            let received = &state.buffer[..size];
+            match (state.classifier)(received, &source) {
+                Some(index) if index < state.inboxes.len() => {
+                    let data = state.pool.take(received);
+                    state.inboxes[index].push_back((data, source));
+                },
+                _ => state.unclaimed += 1
+            }
+        }
+        let (data, source) = state.inboxes[self.index].pop_front().unwrap();
+        let size = cmp::min(data.len(), buffer.len());
+        buffer[..size].copy_from_slice(&data[..size]);
+        state.pool.give(data);
+        Ok(Some((size, source)))
+    }
+
+    fn local_addr(&self) -> io::Result<T::Addr> {
+        self.state.lock().unwrap().inner.local_addr()
+    }
+}
+
+impl BufferPool {
+    /// Copy data into a free buffer or a new one if there are none.
+    fn take(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut buffer = self.free.pop().unwrap_or_default();
+        buffer.clear();
+        buffer.extend_from_slice(data);
+        buffer
+    }
+
+    /// Return a buffer for reuse.
+    fn give(&mut self, buffer: Vec<u8>) {
+        if self.free.len() < MAX_POOLED_BUFFERS {
+            self.free.push(buffer);
+        }
+    }
+}
+
+impl<T: Transport> Transport for FaultyTransport<T> {
+    type Addr = T::Addr;
+
+    fn send_to(&mut self, data: &[u8], addr: &T::Addr) -> io::Result<()> {
+        self.release_due();
+        for datagram in self.apply(Direction::Outgoing, data, addr) {
+            self.inner.send_to(&datagram, addr)?;
+        }
+        Ok(())
+    }
+
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<Option<(usize, T::Addr)>> {
+        self.release_due();
+        // Taken out of self for the time `apply` needs it mutably
+        let mut received = mem::take(&mut self.buffer);
+        let res = self.receive_incoming(&mut received);
+        self.buffer = received;
+        if !res? {
+            return Ok(None);
+        }
+        let (data, source) = self.incoming.pop_front().unwrap();
+        let size = cmp::min(data.len(), buffer.len());
+        buffer[..size].copy_from_slice(&data[..size]);
+        Ok(Some((size, source)))
+    }
+
+    fn local_addr(&self) -> io::Result<T::Addr> {
+        self.inner.local_addr()
+    }
+}
+
+impl Priority {
+    /// Share of the buckets this priority must leave to higher ones.
+    fn reserve(self) -> f64 {
+        match self {
+            Priority::Lookup => 0.0,
+            Priority::Response => 0.25,
+            Priority::Maintenance => 0.5
+        }
+    }
+}
+
+impl RateLimiter {
+    /// Create a limiter allowing `packets_per_sec` datagrams with
+    /// `bytes_per_sec` bytes in total per second.
+    pub fn new(packets_per_sec: u32, bytes_per_sec: u32) -> RateLimiter {
+        assert!(packets_per_sec > 0 && bytes_per_sec > 0);
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        let state = LimiterState {
+            packets_per_sec: packets_per_sec as f64,
+            bytes_per_sec: bytes_per_sec as f64,
+            packets: packets_per_sec as f64,
+            bytes: bytes_per_sec as f64,
+            refilled_at: clock.now(),
+            denied: HashMap::new(),
+            clock
+        };
+        RateLimiter {
+            state: Arc::new(Mutex::new(state))
+        }
+    }
+
+    /// Set the clock used to refill the buckets.
+    pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+        let mut state = self.state.lock().unwrap();
+        state.refilled_at = clock.now();
+        state.clock = clock;
+    }
+
+    /// Take tokens for a datagram of `size` bytes, returns false if it
+    /// should not be sent now.
+    ///
+    /// Datagrams larger than the bytes limit are counted as having
+    /// exactly that size, otherwise they could never be sent.
+    pub fn try_send(&self, size: usize, priority: Priority) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.refill();
+        let bytes = (size as f64).min(state.bytes_per_sec);
+        let share = priority.reserve();
+        // Leave room for this datagram in full buckets
+        let reserved_packets = (state.packets_per_sec * share).min(state.packets_per_sec - 1.0);
+        let reserved_bytes = (state.bytes_per_sec * share).min(state.bytes_per_sec - bytes);
+        if state.packets - 1.0 < reserved_packets || state.bytes - bytes < reserved_bytes {
+            *state.denied.entry(priority).or_insert(0) += 1;
+            return false;
+        }
+        state.packets -= 1.0;
+        state.bytes -= bytes;
+        true
+    }
+
+    /// Number of datagrams of a given priority not allowed so far.
+    pub fn denied(&self, priority: Priority) -> usize {
+        self.state.lock().unwrap().denied.get(&priority).cloned().unwrap_or(0)
+    }
+}
+
+impl LimiterState {
+    fn refill(&mut self) {
+        let now = self.clock.now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.refilled_at = now;
+        self.packets = (self.packets + elapsed * self.packets_per_sec)
+            .min(self.packets_per_sec);
+        self.bytes = (self.bytes + elapsed * self.bytes_per_sec).min(self.bytes_per_sec);
+    }
+}
+
+impl<T: Transport> RateLimitedTransport<T> {
+    /// Wrap a transport, `send_to` uses the `Response` priority.
+    pub fn new(inner: T, limiter: RateLimiter) -> RateLimitedTransport<T> {
+        RateLimitedTransport::with_priority(inner, limiter, Priority::Response)
+    }
+
+    /// Wrap a transport, `send_to` uses a given priority.
+    pub fn with_priority(inner: T, limiter: RateLimiter,
+                         priority: Priority) -> RateLimitedTransport<T> {
+        RateLimitedTransport {
+            inner,
+            limiter,
+            priority
+        }
+    }
+
+    /// Set the priority used by `send_to`.
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+    }
+
+    /// Send a datagram with a given priority.
+    pub fn send_with_priority(&mut self, data: &[u8], addr: &T::Addr,
+                              priority: Priority) -> io::Result<()> {
+        if !self.limiter.try_send(data.len(), priority) {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock,
+                                      "Outgoing rate limit exceeded"));
+        }
+        self.inner.send_to(data, addr)
+    }
+
+    /// Get the limiter.
+    pub fn limiter(&self) -> &RateLimiter {
+        &self.limiter
+    }
+
+    /// Get the wrapped transport back.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Transport> Transport for RateLimitedTransport<T> {
+    type Addr = T::Addr;
+
+    fn send_to(&mut self, data: &[u8], addr: &T::Addr) -> io::Result<()> {
+        let priority = self.priority;
+        self.send_with_priority(data, addr, priority)
+    }
+
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<Option<(usize, T::Addr)>> {
+        self.inner.recv_from(buffer)
+    }
+
+    fn local_addr(&self) -> io::Result<T::Addr> {
+        self.inner.local_addr()
+    }
+}
+
+/// Wrappers around `sendmmsg` and `recvmmsg`.
+#[cfg(target_os = "linux")]
+mod mmsg {
+    use std::io;
+    use std::mem;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+    use std::os::unix::io::AsRawFd;
+    use std::ptr;
+
+    use libc;
+
+
+    pub static MAX_BATCH_SIZE: usize = 64;
+
+
+    /// Send at most `MAX_BATCH_SIZE` datagrams with one call.
+    pub fn send(socket: &UdpSocket, batch: &[(&[u8], SocketAddr)]) -> io::Result<usize> {
+        assert!(batch.len() <= MAX_BATCH_SIZE);
+        let mut addrs: Vec<(libc::sockaddr_storage, libc::socklen_t)> =
+            batch.iter().map(|(_, addr)| to_raw(addr)).collect();
+        let mut iovecs: Vec<libc::iovec> = batch.iter()
+            .map(|&(data, _)| libc::iovec {
+                iov_base: data.as_ptr() as *mut libc::c_void,
+                iov_len: data.len()
+            })
+            .collect();
+        let mut headers: Vec<libc::mmsghdr> = addrs.iter_mut().zip(iovecs.iter_mut())
+            .map(|(&mut (ref mut addr, len), iovec)| {
+                // Zeroing is the only portable way to fill private fields
+                let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+                header.msg_hdr.msg_name = addr as *mut _ as *mut libc::c_void;
+                header.msg_hdr.msg_namelen = len;
+                header.msg_hdr.msg_iov = iovec;
+                header.msg_hdr.msg_iovlen = 1;
+                header
+            })
+            .collect();
+        let res = unsafe {
+            libc::sendmmsg(socket.as_raw_fd(), headers.as_mut_ptr(),
+                           headers.len() as libc::c_uint, 0)
+        };
+        if res < 0 {
+            Err(io::Error::last_os_error())
+        }
+        else {
+            Ok(res as usize)
+        }
+    }
+
+    /// Receive at most `MAX_BATCH_SIZE` datagrams with one call.
+    pub fn recv(socket: &UdpSocket, buffers: &mut [Vec<u8>])
+            -> io::Result<Vec<(usize, SocketAddr)>> {
+        assert!(buffers.len() <= MAX_BATCH_SIZE);
+        let mut addrs: Vec<libc::sockaddr_storage> =
+            buffers.iter().map(|_| unsafe { mem::zeroed() }).collect();
+        let mut iovecs: Vec<libc::iovec> = buffers.iter_mut()
+            .map(|buffer| libc::iovec {
+                iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buffer.len()
+            })
+            .collect();
+        let mut headers: Vec<libc::mmsghdr> = addrs.iter_mut().zip(iovecs.iter_mut())
+            .map(|(addr, iovec)| {
+                let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+                header.msg_hdr.msg_name = addr as *mut _ as *mut libc::c_void;
+                header.msg_hdr.msg_namelen =
+                    mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+                header.msg_hdr.msg_iov = iovec;
+                header.msg_hdr.msg_iovlen = 1;
+                header
+            })
+            .collect();
+        let res = unsafe {
+            libc::recvmmsg(socket.as_raw_fd(), headers.as_mut_ptr(),
+                           headers.len() as libc::c_uint, 0, ptr::null_mut())
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let entries = headers.iter().zip(addrs.iter()).take(res as usize)
+            .map(|(header, addr)| (header.msg_len as usize, from_raw(addr)))
+            .collect();
+        Ok(compact(buffers, entries))
+    }
+
+    /// Skip datagrams from unsupported sources.
+    ///
+    /// Buffers of the remaining datagrams are moved to the front, so that
+    /// the Nth result is still in the Nth buffer.
+    pub fn compact(buffers: &mut [Vec<u8>], entries: Vec<(usize, Option<SocketAddr>)>)
+            -> Vec<(usize, SocketAddr)> {
+        let mut received = Vec::with_capacity(entries.len());
+        for (index, (size, source)) in entries.into_iter().enumerate() {
+            match source {
+                Some(source) => {
+                    buffers.swap(received.len(), index);
+                    received.push((size, source));
+                },
+                None => debug!("Skipping a datagram from an unsupported address family")
+            }
+        }
+        received
+    }
+
+    fn to_raw(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let len = match *addr {
+            SocketAddr::V4(ref addr) => {
+                let mut raw: libc::sockaddr_in = unsafe { mem::zeroed() };
+                raw.sin_family = libc::AF_INET as libc::sa_family_t;
+                raw.sin_port = addr.port().to_be();
+                raw.sin_addr.s_addr = u32::from_ne_bytes(addr.ip().octets());
+                unsafe { ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in, raw) };
+                mem::size_of::<libc::sockaddr_in>()
+            },
+            SocketAddr::V6(ref addr) => {
+                let mut raw: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+                raw.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                raw.sin6_port = addr.port().to_be();
+                raw.sin6_flowinfo = addr.flowinfo();
+                raw.sin6_addr.s6_addr = addr.ip().octets();
+                raw.sin6_scope_id = addr.scope_id();
+                unsafe { ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in6, raw) };
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+        (storage, len as libc::socklen_t)
+    }
+
+    fn from_raw(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+        match storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let raw = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+                let ip = Ipv4Addr::from(raw.sin_addr.s_addr.to_ne_bytes());
+                Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(raw.sin_port))))
+            },
+            libc::AF_INET6 => {
+                let raw = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+                let ip = Ipv6Addr::from(raw.sin6_addr.s6_addr);
+                Some(SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(raw.sin6_port),
+                                                      raw.sin6_flowinfo, raw.sin6_scope_id)))
+            },
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{ErrorKind, Read, Write};
+    use std::net::{SocketAddr, TcpListener, UdpSocket};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::super::mock::{MockClock, MockTransport};
+    use super::{BufferPool, Direction, Fault, FaultyTransport, MemoryNetwork, Priority,
+                RateLimitedTransport, RateLimiter, RecvBatch, SharedTransport,
+                Socks5Transport, Transport, UdpTransport};
+
+
+    #[test]
+    fn test_immediate_delivery() {
+        let network = MemoryNetwork::new();
+        let mut t1 = network.bind(1);
+        let mut t2 = network.bind(2);
+        assert_eq!(2, t2.local_addr().unwrap());
+        let mut buffer = [0u8; 16];
+        assert!(t2.recv_from(&mut buffer).unwrap().is_none());
+
+        t1.send_to(b"ping", &2).unwrap();
+        assert_eq!(Some((4, 1)), t2.recv_from(&mut buffer).unwrap());
+        assert_eq!(b"ping", &buffer[..4]);
+        assert!(t2.recv_from(&mut buffer).unwrap().is_none());
+        assert!(t1.recv_from(&mut buffer).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_manual_delivery() {
+        let network = MemoryNetwork::new();
+        let mut t1 = network.bind(1);
+        let mut t2 = network.bind(2);
+        let mut buffer = [0u8; 16];
+        network.set_manual_delivery(true);
+
+        t1.send_to(b"one", &2).unwrap();
+        t1.send_to(b"two", &2).unwrap();
+        assert_eq!(2, network.pending());
+        assert!(t2.recv_from(&mut buffer).unwrap().is_none());
+
+        assert_eq!(1, network.deliver(1));
+        assert_eq!(Some((3, 1)), t2.recv_from(&mut buffer).unwrap());
+        assert_eq!(b"one", &buffer[..3]);
+        assert!(t2.recv_from(&mut buffer).unwrap().is_none());
+
+        assert_eq!(1, network.deliver_all());
+        assert_eq!(Some((3, 1)), t2.recv_from(&mut buffer).unwrap());
+        assert_eq!(b"two", &buffer[..3]);
+    }
+
+    #[test]
+    fn test_unknown_destination() {
+        let network = MemoryNetwork::new();
+        let mut t1 = network.bind(1);
+        t1.send_to(b"ping", &2).unwrap();
+        assert_eq!(1, network.dropped());
+
+        let t2 = network.bind(2);
+        drop(t2);
+        t1.send_to(b"ping", &2).unwrap();
+        assert_eq!(2, network.dropped());
+    }
+
+    #[test]
+    fn test_truncate() {
+        let network = MemoryNetwork::new();
+        let mut t1 = network.bind(1);
+        let mut t2 = network.bind(2);
+        let mut buffer = [0u8; 2];
+        t1.send_to(b"ping", &2).unwrap();
+        assert_eq!(Some((2, 1)), t2.recv_from(&mut buffer).unwrap());
+        assert_eq!(b"pi", &buffer);
+    }
+
+    #[test]
+    fn test_udp() {
+        let mut t1 = UdpTransport::bind("127.0.0.1:0").unwrap();
+        let mut t2 = UdpTransport::bind("127.0.0.1:0").unwrap();
+        let addr1 = t1.local_addr().unwrap();
+        let addr2 = t2.local_addr().unwrap();
+        let mut buffer = [0u8; 16];
+        assert!(t2.recv_from(&mut buffer).unwrap().is_none());
+
+        t1.send_to(b"ping", &addr2).unwrap();
+        let mut received = None;
+        for _ in 0..100 {
+            received = t2.recv_from(&mut buffer).unwrap();
+            if received.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(Some((4, addr1)), received);
+        assert_eq!(b"ping", &buffer[..4]);
+    }
+
+    #[test]
+    fn test_udp_batch() {
+        let mut t1 = UdpTransport::bind("127.0.0.1:0").unwrap();
+        let mut t2 = UdpTransport::bind("127.0.0.1:0").unwrap();
+        let addr1 = t1.local_addr().unwrap();
+        let addr2 = t2.local_addr().unwrap();
+        let datagrams: Vec<Vec<u8>> = (0..100u8).map(|i| vec![i; 4]).collect();
+        let batch: Vec<(&[u8], SocketAddr)> = datagrams.iter()
+            .map(|data| (&data[..], addr2)).collect();
+        assert_eq!(100, t1.send_batch(&batch).unwrap());
+
+        let mut batch = RecvBatch::new(30, 16);
+        let mut received = vec![];
+        for _ in 0..100 {
+            while t2.recv_batch(&mut batch).unwrap() > 0 {
+                for (data, source) in batch.iter() {
+                    assert_eq!(addr1, *source);
+                    received.push(data.to_vec());
+                }
+            }
+            if received.len() == datagrams.len() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(datagrams, received);
+    }
+
+    #[test]
+    fn test_default_batch() {
+        let mut t = MockTransport::new(1);
+        assert_eq!(2, t.send_batch(&[(b"a", 2), (b"b", 3)]).unwrap());
+        assert_eq!(vec![(b"a".to_vec(), 2), (b"b".to_vec(), 3)], t.take_sent());
+        t.fail_next_send(ErrorKind::WouldBlock);
+        assert!(t.send_batch(&[(b"c", 2)]).is_err());
+        assert!(t.sent().is_empty());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_mmsg_compact() {
+        let address: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let mut buffers = vec![b"one".to_vec(), b"two".to_vec(), b"three".to_vec()];
+        let received = super::mmsg::compact(
+            &mut buffers, vec![(3, None), (3, Some(address)), (5, Some(address))]);
+        assert_eq!(vec![(3, address), (5, address)], received);
+        assert_eq!(b"two".to_vec(), buffers[0]);
+        assert_eq!(b"three".to_vec(), buffers[1]);
+    }
+
+    #[test]
+    fn test_recv_batch() {
+        let mut t = MockTransport::new(1);
+        for data in &[&b"a"[..], b"bb", b"ccc"] {
+            t.push_incoming(data, 2);
+        }
+        let mut batch = RecvBatch::new(2, 16);
+        assert_eq!(2, batch.capacity());
+        assert_eq!(2, t.recv_batch(&mut batch).unwrap());
+        assert_eq!(vec![(&b"a"[..], &2), (&b"bb"[..], &2)],
+                   batch.iter().collect::<Vec<_>>());
+        assert_eq!(1, t.recv_batch(&mut batch).unwrap());
+        assert_eq!(b"ccc", batch.iter().next().unwrap().0);
+        assert_eq!(0, t.recv_batch(&mut batch).unwrap());
+        assert!(batch.is_empty());
+    }
+
+    /// Accept one client with user "u" and password "p", then echo
+    /// one datagram back as if it came from the destination.
+    fn run_socks_proxy(listener: TcpListener) {
+        let (mut control, _) = listener.accept().unwrap();
+        let mut buffer = [0u8; 64];
+        control.read_exact(&mut buffer[..3]).unwrap();
+        assert_eq!([5, 1, 2], buffer[..3]);
+        control.write_all(&[5, 2]).unwrap();
+        control.read_exact(&mut buffer[..5]).unwrap();
+        assert_eq!([1, 1, b'u', 1, b'p'], buffer[..5]);
+        control.write_all(&[1, 0]).unwrap();
+        control.read_exact(&mut buffer[..10]).unwrap();
+        assert_eq!([5, 3, 0, 1], buffer[..4]);
+
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = relay.local_addr().unwrap().port().to_be_bytes();
+        control.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, port[0], port[1]]).unwrap();
+        let (size, client) = relay.recv_from(&mut buffer).unwrap();
+        relay.send_to(&buffer[..size], client).unwrap();
+        // Keep the association until the client is gone
+        let _ = control.read(&mut buffer);
+    }
+
+    #[test]
+    fn test_socks5() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let server = thread::spawn(move || run_socks_proxy(listener));
+
+        let mut t = Socks5Transport::connect_with_auth(proxy, Some(("u", "p"))).unwrap();
+        assert_eq!(proxy.ip(), t.relay_addr().ip());
+        let destination: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        let mut buffer = [0u8; 16];
+        assert!(t.recv_from(&mut buffer).unwrap().is_none());
+        t.send_to(b"ping", &destination).unwrap();
+        let mut received = None;
+        for _ in 0..100 {
+            received = t.recv_from(&mut buffer).unwrap();
+            if received.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(Some((4, destination)), received);
+        assert_eq!(b"ping", &buffer[..4]);
+        drop(t);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_shared() {
+        let network = MemoryNetwork::new();
+        let mut peer = network.bind(1);
+        let shared = SharedTransport::new(network.bind(2), |data, _| match data[0] {
+            b'a' => Some(0),
+            b'b' => Some(1),
+            _ => None
+        });
+        let mut t1 = shared.instance();
+        let mut t2 = shared.instance();
+        assert_eq!(1, t2.index());
+        assert_eq!(2, t2.local_addr().unwrap());
+
+        for data in &[&b"a1"[..], b"b1", b"c1", b"a2"] {
+            peer.send_to(data, &2).unwrap();
+        }
+        let mut buffer = [0u8; 16];
+        assert_eq!(Some((2, 1)), t2.recv_from(&mut buffer).unwrap());
+        assert_eq!(b"b1", &buffer[..2]);
+        assert!(t2.recv_from(&mut buffer).unwrap().is_none());
+        assert_eq!(1, shared.unclaimed());
+        t1.recv_from(&mut buffer).unwrap().unwrap();
+        assert_eq!(b"a1", &buffer[..2]);
+        t1.recv_from(&mut buffer).unwrap().unwrap();
+        assert_eq!(b"a2", &buffer[..2]);
+
+        t2.send_to(b"pong", &1).unwrap();
+        assert_eq!(Some((4, 2)), peer.recv_from(&mut buffer).unwrap());
+    }
+
+    #[test]
+    fn test_buffer_pool() {
+        let mut pool = BufferPool::default();
+        let buffer = pool.take(b"data");
+        assert_eq!(b"data", &buffer[..]);
+        let pointer = buffer.as_ptr();
+        pool.give(buffer);
+        let buffer = pool.take(b"more");
+        assert_eq!(b"more", &buffer[..]);
+        assert_eq!(pointer, buffer.as_ptr());
+    }
+
+    #[test]
+    fn test_faulty_outgoing() {
+        let network = MemoryNetwork::new();
+        let mut counter = 0;
+        let mut t1 = FaultyTransport::new(network.bind(1), move |_, _: &[u8], _: &i32| {
+            counter += 1;
+            match counter {
+                1 => Fault::Drop,
+                2 => Fault::Duplicate,
+                3 => Fault::Truncate(2),
+                _ => Fault::Deliver
+            }
+        });
+        let mut t2 = network.bind(2);
+        let mut buffer = [0u8; 16];
+        for _ in 0..4 {
+            t1.send_to(b"ping", &2).unwrap();
+        }
+        let mut sizes = vec![];
+        while let Some((size, _)) = t2.recv_from(&mut buffer).unwrap() {
+            sizes.push(size);
+        }
+        assert_eq!(vec![4, 4, 2, 4], sizes);
+        let stats = t1.stats();
+        assert_eq!((1, 1, 1, 0), (stats.dropped, stats.duplicated, stats.truncated,
+                                  stats.delayed));
+    }
+
+    #[test]
+    fn test_faulty_delay_reorders() {
+        let network = MemoryNetwork::new();
+        let clock = MockClock::new();
+        let mut t1 = network.bind(1);
+        let mut t2 = FaultyTransport::new(network.bind(2), |direction, data: &[u8], _: &i32| {
+            assert_eq!(Direction::Incoming, direction);
+            if data == b"one" { Fault::Delay(Duration::from_secs(1)) } else { Fault::Deliver }
+        });
+        t2.set_clock(Arc::new(clock.clone()));
+        let mut buffer = [0u8; 16];
+
+        t1.send_to(b"one", &2).unwrap();
+        t1.send_to(b"two", &2).unwrap();
+        assert_eq!(Some((3, 1)), t2.recv_from(&mut buffer).unwrap());
+        assert_eq!(b"two", &buffer[..3]);
+        assert!(t2.recv_from(&mut buffer).unwrap().is_none());
+        assert_eq!(1, t2.delayed());
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(Some((3, 1)), t2.recv_from(&mut buffer).unwrap());
+        assert_eq!(b"one", &buffer[..3]);
+        assert_eq!(0, t2.delayed());
+    }
+
+    #[test]
+    fn test_faulty_delay_send_error() {
+        let clock = MockClock::new();
+        let inner = MockTransport::new(1);
+        let mut t = FaultyTransport::new(inner.clone(), |_, data: &[u8], _: &i32| {
+            if data == b"now" { Fault::Deliver } else { Fault::Delay(Duration::from_secs(1)) }
+        });
+        t.set_clock(Arc::new(clock.clone()));
+        t.send_to(b"one", &2).unwrap();
+        t.send_to(b"two", &2).unwrap();
+        assert_eq!(2, t.delayed());
+
+        clock.advance(Duration::from_secs(1));
+        inner.fail_next_send(ErrorKind::Other);
+        t.send_to(b"now", &2).unwrap();
+        assert_eq!(0, t.delayed());
+        let sent: Vec<_> = inner.sent().into_iter().map(|(data, _)| data).collect();
+        assert_eq!(vec![b"two".to_vec(), b"now".to_vec()], sent);
+    }
+
+    #[test]
+    fn test_rate_limiter_priorities() {
+        let clock = MockClock::new();
+        let limiter = RateLimiter::new(8, 10000);
+        limiter.set_clock(Arc::new(clock.clone()));
+        // Maintenance leaves half of the packets
+        let maintenance = (0..8).filter(|_| limiter.try_send(10, Priority::Maintenance)).count();
+        assert_eq!(4, maintenance);
+        // Responses leave a quarter
+        let responses = (0..8).filter(|_| limiter.try_send(10, Priority::Response)).count();
+        assert_eq!(2, responses);
+        let lookups = (0..8).filter(|_| limiter.try_send(10, Priority::Lookup)).count();
+        assert_eq!(2, lookups);
+        assert_eq!((4, 6, 6), (limiter.denied(Priority::Maintenance),
+                               limiter.denied(Priority::Response),
+                               limiter.denied(Priority::Lookup)));
+
+        clock.advance(Duration::from_millis(500));
+        assert!(limiter.try_send(10, Priority::Lookup));
+        assert!(!limiter.try_send(10, Priority::Maintenance));
+        // The buckets hold at most one second worth of traffic
+        clock.advance(Duration::from_secs(10));
+        let lookups = (0..20).filter(|_| limiter.try_send(10, Priority::Lookup)).count();
+        assert_eq!(8, lookups);
+    }
+
+    #[test]
+    fn test_rate_limiter_bytes() {
+        let clock = MockClock::new();
+        let limiter = RateLimiter::new(100, 1000);
+        limiter.set_clock(Arc::new(clock.clone()));
+        assert!(limiter.try_send(700, Priority::Lookup));
+        assert!(!limiter.try_send(400, Priority::Lookup));
+        assert!(limiter.try_send(300, Priority::Lookup));
+        // Oversized datagrams fit into a full bucket
+        clock.advance(Duration::from_secs(1));
+        assert!(limiter.try_send(5000, Priority::Lookup));
+    }
+
+    #[test]
+    fn test_rate_limited_transport() {
+        let clock = MockClock::new();
+        let limiter = RateLimiter::new(4, 10000);
+        limiter.set_clock(Arc::new(clock.clone()));
+        let mock = MockTransport::new(1);
+        let handle = mock.clone();
+        let mut maintenance = RateLimitedTransport::with_priority(
+            MockTransport::new(1), limiter.clone(), Priority::Maintenance);
+        let mut t = RateLimitedTransport::new(mock, limiter.clone());
+
+        maintenance.send_to(b"ping", &2).unwrap();
+        maintenance.send_to(b"ping", &2).unwrap();
+        let err = maintenance.send_to(b"ping", &2).unwrap_err();
+        assert_eq!(ErrorKind::WouldBlock, err.kind());
+        t.send_to(b"pong", &2).unwrap();
+        assert!(t.send_to(b"pong", &2).is_err());
+        t.send_with_priority(b"find", &3, Priority::Lookup).unwrap();
+        assert_eq!(vec![(b"pong".to_vec(), 2), (b"find".to_vec(), 3)], handle.sent());
+        assert_eq!(1, t.limiter().denied(Priority::Maintenance));
+    }
+
+    #[test]
+    fn test_rate_limiter_low_limits() {
+        let clock = MockClock::new();
+        let limiter = RateLimiter::new(1, 10000);
+        limiter.set_clock(Arc::new(clock.clone()));
+        for &priority in &[Priority::Maintenance, Priority::Response, Priority::Lookup] {
+            assert!(limiter.try_send(10, priority));
+            assert!(!limiter.try_send(10, priority));
+            clock.advance(Duration::from_secs(1));
+        }
+
+        // Maintenance keeps one packet out of 3 for higher priorities
+        let limiter = RateLimiter::new(3, 10000);
+        limiter.set_clock(Arc::new(clock.clone()));
+        let maintenance = (0..3).filter(|_| limiter.try_send(10, Priority::Maintenance)).count();
+        assert_eq!(1, maintenance);
+        assert!(limiter.try_send(10, Priority::Lookup));
+    }
+
+    #[test]
+    fn test_rate_limiter_oversized() {
+        let clock = MockClock::new();
+        let limiter = RateLimiter::new(100, 1000);
+        limiter.set_clock(Arc::new(clock.clone()));
+        for &priority in &[Priority::Maintenance, Priority::Response] {
+            assert!(limiter.try_send(800, priority));
+            assert!(!limiter.try_send(800, priority));
+            clock.advance(Duration::from_secs(1));
+            assert!(limiter.try_send(5000, priority));
+            clock.advance(Duration::from_secs(1));
+        }
+        // A half-full bucket is still reserved for lookups
+        assert!(limiter.try_send(1000, Priority::Lookup));
+        clock.advance(Duration::from_millis(500));
+        assert!(!limiter.try_send(400, Priority::Maintenance));
+        assert!(limiter.try_send(400, Priority::Lookup));
+    }
+}
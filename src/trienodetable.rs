@@ -0,0 +1,319 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+//! Node table for very large numbers of nodes, e.g. for crawlers.
+//!
+//! Nodes are kept in a crit-bit tree: every branch splits its nodes by
+//! the highest bit in which they differ. Both insertion and looking up
+//! the closest nodes only walk down the tree, instead of going through
+//! all k-buckets like `KNodeTable` does.
+
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::mem;
+
+use super::GenericId;
+use super::GenericNodeTable;
+use super::Node;
+
+
+static HASH_SIZE: usize = 64;
+
+
+/// Node table backed by a binary trie over node IDs.
+///
+/// Unlike `KNodeTable` it has no k-buckets, nodes are accepted until
+/// the capacity is reached. When the table is full, `pop_oldest` returns
+/// the node updated least recently. The distance is always XOR.
+pub struct TrieNodeTable<TId, TAddr> {
+    this_id: TId,
+    hash_size: usize,
+    capacity: usize,
+    root: Trie<TId, TAddr>,
+    // Update counter of every node, oldest first
+    order: BTreeMap<u64, TId>,
+    next_order: u64,
+}
+
+enum Trie<TId, TAddr> {
+    Empty,
+    Leaf(Node<TId, TAddr>, u64),
+    /// Number of the highest differing bit (as in `GenericId::bits`) and
+    /// the two halves. All IDs in a half are equal in this and higher bits.
+    Branch(usize, Box<[Trie<TId, TAddr>; 2]>),
+}
+
+
+impl<TId, TAddr> TrieNodeTable<TId, TAddr>
+        where TId: GenericId,
+              TAddr: Clone + Debug {
+    /// Create a new node table without a capacity limit.
+    ///
+    /// `this_id` -- ID of the current node, it is never added to the table.
+    pub fn new(this_id: TId) -> TrieNodeTable<TId, TAddr> {
+        TrieNodeTable::with_details(this_id, usize::MAX, HASH_SIZE)
+    }
+
+    /// Create a new node table with a given capacity and ID width.
+    ///
+    /// `hash_size` -- number of bits in IDs, e.g. 160 for `[u8; 20]`.
+    pub fn with_details(this_id: TId, capacity: usize,
+                        hash_size: usize) -> TrieNodeTable<TId, TAddr> {
+        assert!(capacity > 0);
+        TrieNodeTable {
+            this_id,
+            hash_size,
+            capacity,
+            root: Trie::Empty,
+            order: BTreeMap::new(),
+            next_order: 0
+        }
+    }
+}
+
+impl<TId, TAddr> GenericNodeTable<TId, TAddr> for TrieNodeTable<TId, TAddr>
+        where TId: GenericId,
+              TAddr: Clone + Debug + Sync + Send {
+    fn random_id(&self) -> TId {
+        TId::gen(self.hash_size)
+    }
+
+    fn update(&mut self, node: &Node<TId, TAddr>) -> bool {
+        assert!(node.id != self.this_id);
+        if !self.root.contains(&node.id) && self.order.len() == self.capacity {
+            debug!("Not adding new node {:?} - no space left", node);
+            return false;
+        }
+        let order = self.next_order;
+        self.next_order += 1;
+        if let Some(previous) = self.root.insert(node.clone(), order) {
+            self.order.remove(&previous);
+        }
+        self.order.insert(order, node.id.clone());
+        true
+    }
+
+    fn find(&self, id: &TId, count: usize) -> Vec<Node<TId, TAddr>> {
+        debug_assert!(count > 0);
+        let mut res = Vec::with_capacity(count);
+        self.root.find(id, count, &mut res);
+        res
+    }
+
+    fn pop_oldest(&mut self) -> Vec<Node<TId, TAddr>> {
+        if self.order.len() < self.capacity {
+            return vec![];
+        }
+        let oldest = match self.order.values().next() {
+            Some(id) => id.clone(),
+            None => return vec![]
+        };
+        self.remove(&oldest).into_iter().collect()
+    }
+
+    fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    fn contains(&self, id: &TId) -> bool {
+        self.root.contains(id)
+    }
+
+    fn remove(&mut self, id: &TId) -> Option<Node<TId, TAddr>> {
+        let (node, order) = self.root.remove(id)?;
+        self.order.remove(&order);
+        Some(node)
+    }
+}
+
+impl<TId, TAddr> Trie<TId, TAddr>
+        where TId: GenericId {
+    /// Any ID in this subtree.
+    fn some_id(&self) -> &TId {
+        match *self {
+            Trie::Empty => unreachable!(),
+            Trie::Leaf(ref node, _) => &node.id,
+            Trie::Branch(_, ref children) => children[0].some_id()
+        }
+    }
+
+    /// Half of a branch with `bit` an ID belongs to, `None` if it differs
+    /// from the whole branch in a higher bit.
+    fn half(children: &[Trie<TId, TAddr>; 2], bit: usize, id: &TId) -> Option<usize> {
+        let diff = id.bitxor(children[0].some_id()).bits();
+        if diff > bit {
+            None
+        }
+        else if diff == bit {
+            Some(1)
+        }
+        else {
+            Some(0)
+        }
+    }
+
+    fn contains(&self, id: &TId) -> bool {
+        match *self {
+            Trie::Empty => false,
+            Trie::Leaf(ref node, _) => node.id == *id,
+            Trie::Branch(bit, ref children) => match Trie::half(children, bit, id) {
+                Some(half) => children[half].contains(id),
+                None => false
+            }
+        }
+    }
+
+    /// Insert or replace a node, returns the order of the replaced one.
+    fn insert(&mut self, node: Node<TId, TAddr>, order: u64) -> Option<u64> {
+        let split_at = match *self {
+            Trie::Empty => {
+                *self = Trie::Leaf(node, order);
+                return None;
+            },
+            Trie::Leaf(ref mut existing, ref mut existing_order) => {
+                let diff = node.id.bitxor(&existing.id).bits();
+                if diff == 0 {
+                    *existing = node;
+                    return Some(mem::replace(existing_order, order));
+                }
+                diff
+            },
+            Trie::Branch(bit, ref mut children) => {
+                let diff = node.id.bitxor(children[0].some_id()).bits();
+                if diff < bit {
+                    return children[0].insert(node, order);
+                }
+                else if diff == bit {
+                    return children[1].insert(node, order);
+                }
+                diff
+            }
+        };
+        let existing = mem::replace(self, Trie::Empty);
+        *self = Trie::Branch(split_at, Box::new([existing, Trie::Leaf(node, order)]));
+        None
+    }
+
+    /// Remove a node, returns it with its order.
+    fn remove(&mut self, id: &TId) -> Option<(Node<TId, TAddr>, u64)> {
+        let (half, res) = match *self {
+            Trie::Empty => return None,
+            Trie::Leaf(ref node, _) => {
+                if node.id != *id {
+                    return None;
+                }
+                match mem::replace(self, Trie::Empty) {
+                    Trie::Leaf(node, order) => return Some((node, order)),
+                    _ => unreachable!()
+                }
+            },
+            Trie::Branch(bit, ref mut children) => {
+                let half = Trie::half(children, bit, id)?;
+                let res = children[half].remove(id)?;
+                if let Trie::Empty = children[half] {
+                    (half, res)
+                }
+                else {
+                    return Some(res);
+                }
+            }
+        };
+        // One half is gone, replace the branch with the other one
+        if let Trie::Branch(_, ref mut children) = *self {
+            let other = mem::replace(&mut children[1 - half], Trie::Empty);
+            *self = other;
+        }
+        Some(res)
+    }
+
+    /// Append nodes closest to `id` to `res` until it has `count` nodes.
+    fn find(&self, id: &TId, count: usize, res: &mut Vec<Node<TId, TAddr>>)
+            where TAddr: Clone {
+        if res.len() >= count {
+            return;
+        }
+        match *self {
+            Trie::Empty => {},
+            Trie::Leaf(ref node, _) => res.push(node.clone()),
+            Trie::Branch(_, ref children) => {
+                // All IDs in a half are closer to `id` than the IDs in
+                // the other half, compare any two to find out which is which
+                let first = if id.bitxor(children[0].some_id()) <=
+                               id.bitxor(children[1].some_id()) { 0 } else { 1 };
+                children[first].find(id, count, res);
+                children[1 - first].find(id, count, res);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use rand::{Rng, SeedableRng, XorShiftRng};
+
+    use super::super::{GenericNodeTable, Node};
+    use super::TrieNodeTable;
+
+    use super::super::utils::test;
+
+
+    #[test]
+    fn test_find_matches_sorting() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let mut n = TrieNodeTable::new(0);
+        let nodes: Vec<Node<u64, usize>> = (0..1000)
+            .map(|i| Node { id: rng.gen::<u64>() | 1, address: i }).collect();
+        for node in &nodes {
+            assert!(n.update(node));
+        }
+        assert_eq!(nodes.len(), n.len());
+        for _ in 0..100 {
+            let target: u64 = rng.gen();
+            let mut expected: Vec<u64> = nodes.iter().map(|node| node.id).collect();
+            expected.sort_by_key(|id| id ^ target);
+            expected.truncate(20);
+            let found: Vec<u64> = n.find(&target, 20).iter().map(|node| node.id).collect();
+            assert_eq!(expected, found);
+        }
+    }
+
+    #[test]
+    fn test_update_remove() {
+        let mut n = TrieNodeTable::new(test::make_id(0));
+        for i in 1..6 {
+            assert!(n.update(&test::new_node(test::make_id(i))));
+        }
+        assert!(n.update(&test::new_node(test::make_id(3))));
+        assert_eq!(5, n.len());
+        assert!(n.remove(&test::make_id(4)).is_some());
+        assert!(n.remove(&test::make_id(4)).is_none());
+        assert!(n.remove(&test::make_id(42)).is_none());
+        let found: Vec<_> = n.find(&test::make_id(4), 10).into_iter().map(|n| n.id).collect();
+        assert_eq!(vec![test::make_id(5), test::make_id(1), test::make_id(2),
+                        test::make_id(3)], found);
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut n = TrieNodeTable::with_details(test::make_id(0), 3, 8);
+        for i in 1..4 {
+            assert!(n.update(&test::new_node(test::make_id(i))));
+        }
+        assert!(!n.update(&test::new_node(test::make_id(4))));
+        // Updating moves the node to the end of the queue
+        assert!(n.update(&test::new_node(test::make_id(1))));
+        let oldest = n.pop_oldest();
+        assert_eq!(vec![test::make_id(2)], oldest.into_iter().map(|n| n.id).collect::<Vec<_>>());
+        assert!(n.pop_oldest().is_empty());
+        assert!(n.update(&test::new_node(test::make_id(4))));
+        assert_eq!(3, n.len());
+        assert_eq!(1, n.random_id().len());
+    }
+}